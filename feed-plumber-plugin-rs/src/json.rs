@@ -0,0 +1,44 @@
+//! Turning JSON values into items.
+
+use serde_json::Value;
+
+/// The key a JSON value that is neither an object nor an array is flattened to.
+pub const VALUE_KEY: &str = "value";
+
+/// The pairs of a JSON value. Objects and arrays are flattened into keys joined by `.`, like
+/// `author.name` or `tags.0`, and a value that is neither is keyed [`VALUE_KEY`]. Strings are
+/// taken as they are, other values as JSON text, and `null`s are left out.
+pub fn flatten(value: &Value) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let key = match value {
+        Value::Object(_) | Value::Array(_) => "",
+        _ => VALUE_KEY,
+    };
+    add(value, key, &mut pairs);
+    pairs
+}
+
+fn add(value: &Value, key: &str, pairs: &mut Vec<(String, String)>) {
+    let join = |field: &str| {
+        if key.is_empty() {
+            field.to_owned()
+        } else {
+            format!("{key}.{field}")
+        }
+    };
+    match value {
+        Value::Null => {}
+        Value::String(value) => pairs.push((key.to_owned(), value.clone())),
+        Value::Bool(_) | Value::Number(_) => pairs.push((key.to_owned(), value.to_string())),
+        Value::Array(values) => {
+            for (idx, value) in values.iter().enumerate() {
+                add(value, &join(&idx.to_string()), pairs);
+            }
+        }
+        Value::Object(fields) => {
+            for (field, value) in fields {
+                add(value, &join(field), pairs);
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use std::ffi::c_void;
+use std::{
+    ffi::{c_char, c_void, CStr},
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::Duration,
+};
 
 use sys_feed_plumber_plugin::Items;
 
-use crate::raw::{items_to_vec, vec_to_items};
+use crate::raw::{error_items, items_to_vec, panic_message, vec_to_items};
 
 #[cfg(feature = "deserialize")]
 pub use toml;
@@ -25,6 +29,17 @@ pub use str_config::*;
 
 #[cfg(feature = "deserialize")]
 mod de_config;
+
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "state")]
+pub mod state;
+#[cfg(feature = "template")]
+pub mod template;
+
+#[cfg(target_arch = "wasm32")]
+#[doc(hidden)]
+pub mod wasm;
 #[cfg(feature = "deserialize")]
 pub use de_config::*;
 
@@ -34,24 +49,96 @@ macro_rules! feed_plumber_plugin {
         sources: $($source_name:literal => $source_ty:ty),*;
         sinks: $($sink_name:literal => $sink_ty:ty),*;
         processors: $($processor_name:literal => $processor_ty:ty),*;
+        $(stream_processors: $($stream_name:literal => $stream_ty:ty),*;)?
+        $(event_sources: $($event_name:literal => $event_ty:ty),*;)?
     } => {
+        #[cfg(target_arch = "wasm32")]
+        const _: () = {
+            #[no_mangle]
+            pub extern "C" fn feed_plumber_alloc(len: u32) -> u32 {
+                $crate::wasm::alloc(len)
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_free(ptr: u32, len: u32) {
+                $crate::wasm::free(ptr, len)
+            }
+            #[no_mangle]
+            pub extern "C" fn feed_plumber_manifest() -> u64 {
+                $crate::wasm::manifest(_feedplumber_plugin_init)
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_create(
+                kind: u32,
+                type_ptr: u32,
+                type_len: u32,
+                config_ptr: u32,
+                config_len: u32,
+            ) -> u64 {
+                $crate::wasm::create(
+                    _feedplumber_plugin_init,
+                    kind,
+                    type_ptr,
+                    type_len,
+                    config_ptr,
+                    config_len,
+                )
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_poll_source(handle: u32) -> u64 {
+                $crate::wasm::poll_source(handle)
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_sink_items(handle: u32, ptr: u32, len: u32) -> u64 {
+                $crate::wasm::sink_items(handle, ptr, len)
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_process_items(handle: u32, ptr: u32, len: u32) -> u64 {
+                $crate::wasm::process_items(handle, ptr, len)
+            }
+            #[no_mangle]
+            pub unsafe extern "C" fn feed_plumber_destroy(handle: u32) {
+                $crate::wasm::destroy(handle)
+            }
+        };
+
+        #[no_mangle]
+        pub static FEED_PLUMBER_ABI_VERSION: u32 = $crate::sys::ABI_VERSION;
+
         #[no_mangle]
         pub extern "C" fn _feedplumber_plugin_init() -> $crate::sys::FeedPlumberPlugin {
             let sources = Box::leak(Box::new([$($crate::sys::FeedPlumberSourceMeta {
                 name: $crate::sys::StaticString::from_static($crate::sys::cstr!($source_name)),
                 create: $crate::source_create::<$source_ty>,
                 poll_source: $crate::source_poll_source::<$source_ty>,
-            },),*]));
+                destroy: $crate::component_destroy::<$source_ty>,
+            },)*]));
             let sinks = Box::leak(Box::new([$($crate::sys::FeedPlumberSinkMeta {
                 name: $crate::sys::StaticString::from_static($crate::sys::cstr!($sink_name)),
                 create: $crate::sink_create::<$sink_ty>,
                 sink_items: $crate::sink_sink_items::<$sink_ty>,
-            },),*]));
+                destroy: $crate::component_destroy::<$sink_ty>,
+            },)*]));
             let processors = Box::leak(Box::new([$($crate::sys::FeedPlumberProcessorMeta {
                 name: $crate::sys::StaticString::from_static($crate::sys::cstr!($processor_name)),
                 create: $crate::processor_create::<$processor_ty>,
                 process_items: $crate::processor_process_items::<$processor_ty>,
-            },),*]));
+                destroy: $crate::component_destroy::<$processor_ty>,
+            },)*]));
+            let stream_processors: &mut [$crate::sys::FeedPlumberStreamProcessorMeta] =
+                Box::leak(Box::new([$($($crate::sys::FeedPlumberStreamProcessorMeta {
+                    name: $crate::sys::StaticString::from_static($crate::sys::cstr!($stream_name)),
+                    create: $crate::stream_processor_create::<$stream_ty>,
+                    receive_items: $crate::stream_processor_receive_items::<$stream_ty>,
+                    flush: $crate::stream_processor_flush::<$stream_ty>,
+                    destroy: $crate::component_destroy::<$stream_ty>,
+                },)*)?]));
+            let event_sources: &mut [$crate::sys::FeedPlumberEventSourceMeta] =
+                Box::leak(Box::new([$($($crate::sys::FeedPlumberEventSourceMeta {
+                    name: $crate::sys::StaticString::from_static($crate::sys::cstr!($event_name)),
+                    create: $crate::event_source_create::<$event_ty>,
+                    run: $crate::event_source_run::<$event_ty>,
+                    destroy: $crate::component_destroy::<$event_ty>,
+                },)*)?]));
             $crate::sys::FeedPlumberPlugin {
                 sources: sources.as_ptr(),
                 sources_len: sources.len(),
@@ -59,9 +146,33 @@ macro_rules! feed_plumber_plugin {
                 sinks_len: sinks.len(),
                 processors: processors.as_ptr(),
                 processors_len: processors.len(),
+                stream_processors: stream_processors.as_ptr(),
+                stream_processors_len: stream_processors.len(),
+                event_sources: event_sources.as_ptr(),
+                event_sources_len: event_sources.len(),
             }
         }
     };
+    {
+        stream_processors: $($stream_name:literal => $stream_ty:ty),*;
+    } => {
+        feed_plumber_plugin! {
+            sources:;
+            sinks:;
+            processors:;
+            stream_processors: $($stream_name => $stream_ty),*;
+        }
+    };
+    {
+        event_sources: $($event_name:literal => $event_ty:ty),*;
+    } => {
+        feed_plumber_plugin! {
+            sources:;
+            sinks:;
+            processors:;
+            event_sources: $($event_name => $event_ty),*;
+        }
+    };
     {
         sources: $($source_name:literal => $source_ty:ty),*;
     } => {
@@ -206,35 +317,133 @@ macro_rules! feed_plumber_plugin {
     };
 }
 
+/// # Safety
+/// `handle` must have been returned by [`source_create`] for the same `T`.
 pub unsafe extern "C" fn source_poll_source<T: FeedPlumberSource>(handle: *mut c_void) -> Items {
     let source = &mut *(handle as *mut T);
-    let pairs = source.poll_source();
-    match pairs {
-        Ok(pairs) => vec_to_items(pairs),
-        Err(err) => vec_to_items(vec![vec![(
-            "FEED_PLUMBER_ERROR".to_owned(),
-            format!("{err}"),
-        )]]),
+    match catch_unwind(AssertUnwindSafe(|| source.poll_source())) {
+        Ok(Ok(pairs)) => vec_to_items(pairs),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_WARN, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
     }
 }
 
-pub unsafe extern "C" fn sink_sink_items<T: FeedPlumberSink>(handle: *mut c_void, items: Items) {
+/// # Safety
+/// `handle` must have been returned by [`sink_create`] for the same `T`, and `items` must be valid.
+pub unsafe extern "C" fn sink_sink_items<T: FeedPlumberSink>(
+    handle: *mut c_void,
+    items: Items,
+) -> Items {
     let sink = &mut *(handle as *mut T);
-    sink.sink_items(items_to_vec(items));
+    match catch_unwind(AssertUnwindSafe(|| sink.sink_items(items_to_vec(items)))) {
+        Ok(Ok(())) => vec_to_items(Vec::new()),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_WARN, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
+    }
 }
 
+/// # Safety
+/// `handle` must have been returned by [`processor_create`] for the same `T`, and `items` must be
+/// valid.
 pub unsafe extern "C" fn processor_process_items<T: FeedPlumberProcessor>(
     handle: *mut c_void,
     items: Items,
 ) -> Items {
     let processor = &mut *(handle as *mut T);
-    let pairs = processor.process_items(items_to_vec(items));
-    match pairs {
-        Ok(pairs) => vec_to_items(pairs),
-        Err(err) => vec_to_items(vec![vec![(
-            "FEED_PLUMBER_WARN".to_owned(),
-            format!("{err}"),
-        )]]),
+    match catch_unwind(AssertUnwindSafe(|| {
+        processor.process_items(items_to_vec(items))
+    })) {
+        Ok(Ok(pairs)) => vec_to_items(pairs),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_WARN, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
+    }
+}
+
+/// # Safety
+/// `handle` must have been returned by [`stream_processor_create`] for the same `T`, `source` must
+/// be a valid, NUL-terminated string and `items` must be valid.
+pub unsafe extern "C" fn stream_processor_receive_items<T: FeedPlumberStreamProcessor>(
+    handle: *mut c_void,
+    source: *const c_char,
+    items: Items,
+) -> Items {
+    let processor = &mut *(handle as *mut T);
+    let source = CStr::from_ptr(source).to_string_lossy();
+    match catch_unwind(AssertUnwindSafe(|| {
+        processor.receive_items(&source, items_to_vec(items))
+    })) {
+        Ok(Ok(pairs)) => vec_to_items(pairs),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_WARN, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
+    }
+}
+
+/// # Safety
+/// `handle` must have been returned by [`stream_processor_create`] for the same `T`.
+pub unsafe extern "C" fn stream_processor_flush<T: FeedPlumberStreamProcessor>(
+    handle: *mut c_void,
+) -> Items {
+    let processor = &mut *(handle as *mut T);
+    match catch_unwind(AssertUnwindSafe(|| processor.flush())) {
+        Ok(Ok(pairs)) => vec_to_items(pairs),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_WARN, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
+    }
+}
+
+/// # Safety
+/// `handle` must have been returned by [`event_source_create`] for the same `T`, and `emitter` must
+/// be valid for the duration of the call.
+pub unsafe extern "C" fn event_source_run<T: FeedPlumberEventSource>(
+    handle: *mut c_void,
+    emitter: sys::Emitter,
+) -> Items {
+    let source = &mut *(handle as *mut T);
+    let emitter = Emitter(emitter);
+    match catch_unwind(AssertUnwindSafe(|| source.run(&emitter))) {
+        Ok(Ok(())) => vec_to_items(Vec::new()),
+        Ok(Err(err)) => error_items(sys::ERROR_KEY_FATAL, format!("{err}")),
+        Err(panic) => error_items(sys::ERROR_KEY_PANIC, panic_message(panic)),
+    }
+}
+
+/// Hands the items of a running [`FeedPlumberEventSource`] to the service, and tells it when to
+/// stop.
+pub struct Emitter(sys::Emitter);
+
+impl Emitter {
+    /// Emits items into the source's pipelines. Returns false once the source is asked to stop,
+    /// in which case the items are dropped.
+    pub fn emit(&self, items: Vec<Vec<(String, String)>>) -> bool {
+        if items.is_empty() {
+            return !self.stopping();
+        }
+        // Safety: FFI, the emitter is valid while the source runs
+        unsafe { (self.0.emit)(self.0.context, vec_to_items(items)) }
+    }
+
+    /// Whether the source is asked to stop.
+    pub fn stopping(&self) -> bool {
+        // Safety: FFI, the emitter is valid while the source runs
+        unsafe { (self.0.wait_stop)(self.0.context, 0) }
+    }
+
+    /// Sleeps up to `timeout`, returning early once the source is asked to stop. Returns whether
+    /// it was.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+        // Safety: FFI, the emitter is valid while the source runs
+        unsafe { (self.0.wait_stop)(self.0.context, millis) }
+    }
+}
+
+/// # Safety
+/// `handle` must have been returned by the create function for the same `T`, and must not be used
+/// afterwards.
+pub unsafe extern "C" fn component_destroy<T>(handle: *mut c_void) {
+    if !handle.is_null() {
+        // A panicking destructor has nowhere to report to, but must not unwind into the service.
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle as *mut T))));
     }
 }
 
@@ -242,7 +451,7 @@ pub unsafe extern "C" fn processor_process_items<T: FeedPlumberProcessor>(
 macro_rules! feed_plumber_fatal {
     ($msg:tt) => {
         return Ok(vec![vec![(
-            "FEED_PLUMBER_FATAL".to_owned(),
+            $crate::sys::ERROR_KEY_FATAL.to_owned(),
             format!("{}", format_args!($msg)),
         )]]);
     };
@@ -250,28 +459,57 @@ macro_rules! feed_plumber_fatal {
 
 pub(crate) mod raw {
     use std::{
+        any::Any,
         ffi::{c_char, CStr, CString},
         mem::forget,
+        panic::{catch_unwind, AssertUnwindSafe},
         ptr::null_mut,
     };
 
     use sys_feed_plumber_plugin::{CreationResult, Item, Items, KeyValuePair};
 
+    /// Runs a component constructor, turning errors and panics into a [`CreationResult`].
+    pub fn catch_creation<T>(create: impl FnOnce() -> anyhow::Result<T>) -> CreationResult {
+        match catch_unwind(AssertUnwindSafe(create)) {
+            Ok(res) => result_to_creation_result(res),
+            Err(panic) => CreationResult {
+                handle: null_mut(),
+                message: string_to_pointer(panic_message(panic)),
+                panicked: true,
+                destroy_message: destroy_string,
+            },
+        }
+    }
+
     pub fn result_to_creation_result<T>(result: anyhow::Result<T>) -> CreationResult {
         match result {
             Ok(v) => CreationResult {
                 handle: Box::into_raw(Box::new(v)) as _,
                 message: null_mut(),
+                panicked: false,
                 destroy_message: destroy_string,
             },
             Err(err) => CreationResult {
                 handle: null_mut(),
                 message: string_to_pointer(format!("{err}")),
+                panicked: false,
                 destroy_message: destroy_string,
             },
         }
     }
 
+    pub fn panic_message(panic: Box<dyn Any + Send>) -> String {
+        panic
+            .downcast_ref::<&str>()
+            .map(|a| (*a).to_owned())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Plugin panicked with a non-string payload".to_owned())
+    }
+
+    pub unsafe fn error_items(key: &str, message: String) -> Items {
+        vec_to_items(vec![vec![(key.to_owned(), message)]])
+    }
+
     pub unsafe extern "C" fn pair_destroy(ptr1: *mut c_char, ptr2: *mut c_char) {
         destroy_string(ptr1);
         destroy_string(ptr2);
@@ -290,7 +528,14 @@ pub(crate) mod raw {
     }
 
     pub fn string_to_pointer(a: String) -> *mut c_char {
-        CString::new(a).unwrap().into_raw()
+        // Interior NULs cannot cross the ABI, drop them rather than failing the whole batch.
+        CString::new(a)
+            .unwrap_or_else(|err| {
+                let mut bytes = err.into_vec();
+                bytes.retain(|b| *b != 0);
+                CString::new(bytes).unwrap()
+            })
+            .into_raw()
     }
 
     pub unsafe extern "C" fn destroy_string(ptr: *mut c_char) {
@@ -319,6 +564,31 @@ pub(crate) mod raw {
         outer_vec
     }
 
+    /// Copies raw items into owned strings and releases them.
+    #[allow(dead_code)]
+    pub unsafe fn items_into_vec(items: Items) -> Vec<Vec<(String, String)>> {
+        let owned = items_to_vec(items)
+            .into_iter()
+            .map(|item| {
+                item.into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect()
+            })
+            .collect();
+        if !items.items.is_null() {
+            for item in std::slice::from_raw_parts(items.items, items.len) {
+                if !item.key_values.is_null() {
+                    for pair in std::slice::from_raw_parts(item.key_values, item.len) {
+                        (pair.destroy)(pair.key, pair.value);
+                    }
+                }
+                (item.destroy)(item.key_values, item.len);
+            }
+        }
+        (items.destroy)(items.items, items.len);
+        owned
+    }
+
     pub unsafe fn vec_to_items(items: Vec<Vec<(String, String)>>) -> Items {
         let items = items
             .into_iter()
@@ -0,0 +1,25 @@
+//! State that sources keep across restarts, saved as JSON.
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads what a source saved with [`save_state`], if it saved anything yet.
+pub fn load_state<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .with_context(|| format!("Reading state file \"{}\"", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Reading state file \"{}\"", path.display())),
+    }
+}
+
+/// Replaces the state file as a whole, so it is never left half-written.
+pub fn save_state<T: Serialize>(path: &Path, state: &T) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("Writing state file \"{}\"", path.display()))
+}
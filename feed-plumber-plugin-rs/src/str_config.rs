@@ -1,7 +1,9 @@
 use anyhow::Context;
-use std::ffi::{c_char, c_void, CStr};
+use std::ffi::{c_char, CStr};
 use sys_feed_plumber_plugin::CreationResult;
 
+use crate::Emitter;
+
 pub trait FeedPlumberSource: Sized + 'static {
     fn new(config: &str) -> anyhow::Result<Self>;
     fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>>;
@@ -9,7 +11,8 @@ pub trait FeedPlumberSource: Sized + 'static {
 
 pub trait FeedPlumberSink: Sized + 'static {
     fn new(config: &str) -> anyhow::Result<Self>;
-    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>);
+    /// Errors are reported as warnings, and the batch is not retried.
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()>;
 }
 
 pub trait FeedPlumberProcessor: Sized + 'static {
@@ -20,36 +23,95 @@ pub trait FeedPlumberProcessor: Sized + 'static {
     ) -> anyhow::Result<Vec<Vec<(String, String)>>>;
 }
 
+/// A processor that keeps state across batches and emits into its own pipelines.
+pub trait FeedPlumberStreamProcessor: Sized + 'static {
+    fn new(config: &str) -> anyhow::Result<Self>;
+    /// Receives a batch from `source`. Returns the items to emit right away, usually none.
+    fn receive_items(
+        &mut self,
+        source: &str,
+        items: Vec<Vec<(&str, &str)>>,
+    ) -> anyhow::Result<Vec<Vec<(String, String)>>>;
+    /// Called on the processor's schedule. Returns the items to emit.
+    fn flush(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        Ok(Vec::new())
+    }
+}
+
+/// A source that runs on a thread of its own and emits items as they arrive, rather than being
+/// polled.
+pub trait FeedPlumberEventSource: Sized + 'static {
+    fn new(config: &str) -> anyhow::Result<Self>;
+    /// Runs until `emitter` says to stop, emitting items as they arrive. Blocking calls should
+    /// time out now and then to check [`Emitter::stopping`]. Errors stop the source like a fatal
+    /// error.
+    fn run(&mut self, emitter: &Emitter) -> anyhow::Result<()>;
+}
+
+/// # Safety
+/// `config` must be a valid, NUL-terminated string.
 pub unsafe extern "C" fn source_create<T: FeedPlumberSource>(
     config: *const c_char,
 ) -> CreationResult {
-    let cstr = CStr::from_ptr(config);
-    let config = cstr.to_str().unwrap();
-    crate::raw::result_to_creation_result(
+    crate::raw::catch_creation(|| {
+        let cstr = CStr::from_ptr(config);
+        let config = cstr.to_str().unwrap();
         T::new(config)
             .context("Initializing source")
-            .context("Creating source"),
-    )
+            .context("Creating source")
+    })
 }
 
+/// # Safety
+/// `config` must be a valid, NUL-terminated string.
 pub unsafe extern "C" fn sink_create<T: FeedPlumberSink>(config: *const c_char) -> CreationResult {
-    let cstr = CStr::from_ptr(config);
-    let config = cstr.to_str().unwrap();
-    crate::raw::result_to_creation_result(
+    crate::raw::catch_creation(|| {
+        let cstr = CStr::from_ptr(config);
+        let config = cstr.to_str().unwrap();
         T::new(config)
             .context("Initializing sink")
-            .context("Creating sink"),
-    )
+            .context("Creating sink")
+    })
 }
 
+/// # Safety
+/// `config` must be a valid, NUL-terminated string.
 pub unsafe extern "C" fn processor_create<T: FeedPlumberProcessor>(
     config: *const c_char,
 ) -> CreationResult {
-    let cstr = CStr::from_ptr(config);
-    let config = cstr.to_str().unwrap();
-    crate::raw::result_to_creation_result(
+    crate::raw::catch_creation(|| {
+        let cstr = CStr::from_ptr(config);
+        let config = cstr.to_str().unwrap();
         T::new(config)
             .context("Initializing processor")
-            .context("Creating processor"),
-    )
+            .context("Creating processor")
+    })
+}
+
+/// # Safety
+/// `config` must be a valid, NUL-terminated string.
+pub unsafe extern "C" fn stream_processor_create<T: FeedPlumberStreamProcessor>(
+    config: *const c_char,
+) -> CreationResult {
+    crate::raw::catch_creation(|| {
+        let cstr = CStr::from_ptr(config);
+        let config = cstr.to_str().unwrap();
+        T::new(config)
+            .context("Initializing stream processor")
+            .context("Creating stream processor")
+    })
+}
+
+/// # Safety
+/// `config` must be a valid, NUL-terminated string.
+pub unsafe extern "C" fn event_source_create<T: FeedPlumberEventSource>(
+    config: *const c_char,
+) -> CreationResult {
+    crate::raw::catch_creation(|| {
+        let cstr = CStr::from_ptr(config);
+        let config = cstr.to_str().unwrap();
+        T::new(config)
+            .context("Initializing event source")
+            .context("Creating event source")
+    })
 }
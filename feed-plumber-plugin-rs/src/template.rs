@@ -0,0 +1,10 @@
+//! Filters shared by the components rendering [minijinja](https://docs.rs/minijinja) templates.
+
+use minijinja::{Error, ErrorKind, Value};
+
+/// The `json` filter, quoting a value as JSON.
+pub fn json(value: &Value) -> Result<String, Error> {
+    serde_json::to_string(value).map_err(|err| {
+        Error::new(ErrorKind::InvalidOperation, "cannot serialize to JSON").with_source(err)
+    })
+}
@@ -0,0 +1,243 @@
+//! Adapts the native plugin ABI to the WebAssembly ABI described in
+//! [`sys_feed_plumber_plugin::wasm`]. The exports themselves are generated by
+//! [`crate::feed_plumber_plugin`] and forward here.
+
+use std::{
+    cell::{OnceCell, RefCell},
+    ffi::{c_void, CStr, CString},
+    ptr, slice,
+};
+
+use sys_feed_plumber_plugin::{
+    wasm::{pack, CREATED, CREATE_FAILED, CREATE_PANICKED, KIND_PROCESSOR, KIND_SINK, KIND_SOURCE},
+    wire::{Reader, Writer},
+    FeedPlumberPlugin, FeedPlumberProcessorMeta, FeedPlumberSinkMeta, FeedPlumberSourceMeta, Items,
+};
+
+use crate::raw::{items_into_vec, vec_to_items};
+
+enum Component {
+    Source(FeedPlumberSourceMeta, *mut c_void),
+    Sink(FeedPlumberSinkMeta, *mut c_void),
+    Processor(FeedPlumberProcessorMeta, *mut c_void),
+}
+
+thread_local! {
+    static PLUGIN: OnceCell<FeedPlumberPlugin> = const { OnceCell::new() };
+    static COMPONENTS: RefCell<Vec<Option<Component>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn with_plugin<R>(
+    init: extern "C" fn() -> FeedPlumberPlugin,
+    f: impl FnOnce(&FeedPlumberPlugin) -> R,
+) -> R {
+    PLUGIN.with(|plugin| f(plugin.get_or_init(|| init())))
+}
+
+unsafe fn metas<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if ptr.is_null() || len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(ptr, len)
+    }
+}
+
+fn leak_buffer(buffer: Vec<u8>) -> u64 {
+    let buffer = Box::leak(buffer.into_boxed_slice());
+    pack(buffer.as_mut_ptr() as u32, buffer.len() as u32)
+}
+
+unsafe fn leak_items(items: Items) -> u64 {
+    let mut writer = Writer::new();
+    writer.items(&items_into_vec(items));
+    leak_buffer(writer.0)
+}
+
+pub fn alloc(len: u32) -> u32 {
+    let buffer = Box::leak(vec![0u8; len as usize].into_boxed_slice());
+    buffer.as_mut_ptr() as u32
+}
+
+/// # Safety
+/// `ptr` and `len` must describe a buffer returned by [`alloc`] or by another export.
+pub unsafe fn free(ptr: u32, len: u32) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        ptr as *mut u8,
+        len as usize,
+    )));
+}
+
+pub fn manifest(init: extern "C" fn() -> FeedPlumberPlugin) -> u64 {
+    let mut writer = Writer::new();
+    with_plugin(init, |plugin| unsafe {
+        let sources = metas(plugin.sources, plugin.sources_len);
+        writer.u32(sources.len() as u32);
+        for meta in sources {
+            writer.str(&meta.name.as_cstr().to_string_lossy());
+        }
+        let sinks = metas(plugin.sinks, plugin.sinks_len);
+        writer.u32(sinks.len() as u32);
+        for meta in sinks {
+            writer.str(&meta.name.as_cstr().to_string_lossy());
+        }
+        let processors = metas(plugin.processors, plugin.processors_len);
+        writer.u32(processors.len() as u32);
+        for meta in processors {
+            writer.str(&meta.name.as_cstr().to_string_lossy());
+        }
+    });
+    leak_buffer(writer.0)
+}
+
+/// # Safety
+/// The pointers must describe valid UTF-8 buffers in guest memory.
+pub unsafe fn create(
+    init: extern "C" fn() -> FeedPlumberPlugin,
+    kind: u32,
+    type_ptr: u32,
+    type_len: u32,
+    config_ptr: u32,
+    config_len: u32,
+) -> u64 {
+    let r#type = slice::from_raw_parts(type_ptr as *const u8, type_len as usize);
+    let config = slice::from_raw_parts(config_ptr as *const u8, config_len as usize);
+    let Ok(config) = CString::new(config) else {
+        let mut writer = Writer::new();
+        writer.u8(CREATE_FAILED);
+        writer.str("Config contains a NUL byte");
+        return leak_buffer(writer.0);
+    };
+    let matches = |name: &CStr| name.to_bytes() == r#type;
+    let created = with_plugin(init, |plugin| match kind {
+        KIND_SOURCE => metas(plugin.sources, plugin.sources_len)
+            .iter()
+            .find(|meta| matches(meta.name.as_cstr()))
+            .map(|meta| {
+                let res = (meta.create)(config.as_ptr());
+                (res, Component::Source(*meta, res.handle))
+            }),
+        KIND_SINK => metas(plugin.sinks, plugin.sinks_len)
+            .iter()
+            .find(|meta| matches(meta.name.as_cstr()))
+            .map(|meta| {
+                let res = (meta.create)(config.as_ptr());
+                (res, Component::Sink(*meta, res.handle))
+            }),
+        KIND_PROCESSOR => metas(plugin.processors, plugin.processors_len)
+            .iter()
+            .find(|meta| matches(meta.name.as_cstr()))
+            .map(|meta| {
+                let res = (meta.create)(config.as_ptr());
+                (res, Component::Processor(*meta, res.handle))
+            }),
+        _ => None,
+    });
+
+    let mut writer = Writer::new();
+    match created {
+        Some((res, component)) if !res.handle.is_null() => {
+            let handle = COMPONENTS.with(|components| {
+                let mut components = components.borrow_mut();
+                components.push(Some(component));
+                components.len() as u32
+            });
+            writer.u8(CREATED);
+            writer.u32(handle);
+        }
+        Some((res, _)) => {
+            writer.u8(if res.panicked {
+                CREATE_PANICKED
+            } else {
+                CREATE_FAILED
+            });
+            if res.message.is_null() {
+                writer.str("Unknown error during creation");
+            } else {
+                writer.str(&CStr::from_ptr(res.message).to_string_lossy());
+                (res.destroy_message)(res.message);
+            }
+        }
+        None => {
+            writer.u8(CREATE_FAILED);
+            writer.str("Plugin does not supply this type");
+        }
+    }
+    leak_buffer(writer.0)
+}
+
+fn take_component(handle: u32) -> Option<Component> {
+    COMPONENTS.with(|components| {
+        components
+            .borrow_mut()
+            .get_mut((handle as usize).wrapping_sub(1))
+            .and_then(Option::take)
+    })
+}
+
+fn put_component(handle: u32, component: Component) {
+    COMPONENTS.with(|components| {
+        components.borrow_mut()[handle as usize - 1] = Some(component);
+    });
+}
+
+unsafe fn read_items(ptr: u32, len: u32) -> Items {
+    let buffer = slice::from_raw_parts(ptr as *const u8, len as usize);
+    vec_to_items(Reader(buffer).items().unwrap_or_default())
+}
+
+/// # Safety
+/// `handle` must have been returned by [`create`].
+pub unsafe fn poll_source(handle: u32) -> u64 {
+    let Some(component) = take_component(handle) else {
+        return leak_buffer(Vec::new());
+    };
+    let items = match &component {
+        Component::Source(meta, ptr) => (meta.poll_source)(*ptr),
+        _ => vec_to_items(Vec::new()),
+    };
+    put_component(handle, component);
+    leak_items(items)
+}
+
+/// # Safety
+/// `handle` must have been returned by [`create`], and the items buffer must be valid.
+pub unsafe fn sink_items(handle: u32, items_ptr: u32, items_len: u32) -> u64 {
+    let Some(component) = take_component(handle) else {
+        return leak_buffer(Vec::new());
+    };
+    let input = read_items(items_ptr, items_len);
+    let items = match &component {
+        Component::Sink(meta, ptr) => (meta.sink_items)(*ptr, input),
+        _ => vec_to_items(Vec::new()),
+    };
+    items_into_vec(input);
+    put_component(handle, component);
+    leak_items(items)
+}
+
+/// # Safety
+/// `handle` must have been returned by [`create`], and the items buffer must be valid.
+pub unsafe fn process_items(handle: u32, items_ptr: u32, items_len: u32) -> u64 {
+    let Some(component) = take_component(handle) else {
+        return leak_buffer(Vec::new());
+    };
+    let input = read_items(items_ptr, items_len);
+    let items = match &component {
+        Component::Processor(meta, ptr) => (meta.process_items)(*ptr, input),
+        _ => vec_to_items(Vec::new()),
+    };
+    items_into_vec(input);
+    put_component(handle, component);
+    leak_items(items)
+}
+
+/// # Safety
+/// `handle` must have been returned by [`create`] and not be used afterwards.
+pub unsafe fn destroy(handle: u32) {
+    match take_component(handle) {
+        Some(Component::Source(meta, ptr)) => (meta.destroy)(ptr),
+        Some(Component::Sink(meta, ptr)) => (meta.destroy)(ptr),
+        Some(Component::Processor(meta, ptr)) => (meta.destroy)(ptr),
+        None => {}
+    }
+}
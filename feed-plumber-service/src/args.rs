@@ -15,4 +15,8 @@ pub struct Opts {
     /// Additional plugin paths (plugin binaries directly). Multiple can be used.
     #[arg(long, short)]
     pub plugins: Vec<PathBuf>,
+
+    /// Run as an isolated plugin host connected to the given socket. Used internally.
+    #[arg(long, hide = true)]
+    pub plugin_host: Option<PathBuf>,
 }
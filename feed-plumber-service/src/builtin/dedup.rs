@@ -0,0 +1,264 @@
+//! The `dedup` processor drops items it has seen before, so sources don't each have to remember
+//! what they emitted.
+//!
+//! Items are identified by the value of a key, or by a hash of the values of several keys. Every
+//! sighting refreshes an identity, so an item that stays in a feed stays seen. Identities are
+//! forgotten after `ttl`, or once there are more than `max_entries` (least recently seen first,
+//! never those of the latest batch), and are kept in `state_file` across restarts.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use chrono::Utc;
+use feed_plumber_plugin_rs::state::{load_state, save_state};
+use log::{debug, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::sys::{FeedPlumberComponentError, Items, ProcessorComponent};
+
+pub const TYPE_NAME: &str = "dedup";
+
+#[derive(Deserialize)]
+struct DedupConfig {
+    /// The keys identifying an item.
+    keys: Vec<String>,
+    /// Whether a single key is hashed too, e.g. to keep long values out of the state file.
+    #[serde(default)]
+    hash: bool,
+    /// Seconds after which an item that has not been seen again is forgotten.
+    ttl: Option<u64>,
+    /// The most items remembered.
+    max_entries: Option<usize>,
+    /// Where the seen items are kept. Defaults to `dedup-<name>.json`.
+    state_file: Option<PathBuf>,
+}
+
+pub struct DedupProcessor {
+    keys: Vec<String>,
+    hash: bool,
+    ttl: Option<i64>,
+    max_entries: Option<usize>,
+    state_file: PathBuf,
+    /// When each identity was last seen, as a unix timestamp.
+    seen: HashMap<String, i64>,
+}
+
+impl DedupProcessor {
+    pub fn new(name: &str, config: &str) -> Result<Self, String> {
+        let (config, ttl) = parse_config(config)?;
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("dedup-{name}.json")));
+        let seen = load_state(&state_file)
+            .map_err(|err| format!("{err:#}"))?
+            .unwrap_or_default();
+        Ok(Self {
+            keys: config.keys,
+            hash: config.hash,
+            ttl,
+            max_entries: config.max_entries,
+            state_file,
+            seen,
+        })
+    }
+
+    /// What identifies the item, if it has any of the keys.
+    fn identity(&self, item: &[(String, String)]) -> Option<String> {
+        let values = self
+            .keys
+            .iter()
+            .map(|key| item.iter().find(|(k, _)| k == key).map(|(_, value)| value))
+            .collect::<Vec<_>>();
+        if values.iter().all(Option::is_none) {
+            return None;
+        }
+        if !self.hash && values.len() == 1 {
+            return values[0].cloned();
+        }
+        let mut hasher = Sha256::new();
+        for value in values {
+            match value {
+                Some(value) => {
+                    hasher.update([1]);
+                    hasher.update((value.len() as u64).to_le_bytes());
+                    hasher.update(value.as_bytes());
+                }
+                None => hasher.update([0]),
+            }
+        }
+        Some(
+            hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        )
+    }
+
+    fn forget_expired(&mut self, now: i64) {
+        if let Some(ttl) = self.ttl {
+            self.seen.retain(|_, seen| now - *seen < ttl);
+        }
+    }
+
+    /// Forgets the least recently seen identities past `max_entries`, except those of the batch
+    /// at hand, so none of its items come through again with the next batch.
+    fn forget_oldest(&mut self, batch: &HashSet<String>) {
+        let Some(max_entries) = self.max_entries else {
+            return;
+        };
+        if self.seen.len() <= max_entries {
+            return;
+        }
+        let mut by_age = self
+            .seen
+            .iter()
+            .filter(|(identity, _)| !batch.contains(*identity))
+            .map(|(identity, seen)| (*seen, identity.clone()))
+            .collect::<Vec<_>>();
+        by_age.sort_unstable();
+        let excess = (self.seen.len() - max_entries).min(by_age.len());
+        for (_, identity) in &by_age[..excess] {
+            self.seen.remove(identity);
+        }
+    }
+
+    /// Checks `config` without reading or creating the state file.
+    pub fn validate(config: &str) -> Result<(), String> {
+        parse_config(config).map(drop)
+    }
+}
+
+/// Parses and checks the config, along with its `ttl` as used for timestamps.
+fn parse_config(config: &str) -> Result<(DedupConfig, Option<i64>), String> {
+    let config = toml::from_str::<DedupConfig>(config)
+        .map_err(|err| format!("Invalid dedup config: {err}"))?;
+    if config.keys.is_empty() {
+        return Err("Invalid dedup config: `keys` cannot be empty".to_owned());
+    }
+    if config.max_entries == Some(0) {
+        return Err("Invalid dedup config: `max_entries` must be at least 1".to_owned());
+    }
+    let ttl = config
+        .ttl
+        .map(i64::try_from)
+        .transpose()
+        .map_err(|_| "Invalid dedup config: `ttl` is too large".to_owned())?;
+    Ok((config, ttl))
+}
+
+impl ProcessorComponent for DedupProcessor {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let now = Utc::now().timestamp();
+        self.forget_expired(now);
+        let mut batch = HashSet::new();
+        let mut out = Vec::new();
+        for item in items.items() {
+            let Some(identity) = self.identity(item) else {
+                debug!(
+                    "Passing through an item without any of the keys {:?}",
+                    &self.keys
+                );
+                out.push(item.clone());
+                continue;
+            };
+            if self.seen.insert(identity.clone(), now).is_none() {
+                out.push(item.clone());
+            }
+            batch.insert(identity);
+        }
+        self.forget_oldest(&batch);
+        if !items.is_empty() {
+            if let Err(err) = save_state(&self.state_file, &self.seen) {
+                warn!("Failed to save dedup state: {err:#}");
+            }
+        }
+        Ok(Items::from(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    fn dedup(state_file: &Path, config: &str) -> DedupProcessor {
+        DedupProcessor::new(
+            "test",
+            &format!(
+                "state_file = {:?}\n{config}",
+                state_file.display().to_string()
+            ),
+        )
+        .unwrap()
+    }
+
+    /// The ids of the items that come through.
+    fn process(dedup: &mut DedupProcessor, ids: &[&str]) -> Vec<String> {
+        let items = ids
+            .iter()
+            .map(|id| vec![("id".to_owned(), id.to_string())])
+            .collect::<Vec<_>>();
+        let Ok(items) = dedup.process_items(&Items::from(items)) else {
+            panic!("Processing failed");
+        };
+        items.items().map(|item| item[0].1.clone()).collect()
+    }
+
+    #[test]
+    fn keeps_a_batch_larger_than_max_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dedup = dedup(
+            &dir.path().join("seen.json"),
+            "keys = [\"id\"]\nmax_entries = 2",
+        );
+        assert_eq!(process(&mut dedup, &["a", "b", "c", "a"]), ["a", "b", "c"]);
+        assert_eq!(process(&mut dedup, &["a", "b", "c"]), Vec::<String>::new());
+        // Once a batch no longer has them, they are forgotten down to `max_entries`
+        assert_eq!(process(&mut dedup, &["d"]), ["d"]);
+        assert_eq!(process(&mut dedup, &["a", "d"]), ["a"]);
+    }
+
+    #[test]
+    fn keeps_a_batch_past_its_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dedup = dedup(&dir.path().join("seen.json"), "keys = [\"id\"]\nttl = 0");
+        assert_eq!(process(&mut dedup, &["a", "a", "b"]), ["a", "b"]);
+        assert_eq!(process(&mut dedup, &["a"]), ["a"]);
+    }
+
+    #[test]
+    fn remembers_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("seen.json");
+        assert_eq!(
+            process(&mut dedup(&state_file, "keys = [\"id\"]"), &["a"]),
+            ["a"]
+        );
+        assert_eq!(
+            process(&mut dedup(&state_file, "keys = [\"id\"]"), &["a", "b"]),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn validates_without_touching_the_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("seen.json");
+        fs::write(&state_file, "corrupt").unwrap();
+        let config = format!(
+            "keys = [\"id\"]\nstate_file = {:?}",
+            state_file.display().to_string()
+        );
+        assert_eq!(DedupProcessor::validate(&config), Ok(()));
+        assert!(DedupProcessor::new("test", &config).is_err());
+        assert_eq!(
+            DedupProcessor::validate("keys = []"),
+            Err("Invalid dedup config: `keys` cannot be empty".to_owned())
+        );
+    }
+}
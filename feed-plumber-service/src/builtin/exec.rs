@@ -0,0 +1,390 @@
+//! The `exec` type runs an external command, exchanging items as JSON lines.
+//!
+//! - As a source, the command is run on every poll and each line of its output becomes an item.
+//! - As a processor, the items are written to its stdin and each line of its output becomes an item.
+//! - As a sink, the items are written to its stdin and its output is ignored.
+//!
+//! Each line is a JSON object whose fields are the item's keys. A key that appears more than once
+//! in an item is written as an array of its values, and arrays read back become one pair per
+//! value. Commands can report errors the same way plugins do, by emitting a single
+//! `{"FEED_PLUMBER_WARN": "..."}` or `FEED_PLUMBER_FATAL` item.
+//!
+//! On Unix, a command runs in its own process group, so whatever it starts is killed with it when
+//! it times out.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Receiver;
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::sys::{
+    items_with_error_to_result, FeedPlumberComponentError, Items, ProcessorComponent,
+    SinkComponent, SourceComponent,
+};
+
+pub const TYPE_NAME: &str = "exec";
+
+const DEFAULT_TIMEOUT: u64 = 30_000;
+/// How much of stderr is quoted in error messages.
+const STDERR_EXCERPT_LEN: usize = 1024;
+/// How long the output of a command that finished right at its timeout is still read for.
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize)]
+struct ExecConfig {
+    /// The program to run. It is not passed through a shell.
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Extra environment variables for the command.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Whether the command inherits the service's environment.
+    #[serde(default = "default_inherit_env")]
+    inherit_env: bool,
+    working_directory: Option<PathBuf>,
+    /// Milliseconds the command may run before it is killed.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Exit codes that disable the component instead of skipping the batch.
+    #[serde(default)]
+    fatal_exit_codes: Vec<i32>,
+}
+
+#[inline]
+const fn default_inherit_env() -> bool {
+    true
+}
+
+#[inline]
+const fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+pub struct ExecComponent {
+    config: ExecConfig,
+}
+
+impl ExecComponent {
+    pub fn new(config: &str) -> Result<Self, String> {
+        let config = toml::from_str::<ExecConfig>(config)
+            .map_err(|err| format!("Invalid exec config: {err}"))?;
+        Ok(Self { config })
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.config.command);
+        command.args(&self.config.args);
+        if !self.config.inherit_env {
+            command.env_clear();
+        }
+        command.envs(&self.config.env);
+        if let Some(dir) = &self.config.working_directory {
+            command.current_dir(dir);
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        command
+    }
+
+    /// Runs the command to completion, feeding it `input` and returning its stdout.
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>, FeedPlumberComponentError> {
+        let mut child = self.command().spawn().map_err(|err| {
+            FeedPlumberComponentError::Warn(format!(
+                "Unable to run \"{}\": {err}",
+                &self.config.command
+            ))
+        })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            thread::spawn(move || {
+                // A command that does not read its input closes the pipe early, which is fine.
+                let _ = stdin.write_all(&input);
+            });
+        }
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let timed_out = || {
+            FeedPlumberComponentError::Warn(format!(
+                "\"{}\" timed out after {} ms",
+                &self.config.command, self.config.timeout
+            ))
+        };
+        let deadline = Instant::now() + Duration::from_millis(self.config.timeout);
+        let status = wait_until(&mut child, deadline).ok_or_else(timed_out)?;
+        // What the command left running in the background can keep its output open, so that is
+        // not waited for past the timeout either
+        let deadline = deadline.max(Instant::now() + OUTPUT_GRACE);
+        let stdout = stdout.recv_deadline(deadline).map_err(|_| timed_out())?;
+        if status.success() {
+            return Ok(stdout);
+        }
+        let stderr = stderr.recv_deadline(deadline).unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr);
+        let stderr = stderr.trim();
+        let excerpt = match stderr.char_indices().nth(STDERR_EXCERPT_LEN) {
+            Some((idx, _)) => &stderr[..idx],
+            None => stderr,
+        };
+        let message = format!(
+            "\"{}\" exited with {status}: {excerpt}",
+            &self.config.command
+        );
+        match status.code() {
+            Some(code) if self.config.fatal_exit_codes.contains(&code) => {
+                Err(FeedPlumberComponentError::Fatal(message))
+            }
+            _ => Err(FeedPlumberComponentError::Warn(message)),
+        }
+    }
+}
+
+impl SourceComponent for ExecComponent {
+    fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError> {
+        let output = self.run(Vec::new())?;
+        items_with_error_to_result(parse_json_lines(&output)?)
+    }
+}
+
+impl SinkComponent for ExecComponent {
+    fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError> {
+        let output = self.run(to_json_lines(items))?;
+        if !output.is_empty() {
+            debug!(
+                "\"{}\" said: {}",
+                &self.config.command,
+                String::from_utf8_lossy(&output).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ProcessorComponent for ExecComponent {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let output = self.run(to_json_lines(items))?;
+        items_with_error_to_result(parse_json_lines(&output)?)
+    }
+}
+
+/// Reads all of `pipe` on another thread, sending it once the pipe is closed.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = crossbeam::channel::bounded(1);
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        let _ = sender.send(buf);
+    });
+    receiver
+}
+
+/// Waits for the command to exit, killing it and everything it started at `deadline`.
+fn wait_until(child: &mut Child, deadline: Instant) -> Option<ExitStatus> {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if Instant::now() < deadline => sleep(Duration::from_millis(10)),
+            _ => {
+                // SAFETY: killpg only sends a signal. The command's process group has its pid, and
+                // the group still exists as the command has not been waited for.
+                #[cfg(unix)]
+                unsafe {
+                    libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+                }
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+}
+
+fn to_json_lines(items: &Items) -> Vec<u8> {
+    let mut out = Vec::new();
+    for item in items.items() {
+        let mut object = Map::new();
+        for (key, value) in item {
+            let value = Value::String(value.clone());
+            match object.get_mut(key) {
+                None => {
+                    object.insert(key.clone(), value);
+                }
+                Some(Value::Array(values)) => values.push(value),
+                Some(first) => *first = Value::Array(vec![first.take(), value]),
+            }
+        }
+        // Serializing a map of strings cannot fail
+        serde_json::to_writer(&mut out, &object).unwrap();
+        out.push(b'\n');
+    }
+    out
+}
+
+fn parse_json_lines(output: &[u8]) -> Result<Items, FeedPlumberComponentError> {
+    let output = String::from_utf8_lossy(output);
+    let mut items = Vec::new();
+    for (idx, line) in output.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object = serde_json::from_str::<Map<String, Value>>(line).map_err(|err| {
+            FeedPlumberComponentError::Warn(format!(
+                "Output line {} is not a JSON object: {err}",
+                idx + 1
+            ))
+        })?;
+        let mut item = Vec::new();
+        for (key, value) in object {
+            let values = match value {
+                Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    Value::Null => {}
+                    Value::String(value) => item.push((key.clone(), value)),
+                    value => item.push((key.clone(), value.to_string())),
+                }
+            }
+        }
+        items.push(item);
+    }
+    Ok(Items::from(items))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn exec(config: &str) -> ExecComponent {
+        ExecComponent::new(&format!("command = \"sh\"\n{config}")).unwrap()
+    }
+
+    fn item(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn warning(res: Result<Items, FeedPlumberComponentError>) -> String {
+        match res {
+            Err(FeedPlumberComponentError::Warn(err)) => err,
+            Err(_) => panic!("Failed with something other than a warning"),
+            Ok(items) => panic!("Succeeded with {:?}", items.items().collect::<Vec<_>>()),
+        }
+    }
+
+    #[test]
+    fn source_reads_json_lines() {
+        let mut source = exec(
+            r#"args = ["-c", "printf '%s\\n\\n%s\\n' '{\"title\": \"A\", \"tags\": [\"x\", 2], \"score\": 1.5, \"gone\": null}' '{\"title\": \"B\"}'"]"#,
+        );
+        let Ok(items) = source.poll_source() else {
+            panic!("Polling failed");
+        };
+        assert_eq!(
+            items.items().cloned().collect::<Vec<_>>(),
+            [
+                item(&[
+                    ("title", "A"),
+                    ("tags", "x"),
+                    ("tags", "2"),
+                    ("score", "1.5")
+                ]),
+                item(&[("title", "B")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn processor_round_trips_repeated_keys() {
+        let mut processor = exec(r#"args = ["-c", "cat"]"#);
+        let items = vec![
+            item(&[("title", "A"), ("category", "x"), ("category", "y")]),
+            item(&[("title", "B \"quoted\"\nline")]),
+        ];
+        let Ok(processed) = processor.process_items(&Items::from(items.clone())) else {
+            panic!("Processing failed");
+        };
+        assert_eq!(processed.items().cloned().collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn sink_writes_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out.jsonl");
+        let mut sink = exec(&format!("args = [\"-c\", \"cat > '{}'\"]", out.display()));
+        let items = Items::from(vec![item(&[
+            ("title", "A"),
+            ("category", "x"),
+            ("category", "y"),
+        ])]);
+        assert!(sink.sink_items(&items).is_ok());
+        assert_eq!(
+            fs::read_to_string(out).unwrap(),
+            "{\"title\":\"A\",\"category\":[\"x\",\"y\"]}\n"
+        );
+    }
+
+    #[test]
+    fn kills_what_the_command_started_on_timeout() {
+        // The background sleep holds on to stdout, which alone used to keep the source waiting
+        let mut source = exec(
+            r#"args = ["-c", "sleep 10 & sleep 10"]
+timeout = 200"#,
+        );
+        let started = Instant::now();
+        assert_eq!(
+            warning(source.poll_source()),
+            "\"sh\" timed out after 200 ms"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn rejects_malformed_output() {
+        let mut source = exec(r#"args = ["-c", "echo '{}'; echo '[1, 2]'"]"#);
+        assert!(warning(source.poll_source()).starts_with("Output line 2 is not a JSON object"));
+        let mut source = exec(r#"args = ["-c", "echo '{\"title\": '"]"#);
+        assert!(warning(source.poll_source()).starts_with("Output line 1 is not a JSON object"));
+    }
+
+    #[test]
+    fn reports_failing_commands() {
+        let mut source = exec(
+            r#"args = ["-c", "echo broken >&2; exit 3"]
+fatal_exit_codes = [4]"#,
+        );
+        assert_eq!(
+            warning(source.poll_source()),
+            "\"sh\" exited with exit status: 3: broken"
+        );
+        let mut source = exec(
+            r#"args = ["-c", "exit 4"]
+fatal_exit_codes = [4]"#,
+        );
+        assert!(matches!(
+            source.poll_source(),
+            Err(FeedPlumberComponentError::Fatal(_))
+        ));
+    }
+}
@@ -0,0 +1,531 @@
+//! The `filter` processor keeps (or drops) the items matching an expression.
+//!
+//! Expressions compare item keys with literals:
+//!
+//! - `key == "value"`, `key != "value"`
+//! - `key contains "part"`, `key starts_with "prefix"`, `key ends_with "suffix"`
+//! - `key =~ "regex"`, `key !~ "regex"`
+//! - `key < 10`, `<=`, `>`, `>=` compare numerically against numbers, as dates against
+//!   `date("2024-01-31")` or `ago("7d")`, and as strings otherwise
+//! - `key` on its own checks that the item has the key
+//!
+//! They can be combined with `and` (`&&`), `or` (`||`), `not` (`!`) and parentheses. A comparison
+//! holds if any pair with that key satisfies it, except for `!=` and `!~`, which hold if none of
+//! them matches. No comparison holds for items without the key.
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, Utc};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::sys::{FeedPlumberComponentError, Items, ProcessorComponent};
+
+pub const TYPE_NAME: &str = "filter";
+
+#[derive(Deserialize)]
+struct FilterConfig {
+    expression: String,
+    /// Whether matching items are kept or dropped.
+    #[serde(default)]
+    action: FilterAction,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FilterAction {
+    #[default]
+    Keep,
+    Drop,
+}
+
+pub struct FilterProcessor {
+    expression: Expr,
+    action: FilterAction,
+}
+
+impl FilterProcessor {
+    pub fn new(config: &str) -> Result<Self, String> {
+        let config = toml::from_str::<FilterConfig>(config)
+            .map_err(|err| format!("Invalid filter config: {err}"))?;
+        let expression = syntax::parse(&config.expression)
+            .map_err(|rest| {
+                format!(
+                    "Invalid filter expression \"{}\" at \"{rest}\"",
+                    &config.expression
+                )
+            })
+            .and_then(compile)?;
+        Ok(Self {
+            expression,
+            action: config.action,
+        })
+    }
+}
+
+impl ProcessorComponent for FilterProcessor {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let now = Utc::now().fixed_offset();
+        Ok(Items::from(
+            items
+                .items()
+                .filter(|item| {
+                    self.expression.eval(item, now) == (self.action == FilterAction::Keep)
+                })
+                .cloned()
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Matches,
+    NotMatches,
+}
+
+/// A literal as written, before it is checked against the operator it is used with.
+#[derive(Debug, Clone)]
+enum Literal {
+    Str(String),
+    Number(f64),
+    Date(String),
+    Ago(String),
+}
+
+#[derive(Debug, Clone)]
+enum RawExpr {
+    Or(Vec<RawExpr>),
+    And(Vec<RawExpr>),
+    Not(Box<RawExpr>),
+    Exists(String),
+    Compare(String, Op, Literal),
+}
+
+enum Value {
+    Str(String),
+    Number(f64),
+    Date(DateTime<FixedOffset>),
+    Ago(Duration),
+    Regex(Regex),
+}
+
+enum Expr {
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    Exists(String),
+    Compare(String, Op, Value),
+}
+
+fn compile(raw: RawExpr) -> Result<Expr, String> {
+    Ok(match raw {
+        RawExpr::Or(exprs) => Expr::Or(exprs.into_iter().map(compile).collect::<Result<_, _>>()?),
+        RawExpr::And(exprs) => Expr::And(exprs.into_iter().map(compile).collect::<Result<_, _>>()?),
+        RawExpr::Not(expr) => Expr::Not(Box::new(compile(*expr)?)),
+        RawExpr::Exists(key) => Expr::Exists(key),
+        RawExpr::Compare(key, op, literal) => {
+            let value = match (op, literal) {
+                (Op::Matches | Op::NotMatches, Literal::Str(pattern)) => Value::Regex(
+                    Regex::new(&pattern)
+                        .map_err(|err| format!("Invalid regex for \"{key}\": {err}"))?,
+                ),
+                (Op::Matches | Op::NotMatches, _) => {
+                    return Err(format!("Regex for \"{key}\" must be a string"))
+                }
+                (Op::Contains | Op::StartsWith | Op::EndsWith, Literal::Str(s)) => Value::Str(s),
+                (Op::Contains | Op::StartsWith | Op::EndsWith, _) => {
+                    return Err(format!("{op:?} on \"{key}\" needs a string"))
+                }
+                (_, Literal::Str(s)) => Value::Str(s),
+                (_, Literal::Number(n)) => Value::Number(n),
+                (_, Literal::Date(date)) => Value::Date(
+                    parse_date(&date).ok_or_else(|| format!("Invalid date \"{date}\""))?,
+                ),
+                (_, Literal::Ago(ago)) => Value::Ago(
+                    parse_duration(&ago).ok_or_else(|| format!("Invalid duration \"{ago}\""))?,
+                ),
+            };
+            Expr::Compare(key, op, value)
+        }
+    })
+}
+
+impl Expr {
+    fn eval(&self, item: &[(String, String)], now: DateTime<FixedOffset>) -> bool {
+        fn values<'a>(item: &'a [(String, String)], key: &'a str) -> impl Iterator<Item = &'a str> {
+            item.iter()
+                .filter(move |(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        }
+        match self {
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.eval(item, now)),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.eval(item, now)),
+            Expr::Not(expr) => !expr.eval(item, now),
+            Expr::Exists(key) => values(item, key).next().is_some(),
+            Expr::Compare(key, Op::Ne, value) => {
+                let mut values = values(item, key).peekable();
+                values.peek().is_some() && !values.any(|v| compare(v, Op::Eq, value, now))
+            }
+            Expr::Compare(key, Op::NotMatches, value) => {
+                let mut values = values(item, key).peekable();
+                values.peek().is_some() && !values.any(|v| compare(v, Op::Matches, value, now))
+            }
+            Expr::Compare(key, op, value) => values(item, key).any(|v| compare(v, *op, value, now)),
+        }
+    }
+}
+
+fn compare(actual: &str, op: Op, value: &Value, now: DateTime<FixedOffset>) -> bool {
+    let ordering = match value {
+        Value::Regex(regex) => return regex.is_match(actual),
+        Value::Str(s) => match op {
+            Op::Contains => return actual.contains(s.as_str()),
+            Op::StartsWith => return actual.starts_with(s.as_str()),
+            Op::EndsWith => return actual.ends_with(s.as_str()),
+            _ => Some(actual.cmp(s.as_str())),
+        },
+        Value::Number(n) => actual
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(|actual| actual.partial_cmp(n)),
+        Value::Date(date) => parse_date(actual).map(|actual| actual.cmp(date)),
+        Value::Ago(ago) => parse_date(actual).map(|actual| actual.cmp(&(now - *ago))),
+    };
+    let Some(ordering) = ordering else {
+        return false;
+    };
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+        _ => false,
+    }
+}
+
+/// Accepts RFC 3339 and RFC 2822 (as found in feeds) dates and plain `YYYY-MM-DD` dates.
+fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.trim();
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_rfc2822(s))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|date| date.and_utc().fixed_offset())
+        })
+}
+
+/// Parses durations such as `30m`, `12h` or `7d`.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let unit = s.chars().last()?;
+    let amount = s[..s.len() - unit.len_utf8()].trim().parse::<i64>().ok()?;
+    match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+mod syntax {
+    use nom::{
+        branch::alt,
+        bytes::complete::{escaped_transform, tag},
+        character::complete::{alpha1, alphanumeric1, char, multispace0, none_of},
+        combinator::{all_consuming, map, not, opt, recognize, value, verify},
+        multi::{many0, separated_list1},
+        number::complete::double,
+        sequence::{delimited, pair, preceded, terminated, tuple},
+        Finish, IResult,
+    };
+
+    use super::{Literal, Op, RawExpr};
+
+    fn ws<'a, O>(
+        parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+    ) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+        delimited(multispace0, parser, multispace0)
+    }
+
+    fn ident_char(input: &str) -> IResult<&str, &str> {
+        alt((alphanumeric1, tag("_"), tag("-"), tag(".")))(input)
+    }
+
+    /// A word that is not the start of a longer key.
+    fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+        terminated(tag(word), not(ident_char))
+    }
+
+    fn key(input: &str) -> IResult<&str, String> {
+        map(
+            verify(
+                recognize(pair(alt((alpha1, tag("_"))), many0(ident_char))),
+                |key: &str| !matches!(key, "and" | "or" | "not"),
+            ),
+            ToOwned::to_owned,
+        )(input)
+    }
+
+    fn string(input: &str) -> IResult<&str, String> {
+        let quoted = |quote: char, other: &'static str| {
+            delimited(
+                char(quote),
+                map(
+                    opt(escaped_transform(
+                        none_of(other),
+                        '\\',
+                        alt((
+                            value("\\", tag("\\")),
+                            value("\"", tag("\"")),
+                            value("'", tag("'")),
+                            value("\n", tag("n")),
+                            value("\t", tag("t")),
+                        )),
+                    )),
+                    Option::unwrap_or_default,
+                ),
+                char(quote),
+            )
+        };
+        alt((quoted('"', "\\\""), quoted('\'', "\\'")))(input)
+    }
+
+    fn literal(input: &str) -> IResult<&str, Literal> {
+        alt((
+            map(string, Literal::Str),
+            map(
+                delimited(ws(tag("date(")), string, ws(char(')'))),
+                Literal::Date,
+            ),
+            map(
+                delimited(ws(tag("ago(")), string, ws(char(')'))),
+                Literal::Ago,
+            ),
+            map(double, Literal::Number),
+        ))(input)
+    }
+
+    fn op(input: &str) -> IResult<&str, Op> {
+        alt((
+            value(Op::Eq, tag("==")),
+            value(Op::Ne, tag("!=")),
+            value(Op::Matches, tag("=~")),
+            value(Op::NotMatches, tag("!~")),
+            value(Op::Le, tag("<=")),
+            value(Op::Ge, tag(">=")),
+            value(Op::Lt, tag("<")),
+            value(Op::Gt, tag(">")),
+            value(Op::Contains, keyword("contains")),
+            value(Op::StartsWith, keyword("starts_with")),
+            value(Op::EndsWith, keyword("ends_with")),
+        ))(input)
+    }
+
+    fn primary(input: &str) -> IResult<&str, RawExpr> {
+        ws(alt((
+            delimited(char('('), or, char(')')),
+            map(
+                preceded(
+                    alt((keyword("not"), terminated(tag("!"), not(char('='))))),
+                    primary,
+                ),
+                |expr| RawExpr::Not(Box::new(expr)),
+            ),
+            map(tuple((ws(key), op, ws(literal))), |(key, op, literal)| {
+                RawExpr::Compare(key, op, literal)
+            }),
+            map(key, RawExpr::Exists),
+        )))(input)
+    }
+
+    fn and(input: &str) -> IResult<&str, RawExpr> {
+        map(
+            separated_list1(alt((keyword("and"), tag("&&"))), primary),
+            |mut exprs| match exprs.len() {
+                1 => exprs.remove(0),
+                _ => RawExpr::And(exprs),
+            },
+        )(input)
+    }
+
+    fn or(input: &str) -> IResult<&str, RawExpr> {
+        map(
+            separated_list1(alt((keyword("or"), tag("||"))), and),
+            |mut exprs| match exprs.len() {
+                1 => exprs.remove(0),
+                _ => RawExpr::Or(exprs),
+            },
+        )(input)
+    }
+
+    /// Parses an expression, returning the unparsed remainder on failure.
+    pub fn parse(input: &str) -> Result<RawExpr, &str> {
+        all_consuming(ws(or))(input)
+            .finish()
+            .map(|(_, expr)| expr)
+            .map_err(|err| err.input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(expression: &str) -> Result<FilterProcessor, String> {
+        FilterProcessor::new(&format!("expression = \'\'\'{expression}\'\'\'"))
+    }
+
+    /// Whether the filter with `expression` keeps `item`.
+    fn keeps(expression: &str, item: &[(&str, &str)]) -> bool {
+        let mut filter = filter(expression).unwrap();
+        let item = item
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        match filter.process_items(&Items::from(vec![item])) {
+            Ok(items) => !items.is_empty(),
+            Err(_) => panic!("\"{expression}\" failed"),
+        }
+    }
+
+    fn error(expression: &str) -> String {
+        match filter(expression) {
+            Ok(_) => panic!("\"{expression}\" parsed"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn compares_strings_numbers_and_dates() {
+        let item = [
+            ("title", "Release: v1.2 \"Kiwi\""),
+            ("score", "12"),
+            ("published", "Tue, 02 Jan 2024 10:00:00 +0000"),
+        ];
+        assert!(keeps(r#"title == 'Release: v1.2 "Kiwi"'"#, &item));
+        assert!(keeps(r#"title == "Release: v1.2 \"Kiwi\"""#, &item));
+        assert!(keeps(
+            r#"title starts_with "Release" && title ends_with "\"Kiwi\"""#,
+            &item
+        ));
+        assert!(keeps(
+            r#"title =~ "^Re(lease)?:" and title !~ "beta""#,
+            &item
+        ));
+        assert!(keeps("score >= 10 and score < 12.5", &item));
+        // Numbers compare as numbers, not as strings
+        assert!(!keeps("score < 9", &item));
+        assert!(keeps(r#"published > date("2024-01-01")"#, &item));
+        assert!(!keeps(r#"published > ago("1d")"#, &item));
+        assert!(!keeps("score > 1 and missing < 1", &item));
+    }
+
+    #[test]
+    fn combines_with_precedence() {
+        let item = [("a", "1"), ("order", "x")];
+        assert!(keeps("a or b and c", &item));
+        assert!(!keeps("(a or b) and c", &item));
+        assert!(keeps("not b && !(c || b)", &item));
+        assert!(!keeps("!a", &item));
+        // Keys may start with a keyword
+        assert!(keeps("order and a", &item));
+        assert!(keeps(r#"order != "y""#, &item));
+    }
+
+    #[test]
+    fn compares_missing_and_repeated_keys() {
+        let item = [("category", "news"), ("category", "ads")];
+        for expression in [
+            r#"missing == "x""#,
+            r#"missing != "x""#,
+            r#"missing =~ "x""#,
+            r#"missing !~ "x""#,
+            "missing < 1",
+        ] {
+            assert!(!keeps(expression, &item), "{expression}");
+        }
+        assert!(keeps(r#"not missing == "x""#, &item));
+        assert!(keeps(r#"category == "ads""#, &item));
+        assert!(!keeps(r#"category != "ads""#, &item));
+        assert!(!keeps(r#"category !~ "^ad""#, &item));
+        assert!(keeps(
+            r#"category != "sports" and category !~ "^sp""#,
+            &item
+        ));
+    }
+
+    #[test]
+    fn drops_matching_items() {
+        let mut filter = FilterProcessor::new("expression = 'spam'\naction = \"drop\"").unwrap();
+        let items = Items::from(vec![
+            vec![("spam".to_owned(), "1".to_owned())],
+            vec![("title".to_owned(), "Ham".to_owned())],
+        ]);
+        let Ok(items) = filter.process_items(&items) else {
+            panic!("filtering failed");
+        };
+        assert_eq!(
+            items.items().collect::<Vec<_>>(),
+            [&vec![("title".to_owned(), "Ham".to_owned())]]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert_eq!(
+            error(r#"title == "x" extra"#),
+            r#"Invalid filter expression "title == "x" extra" at "extra""#
+        );
+        assert_eq!(
+            error("(title"),
+            r#"Invalid filter expression "(title" at "(title""#
+        );
+        for expression in [
+            "",
+            "title ==",
+            r#"== "x""#,
+            "a and",
+            "a or or b",
+            "and",
+            r#"a == "x"#,
+        ] {
+            assert!(
+                error(expression).starts_with("Invalid filter expression"),
+                "{expression}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_literals() {
+        assert_eq!(
+            error("title contains 5"),
+            r#"Contains on "title" needs a string"#
+        );
+        assert!(error(r#"title =~ "(""#).starts_with(r#"Invalid regex for "title""#));
+        assert_eq!(
+            error(r#"published < date("yesterday")"#),
+            r#"Invalid date "yesterday""#
+        );
+        assert_eq!(
+            error(r#"published < ago("7y")"#),
+            r#"Invalid duration "7y""#
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Component types that are built into the service rather than supplied by a plugin.
+//!
+//! Built-in types take precedence over plugin types with the same name.
+
+use crate::sys::{
+    PluginEventSourceInstance, PluginProcessorInstance, PluginSinkInstance, PluginSourceInstance,
+};
+
+mod dedup;
+mod exec;
+mod filter;
+mod template;
+mod transform;
+mod webhook;
+
+pub fn supplies_source(r#type: &str) -> bool {
+    matches!(r#type, exec::TYPE_NAME)
+}
+
+pub fn supplies_event_source(r#type: &str) -> bool {
+    matches!(r#type, webhook::TYPE_NAME)
+}
+
+pub fn supplies_sink(r#type: &str) -> bool {
+    matches!(r#type, exec::TYPE_NAME)
+}
+
+pub fn supplies_processor(r#type: &str) -> bool {
+    matches!(
+        r#type,
+        dedup::TYPE_NAME
+            | exec::TYPE_NAME
+            | filter::TYPE_NAME
+            | template::TYPE_NAME
+            | transform::TYPE_NAME
+    )
+}
+
+pub fn instantiate_source(
+    r#type: &str,
+    name: &str,
+    config: &str,
+) -> Option<Result<PluginSourceInstance, String>> {
+    match r#type {
+        exec::TYPE_NAME => Some(
+            exec::ExecComponent::new(config)
+                .map(|exec| PluginSourceInstance::new(name.to_owned(), Box::new(exec))),
+        ),
+        _ => None,
+    }
+}
+
+pub fn instantiate_event_source(
+    r#type: &str,
+    name: &str,
+    config: &str,
+) -> Option<Result<PluginEventSourceInstance, String>> {
+    match r#type {
+        webhook::TYPE_NAME => Some(
+            webhook::WebhookSource::new(config)
+                .map(|webhook| PluginEventSourceInstance::new(name.to_owned(), Box::new(webhook))),
+        ),
+        _ => None,
+    }
+}
+
+pub fn instantiate_sink(
+    r#type: &str,
+    name: &str,
+    config: &str,
+) -> Option<Result<PluginSinkInstance, String>> {
+    match r#type {
+        exec::TYPE_NAME => Some(
+            exec::ExecComponent::new(config)
+                .map(|exec| PluginSinkInstance::new(name.to_owned(), Box::new(exec))),
+        ),
+        _ => None,
+    }
+}
+
+pub fn instantiate_processor(
+    r#type: &str,
+    name: &str,
+    config: &str,
+) -> Option<Result<PluginProcessorInstance, String>> {
+    match r#type {
+        dedup::TYPE_NAME => Some(
+            dedup::DedupProcessor::new(name, config)
+                .map(|dedup| PluginProcessorInstance::new(name.to_owned(), Box::new(dedup))),
+        ),
+        exec::TYPE_NAME => Some(
+            exec::ExecComponent::new(config)
+                .map(|exec| PluginProcessorInstance::new(name.to_owned(), Box::new(exec))),
+        ),
+        filter::TYPE_NAME => Some(
+            filter::FilterProcessor::new(config)
+                .map(|filter| PluginProcessorInstance::new(name.to_owned(), Box::new(filter))),
+        ),
+        template::TYPE_NAME => Some(
+            template::TemplateProcessor::new(config)
+                .map(|template| PluginProcessorInstance::new(name.to_owned(), Box::new(template))),
+        ),
+        transform::TYPE_NAME => {
+            Some(transform::TransformProcessor::new(config).map(|transform| {
+                PluginProcessorInstance::new(name.to_owned(), Box::new(transform))
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Checks the config of a built-in processor, so mistakes are reported when the config is loaded
+/// rather than when the processor is first started.
+pub fn validate_processor(r#type: &str, config: &str) -> Option<Result<(), String>> {
+    match r#type {
+        // Creating a dedup processor reads its state file, which is left to the processor
+        dedup::TYPE_NAME => Some(dedup::DedupProcessor::validate(config)),
+        _ => instantiate_processor(r#type, "", config).map(|res| res.map(drop)),
+    }
+}
@@ -0,0 +1,279 @@
+//! The `template` processor renders new keys from each item's fields with
+//! [minijinja](https://docs.rs/minijinja) templates.
+//!
+//! An item's keys are available as variables (the first pair wins for repeated keys), all of them
+//! as the `item` map (for keys that are not valid names) and in order as `pairs`. Besides the
+//! built-in filters there are:
+//!
+//! - `json`, rendering a value as a JSON string literal, quotes included
+//! - `html`, escaping for HTML text and attributes
+//! - `markdown`, escaping Markdown's special characters
+//! - `truncate(length, end="…")`, shortening to at most `length` characters
+//!
+//! Items that fail to render, e.g. for a missing variable with `strict`, are dropped with a
+//! warning while the rest of the batch goes on.
+
+use std::collections::BTreeMap;
+
+use feed_plumber_plugin_rs::template::json;
+use log::warn;
+use minijinja::{AutoEscape, Environment, Error, UndefinedBehavior, Value};
+use serde::Deserialize;
+
+use crate::sys::{FeedPlumberComponentError, Items, ProcessorComponent};
+
+pub const TYPE_NAME: &str = "template";
+
+const DEFAULT_TRUNCATE_END: &str = "…";
+
+#[derive(Deserialize)]
+struct TemplateConfig {
+    /// The keys to render, and their templates.
+    fields: BTreeMap<String, String>,
+    /// Whether the item's other keys are kept. Rendered keys replace existing ones either way.
+    #[serde(default = "default_keep_fields")]
+    keep_fields: bool,
+    /// Whether using a variable that does not exist is an error rather than an empty string.
+    #[serde(default)]
+    strict: bool,
+}
+
+#[inline]
+const fn default_keep_fields() -> bool {
+    true
+}
+
+pub struct TemplateProcessor {
+    env: Environment<'static>,
+    fields: Vec<String>,
+    keep_fields: bool,
+}
+
+impl TemplateProcessor {
+    pub fn new(config: &str) -> Result<Self, String> {
+        let config = toml::from_str::<TemplateConfig>(config)
+            .map_err(|err| format!("Invalid template config: {err}"))?;
+        let mut env = Environment::new();
+        env.set_undefined_behavior(if config.strict {
+            UndefinedBehavior::Strict
+        } else {
+            UndefinedBehavior::Lenient
+        });
+        // Escaping is up to the filters, rather than guessed from the names of the keys
+        env.set_auto_escape_callback(|_| AutoEscape::None);
+        env.add_filter("json", json);
+        env.add_filter("html", html);
+        env.add_filter("markdown", markdown);
+        env.add_filter("truncate", truncate);
+        let fields = config.fields.keys().cloned().collect();
+        for (key, template) in config.fields {
+            env.add_template_owned(key.clone(), template)
+                .map_err(|err| format!("Invalid template for \"{key}\": {err}"))?;
+        }
+        Ok(Self {
+            env,
+            fields,
+            keep_fields: config.keep_fields,
+        })
+    }
+
+    fn render(&self, item: &[(String, String)]) -> Result<Vec<(String, String)>, Error> {
+        let mut context = BTreeMap::new();
+        for (key, value) in item.iter().rev() {
+            context.insert(key.clone(), Value::from(value.clone()));
+        }
+        let map = context.clone();
+        context.insert("item".to_owned(), Value::from(map));
+        context.insert(
+            "pairs".to_owned(),
+            Value::from(
+                item.iter()
+                    .map(|(key, value)| vec![key.clone(), value.clone()])
+                    .collect::<Vec<_>>(),
+            ),
+        );
+
+        let mut rendered = Vec::new();
+        for key in &self.fields {
+            let value = self.env.get_template(key)?.render(&context)?;
+            rendered.push((key.clone(), value));
+        }
+        let mut out = Vec::new();
+        if self.keep_fields {
+            out.extend(
+                item.iter()
+                    .filter(|(key, _)| !self.fields.contains(key))
+                    .cloned(),
+            );
+        }
+        out.extend(rendered);
+        Ok(out)
+    }
+}
+
+impl ProcessorComponent for TemplateProcessor {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let mut rendered = Vec::with_capacity(items.len());
+        for item in items.items() {
+            match self.render(item) {
+                Ok(item) => rendered.push(item),
+                Err(err) => warn!("Dropping an item that failed to render: {err:#}"),
+            }
+        }
+        Ok(Items::from(rendered))
+    }
+}
+
+fn html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#x27;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn markdown(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\`*_{}[]()<>#+-.!|~".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn truncate(value: &str, length: usize, end: Option<&str>) -> String {
+    if value.chars().count() <= length {
+        return value.to_owned();
+    }
+    let end = end.unwrap_or(DEFAULT_TRUNCATE_END);
+    let keep = length.saturating_sub(end.chars().count());
+    value.chars().take(keep).chain(end.chars()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(config: &str) -> TemplateProcessor {
+        TemplateProcessor::new(config).unwrap()
+    }
+
+    fn item(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn process(template: &mut TemplateProcessor, items: &[&[(&str, &str)]]) -> Items {
+        let items = items.iter().map(|pairs| item(pairs)).collect::<Vec<_>>();
+        let Ok(items) = template.process_items(&Items::from(items)) else {
+            panic!("Processing failed");
+        };
+        items
+    }
+
+    /// The value rendered for `out` from a single item.
+    fn render(template: &str, pairs: &[(&str, &str)]) -> String {
+        let mut template = self::template(&format!("fields.out = {template:?}"));
+        let items = process(&mut template, &[pairs]);
+        let item = items.items().next().unwrap();
+        item.iter().find(|(key, _)| key == "out").unwrap().1.clone()
+    }
+
+    #[test]
+    fn renders_from_variables_item_and_pairs() {
+        let pairs = [("title", "First"), ("title", "Second"), ("odd-key", "x")];
+        assert_eq!(render("{{ title }}", &pairs), "First");
+        assert_eq!(render("{{ item[\"odd-key\"] }}", &pairs), "x");
+        assert_eq!(
+            render(
+                "{% for k, v in pairs %}{{ k }}={{ v }};{% endfor %}",
+                &pairs
+            ),
+            "title=First;title=Second;odd-key=x;"
+        );
+        assert_eq!(render("[{{ missing }}]", &pairs), "[]");
+    }
+
+    #[test]
+    fn keeps_or_drops_the_other_fields() {
+        let pairs: &[(&str, &str)] = &[("title", "Hi"), ("link", "https://example.com")];
+        let config = "fields.title = \"<{{ title }}>\"\nfields.text = \"{{ link }}\"";
+        let mut kept = template(config);
+        let items = process(&mut kept, &[pairs]);
+        assert_eq!(
+            items.items().next().unwrap(),
+            &item(&[
+                ("link", "https://example.com"),
+                ("text", "https://example.com"),
+                ("title", "<Hi>"),
+            ])
+        );
+
+        let mut dropped = template(&format!("keep_fields = false\n{config}"));
+        let items = process(&mut dropped, &[pairs]);
+        assert_eq!(
+            items.items().next().unwrap(),
+            &item(&[("text", "https://example.com"), ("title", "<Hi>")])
+        );
+    }
+
+    #[test]
+    fn escapes_with_filters() {
+        let pairs = [("v", "<a href=\"x\">Tom's *best* [link]</a>\n")];
+        assert_eq!(
+            render("{{ v | json }}", &pairs),
+            r#""<a href=\"x\">Tom's *best* [link]</a>\n""#
+        );
+        assert_eq!(
+            render("{{ v | html }}", &pairs),
+            "&lt;a href=&quot;x&quot;&gt;Tom&#x27;s *best* [link]&lt;/a&gt;\n"
+        );
+        assert_eq!(
+            render("{{ v | markdown }}", &pairs),
+            "\\<a href=\"x\"\\>Tom's \\*best\\* \\[link\\]\\</a\\>\n"
+        );
+    }
+
+    #[test]
+    fn truncates() {
+        let pairs = [("v", "Hello, world")];
+        assert_eq!(render("{{ v | truncate(12) }}", &pairs), "Hello, world");
+        assert_eq!(render("{{ v | truncate(6) }}", &pairs), "Hello…");
+        assert_eq!(render("{{ v | truncate(8, \"...\") }}", &pairs), "Hello...");
+        assert_eq!(render("{{ v | truncate(2, \"...\") }}", &pairs), "...");
+        assert_eq!(render("{{ \"ééé\" | truncate(2, \"\") }}", &pairs), "éé");
+    }
+
+    #[test]
+    fn drops_items_failing_to_render() {
+        let mut strict = template("strict = true\nfields.out = \"{{ title }}\"");
+        let items = process(
+            &mut strict,
+            &[&[("title", "One")], &[], &[("title", "Three")]],
+        );
+        let rendered = items
+            .items()
+            .map(|item| item[item.len() - 1].1.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(rendered, ["One", "Three"]);
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let Err(err) = TemplateProcessor::new("fields.out = \"{{ title \"") else {
+            panic!("Accepted an invalid template");
+        };
+        assert!(err.starts_with("Invalid template for \"out\""));
+    }
+}
@@ -0,0 +1,440 @@
+//! The `transform` processor edits the keys and values of each item with an ordered list of
+//! operations.
+//!
+//! Operations apply to every pair with the key they name, so they work the same on multi-value
+//! keys (e.g. several `category` pairs) as on single ones.
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::sys::{FeedPlumberComponentError, Items, ProcessorComponent};
+
+pub const TYPE_NAME: &str = "transform";
+
+#[derive(Deserialize)]
+struct TransformConfig {
+    ops: Vec<OpConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum OpConfig {
+    /// Renames the key of the pairs with key `from` to `to`.
+    Rename { from: String, to: String },
+    /// Adds a pair with key `to` for each pair with key `from`.
+    Copy { from: String, to: String },
+    /// Removes the pairs with any of the keys.
+    Delete { keys: Vec<String> },
+    /// Replaces the pairs with the key by a single pair with the value.
+    Set { key: String, value: String },
+    /// Adds a pair with the value if the item has no pair with the key.
+    Default { key: String, value: String },
+    /// Replaces the matches of `pattern` in the values of the key, which may refer to capture
+    /// groups like `$1` or `${name}`.
+    Replace {
+        key: String,
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+    },
+    /// Splits the values of the key at `separator`, into one pair per part.
+    Split {
+        key: String,
+        separator: String,
+        /// Whether parts are trimmed of whitespace, and left out when empty.
+        #[serde(default = "default_trim")]
+        trim: bool,
+    },
+    /// Joins the values of the key with `separator` into a single pair, where the first was.
+    Join { key: String, separator: String },
+}
+
+#[inline]
+const fn default_trim() -> bool {
+    true
+}
+
+enum Op {
+    Rename {
+        from: String,
+        to: String,
+    },
+    Copy {
+        from: String,
+        to: String,
+    },
+    Delete {
+        keys: Vec<String>,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Default {
+        key: String,
+        value: String,
+    },
+    Replace {
+        key: String,
+        pattern: Regex,
+        replacement: String,
+    },
+    Split {
+        key: String,
+        separator: String,
+        trim: bool,
+    },
+    Join {
+        key: String,
+        separator: String,
+    },
+}
+
+impl TryFrom<OpConfig> for Op {
+    type Error = String;
+
+    fn try_from(config: OpConfig) -> Result<Self, Self::Error> {
+        let keys = match &config {
+            OpConfig::Rename { from, to } | OpConfig::Copy { from, to } => vec![from, to],
+            OpConfig::Delete { keys } => keys.iter().collect(),
+            OpConfig::Set { key, .. }
+            | OpConfig::Default { key, .. }
+            | OpConfig::Replace { key, .. }
+            | OpConfig::Split { key, .. }
+            | OpConfig::Join { key, .. } => vec![key],
+        };
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err("keys cannot be empty".to_owned());
+        }
+        Ok(match config {
+            OpConfig::Rename { from, to } => Op::Rename { from, to },
+            OpConfig::Copy { from, to } => Op::Copy { from, to },
+            OpConfig::Delete { keys } => Op::Delete { keys },
+            OpConfig::Set { key, value } => Op::Set { key, value },
+            OpConfig::Default { key, value } => Op::Default { key, value },
+            OpConfig::Replace {
+                key,
+                pattern,
+                replacement,
+            } => Op::Replace {
+                key,
+                pattern: Regex::new(&pattern)
+                    .map_err(|err| format!("invalid pattern \"{pattern}\": {err}"))?,
+                replacement,
+            },
+            OpConfig::Split {
+                key,
+                separator,
+                trim,
+            } => {
+                if separator.is_empty() {
+                    return Err(format!("separator for \"{key}\" cannot be empty"));
+                }
+                Op::Split {
+                    key,
+                    separator,
+                    trim,
+                }
+            }
+            OpConfig::Join { key, separator } => Op::Join { key, separator },
+        })
+    }
+}
+
+impl Op {
+    fn apply(&self, item: &mut Vec<(String, String)>) {
+        match self {
+            Op::Rename { from, to } => {
+                for (key, _) in item.iter_mut().filter(|(key, _)| key == from) {
+                    key.clone_from(to);
+                }
+            }
+            Op::Copy { from, to } => {
+                let copies = item
+                    .iter()
+                    .filter(|(key, _)| key == from)
+                    .map(|(_, value)| (to.clone(), value.clone()))
+                    .collect::<Vec<_>>();
+                item.extend(copies);
+            }
+            Op::Delete { keys } => item.retain(|(key, _)| !keys.contains(key)),
+            Op::Set { key, value } => {
+                item.retain(|(k, _)| k != key);
+                item.push((key.clone(), value.clone()));
+            }
+            Op::Default { key, value } => {
+                if !item.iter().any(|(k, _)| k == key) {
+                    item.push((key.clone(), value.clone()));
+                }
+            }
+            Op::Replace {
+                key,
+                pattern,
+                replacement,
+            } => {
+                for (_, value) in item.iter_mut().filter(|(k, _)| k == key) {
+                    *value = pattern.replace_all(value, replacement).into_owned();
+                }
+            }
+            Op::Split {
+                key,
+                separator,
+                trim,
+            } => {
+                *item = item
+                    .drain(..)
+                    .flat_map(|(k, value)| {
+                        if &k != key {
+                            return vec![(k, value)];
+                        }
+                        value
+                            .split(separator.as_str())
+                            .map(|part| if *trim { part.trim() } else { part })
+                            .filter(|part| !*trim || !part.is_empty())
+                            .map(|part| (k.clone(), part.to_owned()))
+                            .collect()
+                    })
+                    .collect();
+            }
+            Op::Join { key, separator } => {
+                let Some(first) = item.iter().position(|(k, _)| k == key) else {
+                    return;
+                };
+                let joined = item
+                    .iter()
+                    .filter(|(k, _)| k == key)
+                    .map(|(_, value)| value.as_str())
+                    .collect::<Vec<_>>()
+                    .join(separator);
+                let mut idx = 0;
+                item.retain(|(k, _)| {
+                    idx += 1;
+                    idx - 1 == first || k != key
+                });
+                item[first].1 = joined;
+            }
+        }
+    }
+}
+
+pub struct TransformProcessor {
+    ops: Vec<Op>,
+}
+
+impl TransformProcessor {
+    pub fn new(config: &str) -> Result<Self, String> {
+        let config = toml::from_str::<TransformConfig>(config)
+            .map_err(|err| format!("Invalid transform config: {err}"))?;
+        let ops = config
+            .ops
+            .into_iter()
+            .enumerate()
+            .map(|(idx, op)| {
+                Op::try_from(op)
+                    .map_err(|err| format!("Invalid transform operation {}: {err}", idx + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { ops })
+    }
+}
+
+impl ProcessorComponent for TransformProcessor {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        Ok(Items::from(
+            items
+                .items()
+                .map(|item| {
+                    let mut item = item.clone();
+                    for op in &self.ops {
+                        op.apply(&mut item);
+                    }
+                    item
+                })
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The item after the ops, given as TOML inline tables.
+    fn transform(ops: &[&str], pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut transform =
+            TransformProcessor::new(&format!("ops = [{}]", ops.join(", "))).unwrap();
+        let Ok(items) = transform.process_items(&Items::from(vec![item(pairs)])) else {
+            panic!("Processing failed");
+        };
+        let item = items.items().next().unwrap().clone();
+        item
+    }
+
+    fn error(ops: &str) -> String {
+        let Err(err) = TransformProcessor::new(&format!("ops = [{ops}]")) else {
+            panic!("Accepted {ops}");
+        };
+        err
+    }
+
+    const ITEM: &[(&str, &str)] = &[("tag", "a"), ("title", "Hi"), ("tag", "b")];
+
+    #[test]
+    fn renames_copies_and_deletes() {
+        assert_eq!(
+            transform(
+                &[r#"{ op = "rename", from = "tag", to = "category" }"#],
+                ITEM
+            ),
+            item(&[("category", "a"), ("title", "Hi"), ("category", "b")])
+        );
+        assert_eq!(
+            transform(&[r#"{ op = "copy", from = "tag", to = "label" }"#], ITEM),
+            item(&[
+                ("tag", "a"),
+                ("title", "Hi"),
+                ("tag", "b"),
+                ("label", "a"),
+                ("label", "b"),
+            ])
+        );
+        assert_eq!(
+            transform(&[r#"{ op = "delete", keys = ["tag", "missing"] }"#], ITEM),
+            item(&[("title", "Hi")])
+        );
+    }
+
+    #[test]
+    fn sets_and_defaults() {
+        assert_eq!(
+            transform(&[r#"{ op = "set", key = "tag", value = "c" }"#], ITEM),
+            item(&[("title", "Hi"), ("tag", "c")])
+        );
+        assert_eq!(
+            transform(&[r#"{ op = "default", key = "tag", value = "c" }"#], ITEM),
+            item(ITEM)
+        );
+        assert_eq!(
+            transform(&[r#"{ op = "default", key = "lang", value = "en" }"#], ITEM),
+            item(&[("tag", "a"), ("title", "Hi"), ("tag", "b"), ("lang", "en")])
+        );
+    }
+
+    #[test]
+    fn replaces_with_capture_groups() {
+        let pairs = [
+            ("date", "2024-03-01"),
+            ("date", "2025-12-31"),
+            ("title", "2024-03-01"),
+        ];
+        assert_eq!(
+            transform(
+                &[
+                    r#"{ op = "replace", key = "date", pattern = '(\d+)-(?<m>\d+)-(\d+)', replacement = '$3.${m}.$1' }"#
+                ],
+                &pairs
+            ),
+            item(&[
+                ("date", "01.03.2024"),
+                ("date", "31.12.2025"),
+                ("title", "2024-03-01")
+            ])
+        );
+        assert_eq!(
+            transform(
+                &[r#"{ op = "replace", key = "title", pattern = '\d' }"#],
+                &pairs
+            ),
+            item(&[
+                ("date", "2024-03-01"),
+                ("date", "2025-12-31"),
+                ("title", "--")
+            ])
+        );
+    }
+
+    #[test]
+    fn splits_in_place() {
+        let pairs = [("tags", " a, b,,c "), ("title", "Hi, there")];
+        assert_eq!(
+            transform(
+                &[r#"{ op = "split", key = "tags", separator = "," }"#],
+                &pairs
+            ),
+            item(&[
+                ("tags", "a"),
+                ("tags", "b"),
+                ("tags", "c"),
+                ("title", "Hi, there")
+            ])
+        );
+        assert_eq!(
+            transform(
+                &[r#"{ op = "split", key = "tags", separator = ",", trim = false }"#],
+                &pairs
+            ),
+            item(&[
+                ("tags", " a"),
+                ("tags", " b"),
+                ("tags", ""),
+                ("tags", "c "),
+                ("title", "Hi, there"),
+            ])
+        );
+    }
+
+    #[test]
+    fn joins_where_the_first_was() {
+        assert_eq!(
+            transform(&[r#"{ op = "join", key = "tag", separator = ", " }"#], ITEM),
+            item(&[("tag", "a, b"), ("title", "Hi")])
+        );
+        assert_eq!(
+            transform(
+                &[r#"{ op = "join", key = "missing", separator = ", " }"#],
+                ITEM
+            ),
+            item(ITEM)
+        );
+    }
+
+    #[test]
+    fn applies_ops_in_order() {
+        let ops = [
+            r#"{ op = "join", key = "tag", separator = "," }"#,
+            r#"{ op = "rename", from = "tag", to = "tags" }"#,
+            r#"{ op = "split", key = "tags", separator = "," }"#,
+        ];
+        assert_eq!(
+            transform(&ops, ITEM),
+            item(&[("tags", "a"), ("tags", "b"), ("title", "Hi")])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ops() {
+        assert_eq!(
+            error(r#"{ op = "rename", from = "", to = "x" }"#),
+            "Invalid transform operation 1: keys cannot be empty"
+        );
+        assert_eq!(
+            error(
+                r#"{ op = "delete", keys = ["a"] }, { op = "split", key = "a", separator = "" }"#
+            ),
+            "Invalid transform operation 2: separator for \"a\" cannot be empty"
+        );
+        assert!(error(r#"{ op = "replace", key = "a", pattern = "(" }"#)
+            .starts_with("Invalid transform operation 1: invalid pattern \"(\""));
+        assert!(
+            error(r#"{ op = "rename", from = "a", to = "b", extra = 1 }"#)
+                .starts_with("Invalid transform config")
+        );
+    }
+}
@@ -0,0 +1,557 @@
+//! The `webhook` source runs an HTTP server and emits the bodies POSTed to it as soon as they
+//! arrive.
+//!
+//! Sources listening on the same address share a server, each receiving the requests to its own
+//! `path`. JSON bodies become one item per object (or per element of a top-level array), with
+//! nested fields flattened to keys like `repository.full_name` and other values keyed `value`.
+//! Form bodies become one item with a pair per field, unless they are a single `payload` field
+//! holding JSON, like GitHub sends.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use feed_plumber_plugin_rs::json;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tiny_http::{Method, Request, Response, Server};
+
+use crate::{
+    pipeline::QUEUE_CAPACITY,
+    sys::{Emitter, EventSourceComponent, FeedPlumberComponentError, Items},
+};
+
+pub const TYPE_NAME: &str = "webhook";
+
+const DEFAULT_PATH: &str = "/";
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_HMAC_HEADER: &str = "X-Hub-Signature-256";
+const DEFAULT_TOKEN_HEADER: &str = "X-Webhook-Token";
+/// How often a source waiting for requests checks whether it should stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Deserialize)]
+struct WebhookConfig {
+    /// The address to listen on, e.g. `127.0.0.1:8080`.
+    listen: SocketAddr,
+    #[serde(default = "default_path")]
+    path: String,
+    /// The shared secret requests are verified with. Requests are not verified without one.
+    secret: Option<String>,
+    #[serde(default)]
+    verify: Verification,
+    /// The header carrying the signature or token.
+    signature_header: Option<String>,
+    /// Headers added to each item, as pairs keyed by the header's name in lowercase.
+    #[serde(default)]
+    headers: Vec<String>,
+    /// The largest body accepted, in bytes.
+    #[serde(default = "default_max_body_size")]
+    max_body_size: usize,
+}
+
+#[inline]
+fn default_path() -> String {
+    DEFAULT_PATH.to_owned()
+}
+
+#[inline]
+const fn default_max_body_size() -> usize {
+    DEFAULT_MAX_BODY_SIZE
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Verification {
+    /// An HMAC-SHA256 of the body with the secret, in hex and optionally prefixed by `sha256=`.
+    #[default]
+    HmacSha256,
+    /// The secret itself.
+    Token,
+}
+
+type Item = Vec<(String, String)>;
+/// The status and message a request is rejected with.
+type Rejection = (u16, String);
+
+/// The routes of the server on one address.
+type Routes = Arc<Mutex<HashMap<String, Sender<Request>>>>;
+
+/// The servers started so far, by address. They run for as long as the service does.
+fn listeners() -> &'static Mutex<HashMap<SocketAddr, Routes>> {
+    static LISTENERS: OnceLock<Mutex<HashMap<SocketAddr, Routes>>> = OnceLock::new();
+    LISTENERS.get_or_init(Default::default)
+}
+
+/// Routes the requests to `path` on `addr` to the returned receiver, starting a server on `addr`
+/// if there is none yet.
+fn route(addr: SocketAddr, path: &str) -> Result<Receiver<Request>, String> {
+    let mut listeners = listeners().lock().unwrap();
+    let routes = match listeners.get(&addr) {
+        Some(routes) => routes.clone(),
+        None => {
+            let server =
+                Server::http(addr).map_err(|err| format!("Unable to listen on {addr}: {err}"))?;
+            info!("Listening for webhooks on {addr}");
+            let routes = Routes::default();
+            let server_routes = routes.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let path = request
+                        .url()
+                        .split('?')
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned();
+                    let sender = server_routes.lock().unwrap().get(&path).cloned();
+                    let Some(sender) = sender else {
+                        respond(request, 404, "Not Found");
+                        continue;
+                    };
+                    match sender.try_send(request) {
+                        Ok(()) => {}
+                        // The source is restarting or its pipelines are backed up; the sender
+                        // should retry later rather than have requests pile up here
+                        Err(TrySendError::Full(request)) => {
+                            warn!("Rejected a webhook to {addr}{path} as its queue is full");
+                            respond(request, 503, "Unavailable");
+                        }
+                        Err(TrySendError::Disconnected(request)) => {
+                            respond(request, 503, "Unavailable")
+                        }
+                    }
+                }
+            });
+            listeners.insert(addr, routes.clone());
+            routes
+        }
+    };
+    let mut routes = routes.lock().unwrap();
+    if routes.contains_key(path) {
+        return Err(format!(
+            "Path \"{path}\" on {addr} is taken by another webhook source"
+        ));
+    }
+    let (sender, receiver) = crossbeam::channel::bounded(QUEUE_CAPACITY);
+    routes.insert(path.to_owned(), sender);
+    Ok(receiver)
+}
+
+fn respond(request: Request, status: u16, message: &str) {
+    if let Err(err) = request.respond(Response::from_string(message).with_status_code(status)) {
+        debug!("Unable to respond to a webhook: {err}");
+    }
+}
+
+pub struct WebhookSource {
+    addr: SocketAddr,
+    path: String,
+    secret: Option<String>,
+    verify: Verification,
+    signature_header: String,
+    headers: Vec<String>,
+    max_body_size: usize,
+    requests: Receiver<Request>,
+}
+
+impl WebhookSource {
+    pub fn new(config: &str) -> Result<Self, String> {
+        let config = toml::from_str::<WebhookConfig>(config)
+            .map_err(|err| format!("Invalid webhook config: {err}"))?;
+        if !config.path.starts_with('/') {
+            return Err(format!(
+                "Invalid webhook config: `path` \"{}\" does not start with \"/\"",
+                &config.path
+            ));
+        }
+        let signature_header = config.signature_header.unwrap_or_else(|| {
+            match config.verify {
+                Verification::HmacSha256 => DEFAULT_HMAC_HEADER,
+                Verification::Token => DEFAULT_TOKEN_HEADER,
+            }
+            .to_owned()
+        });
+        let requests = route(config.listen, &config.path)?;
+        Ok(Self {
+            addr: config.listen,
+            path: config.path,
+            secret: config.secret,
+            verify: config.verify,
+            signature_header,
+            headers: config.headers,
+            max_body_size: config.max_body_size,
+            requests,
+        })
+    }
+
+    fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    /// Turns a request into items, or what to reject it with.
+    fn receive(&self, request: &mut Request) -> Result<Vec<Item>, Rejection> {
+        if !matches!(request.method(), Method::Post | Method::Put) {
+            return Err((405, "Method Not Allowed".to_owned()));
+        }
+        let mut body = Vec::new();
+        request
+            .as_reader()
+            .take(self.max_body_size as u64 + 1)
+            .read_to_end(&mut body)
+            .map_err(|err| (400, format!("Unable to read body: {err}")))?;
+        if body.len() > self.max_body_size {
+            return Err((413, "Payload Too Large".to_owned()));
+        }
+        if let Some(secret) = &self.secret {
+            let signature = Self::header(request, &self.signature_header).unwrap_or_default();
+            if !verify(self.verify, secret, signature, &body) {
+                return Err((401, "Unauthorized".to_owned()));
+            }
+        }
+
+        let content_type = Self::header(request, "Content-Type")
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let mut items = if content_type == "application/x-www-form-urlencoded" {
+            let fields = form_urlencoded::parse(&body)
+                .into_owned()
+                .collect::<Vec<_>>();
+            match fields.as_slice() {
+                [(key, payload)] if key == "payload" => parse_json(payload.as_bytes())?,
+                _ => vec![fields],
+            }
+        } else if content_type == "application/json" || content_type.ends_with("+json") {
+            parse_json(&body)?
+        } else {
+            let body =
+                String::from_utf8(body).map_err(|_| (415, "Unsupported Media Type".to_owned()))?;
+            vec![vec![("body".to_owned(), body)]]
+        };
+
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|name| {
+                Self::header(request, name)
+                    .map(|value| (name.to_ascii_lowercase(), value.to_owned()))
+            })
+            .collect::<Vec<_>>();
+        for item in &mut items {
+            item.extend(headers.iter().cloned());
+        }
+        Ok(items)
+    }
+}
+
+impl Drop for WebhookSource {
+    fn drop(&mut self) {
+        if let Some(routes) = listeners().lock().unwrap().get(&self.addr) {
+            routes.lock().unwrap().remove(&self.path);
+        }
+    }
+}
+
+impl EventSourceComponent for WebhookSource {
+    fn run(&mut self, emitter: &Emitter) -> Result<(), FeedPlumberComponentError> {
+        while !emitter.stopping() {
+            let mut request = match self.requests.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match self.receive(&mut request) {
+                Ok(items) => {
+                    if emitter.emit(Items::from(items)) {
+                        respond(request, 202, "Accepted");
+                    } else {
+                        respond(request, 503, "Unavailable");
+                    }
+                }
+                Err((status, message)) => {
+                    warn!(
+                        "Rejected a webhook to {}{} with {status}: {message}",
+                        &self.addr, &self.path
+                    );
+                    respond(request, status, &message);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn verify(verification: Verification, secret: &str, signature: &str, body: &[u8]) -> bool {
+    match verification {
+        Verification::HmacSha256 => {
+            let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+            let Some(signature) = decode_hex(signature) else {
+                return false;
+            };
+            let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+                return false;
+            };
+            mac.update(body);
+            mac.verify_slice(&signature).is_ok()
+        }
+        // Compared in constant time, so the token cannot be guessed byte by byte
+        Verification::Token => {
+            signature.len() == secret.len()
+                && signature
+                    .bytes()
+                    .zip(secret.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_json(body: &[u8]) -> Result<Vec<Item>, Rejection> {
+    let value = serde_json::from_slice::<Value>(body)
+        .map_err(|err| (400, format!("Invalid JSON: {err}")))?;
+    let values = match value {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+    Ok(values
+        .into_iter()
+        .map(|value| json::flatten(&value))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::sys::Shutdown;
+
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// A source on a free local port, receiving requests to `/hook`.
+    fn source(config: &str) -> WebhookSource {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        WebhookSource::new(&format!("listen = \"{addr}\"\npath = \"/hook\"\n{config}")).unwrap()
+    }
+
+    /// Sends a POST to `/hook` without waiting for the response.
+    fn send(addr: SocketAddr, headers: &[(&str, &str)], body: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = format!(
+            "POST /hook HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+    }
+
+    /// The status a request was answered with.
+    fn status(mut stream: TcpStream) -> u16 {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.split(' ').nth(1).unwrap().parse().unwrap()
+    }
+
+    fn post(addr: SocketAddr, headers: &[(&str, &str)], body: &str) -> u16 {
+        status(send(addr, headers, body))
+    }
+
+    /// Runs the source while `post` sends requests to it, returning the items it emitted.
+    fn run(mut source: WebhookSource, post: impl FnOnce(SocketAddr)) -> Vec<Item> {
+        let addr = source.addr;
+        let shutdown = Shutdown::default();
+        let emitted = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let emit = |items: Items| emitted.lock().unwrap().extend(items.items().cloned());
+                assert!(source.run(&Emitter::new(&emit, &shutdown)).is_ok());
+            });
+            post(addr);
+            shutdown.signal();
+        });
+        emitted.into_inner().unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Item {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn verifies_hmac_signatures() {
+        let body = br#"{"action":"opened"}"#;
+        let signature = sign("s3cret", body);
+        let hmac = Verification::HmacSha256;
+        assert!(verify(hmac, "s3cret", &signature, body));
+        assert!(verify(hmac, "s3cret", &format!("sha256={signature}"), body));
+        assert!(!verify(
+            hmac,
+            "s3cret",
+            &signature,
+            br#"{"action":"closed"}"#
+        ));
+        assert!(!verify(hmac, "other", &signature, body));
+        let mut tampered = signature.clone();
+        tampered.replace_range(
+            ..2,
+            if signature.starts_with("00") {
+                "01"
+            } else {
+                "00"
+            },
+        );
+        assert!(!verify(hmac, "s3cret", &tampered, body));
+        assert!(!verify(hmac, "s3cret", &signature[1..], body));
+        assert!(!verify(hmac, "s3cret", "", body));
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let token = Verification::Token;
+        assert!(verify(token, "s3cret", "s3cret", b""));
+        assert!(!verify(token, "s3cret", "s3creT", b""));
+        assert!(!verify(token, "s3cret", "s3cre", b""));
+        assert!(!verify(token, "s3cret", "s3crets", b""));
+        assert!(!verify(token, "s3cret", "", b""));
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+f"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+
+    #[test]
+    fn parses_json_objects_and_arrays() {
+        assert_eq!(
+            parse_json(br#"{"repository":{"full_name":"a/b"},"tags":["x","y"]}"#),
+            Ok(vec![pairs(&[
+                ("repository.full_name", "a/b"),
+                ("tags.0", "x"),
+                ("tags.1", "y"),
+            ])])
+        );
+        assert_eq!(
+            parse_json(br#"[{"id":1},{"id":2},3]"#),
+            Ok(vec![
+                pairs(&[("id", "1")]),
+                pairs(&[("id", "2")]),
+                pairs(&[("value", "3")]),
+            ])
+        );
+        assert!(matches!(parse_json(b"{"), Err((400, _))));
+    }
+
+    #[test]
+    fn receives_form_and_json_bodies() {
+        let source = source("secret = \"s3cret\"\nverify = \"token\"\nheaders = [\"X-Event\"]");
+        let form = "application/x-www-form-urlencoded";
+        let items = run(source, |addr| {
+            let token = ("X-Webhook-Token", "s3cret");
+            let payload = "payload=%7B%22action%22%3A%22opened%22%7D";
+            let headers = [token, ("Content-Type", form), ("X-Event", "issues")];
+            assert_eq!(post(addr, &headers, payload), 202);
+            let headers = [token, ("Content-Type", form)];
+            assert_eq!(post(addr, &headers, "payload=1&other=2"), 202);
+            let headers = [token, ("Content-Type", "application/json; charset=utf-8")];
+            assert_eq!(post(addr, &headers, r#"[{"id":1},{"id":2}]"#), 202);
+            assert_eq!(post(addr, &[token], "plain"), 202);
+            let headers = [("X-Webhook-Token", "wrong"), ("Content-Type", form)];
+            assert_eq!(post(addr, &headers, "payload=%7B%7D"), 401);
+            assert_eq!(post(addr, &[("Content-Type", form)], "payload=%7B%7D"), 401);
+        });
+        assert_eq!(
+            items,
+            vec![
+                pairs(&[("action", "opened"), ("x-event", "issues")]),
+                pairs(&[("payload", "1"), ("other", "2")]),
+                pairs(&[("id", "1")]),
+                pairs(&[("id", "2")]),
+                pairs(&[("body", "plain")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let source = source("secret = \"s3cret\"");
+        let body = r#"{"action":"opened"}"#;
+        let signature = format!("sha256={}", sign("s3cret", body.as_bytes()));
+        let items = run(source, |addr| {
+            let headers = [
+                ("X-Hub-Signature-256", signature.as_str()),
+                ("Content-Type", "application/json"),
+            ];
+            assert_eq!(post(addr, &headers, body), 202);
+            assert_eq!(post(addr, &headers, r#"{"action":"closed"}"#), 401);
+        });
+        assert_eq!(items, vec![pairs(&[("action", "opened")])]);
+    }
+
+    #[test]
+    fn answers_503_once_the_queue_is_full() {
+        let source = source("");
+        let waiting = (0..QUEUE_CAPACITY)
+            .map(|_| send(source.addr, &[], "queued"))
+            .collect::<Vec<_>>();
+        while source.requests.len() < QUEUE_CAPACITY {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(post(source.addr, &[], "overflowing"), 503);
+
+        let items = run(source, |_| {
+            assert!(waiting.into_iter().all(|stream| status(stream) == 202));
+        });
+        assert_eq!(items.len(), QUEUE_CAPACITY);
+    }
+}
@@ -15,7 +15,14 @@ use serde::{Deserialize, Serialize};
 use tap::TapFallible;
 use toml::{map::Map, Value};
 
+use crate::{
+    supervisor::RestartPolicy,
+    throttle::{BatchPolicy, RateLimit},
+};
+
 const DEFAULT_TIME_BETWEEN_TICKS: usize = 60000;
+const DEFAULT_WASM_MAX_MEMORY: usize = 64 * 1024 * 1024;
+const DEFAULT_ISOLATION_TIMEOUT: u64 = 300_000;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
@@ -23,20 +30,38 @@ pub struct Config {
     pub time_between_ticks: usize,
     #[serde(default)]
     pub print_plugin_warnings: bool,
+    /// Whether components run in their own child process by default.
+    #[serde(default)]
+    pub isolate_plugins: bool,
+    /// How long an isolated component's process gets to answer a call, in milliseconds, before it
+    /// is killed and respawned.
+    #[serde(default = "default_isolation_timeout")]
+    pub isolation_timeout: u64,
+    /// Limits applied to plugins compiled to WebAssembly.
+    #[serde(default)]
+    pub wasm: WasmLimits,
     #[serde(default = "Vec::new")]
     pub sources: Vec<Source>,
     #[serde(default = "Vec::new")]
     pub sinks: Vec<Sink>,
     #[serde(default = "Vec::new")]
     pub processors: Vec<Processor>,
+    #[serde(default = "Vec::new")]
+    pub pipelines: Vec<NamedPipeline>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Source {
     pub name: String,
-    pub schedule: ParsedSchedule,
+    /// When the source is polled. Event sources emit on their own and have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ParsedSchedule>,
     pub r#type: String,
     pub pipe: Vec<Pipeline>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolated: Option<bool>,
     #[serde(flatten)]
     pub other_fields: Map<String, Value>,
 }
@@ -45,6 +70,16 @@ pub struct Source {
 pub struct Sink {
     pub name: String,
     pub r#type: String,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolated: Option<bool>,
+    /// How often items may go into the sink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    /// How items are collected into calls to the sink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<BatchPolicy>,
     #[serde(flatten)]
     pub other_fields: Map<String, Value>,
 }
@@ -53,15 +88,63 @@ pub struct Sink {
 pub struct Processor {
     pub name: String,
     pub r#type: String,
+    /// Where a stream processor's items go. Left out of the config the plugin gets, so it doesn't
+    /// mistake it for one of its own keys.
+    #[serde(default, skip_serializing)]
+    pub pipe: Vec<Pipeline>,
+    /// When a stream processor is flushed. Left out of the plugin's config like `pipe`.
+    #[serde(default, skip_serializing)]
+    pub schedule: Option<ParsedSchedule>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolated: Option<bool>,
     #[serde(flatten)]
     pub other_fields: Map<String, Value>,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WasmLimits {
+    /// Maximum linear memory of each component instance, in bytes.
+    #[serde(default = "default_wasm_max_memory")]
+    pub max_memory: usize,
+    /// Fuel available to each call into a component, roughly a count of instructions. Calls are
+    /// not metered when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fuel: Option<u64>,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            max_memory: DEFAULT_WASM_MAX_MEMORY,
+            fuel: None,
+        }
+    }
+}
+
+/// A pipeline that sources and other pipelines can refer to as `@name`.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NamedPipeline {
+    pub name: String,
+    pub pipe: Pipeline,
+}
+
 #[inline]
 const fn default_time_between_ticks() -> usize {
     DEFAULT_TIME_BETWEEN_TICKS
 }
 
+#[inline]
+const fn default_wasm_max_memory() -> usize {
+    DEFAULT_WASM_MAX_MEMORY
+}
+
+#[inline]
+const fn default_isolation_timeout() -> u64 {
+    DEFAULT_ISOLATION_TIMEOUT
+}
+
 #[derive(Deserialize, Serialize, Debug, Deref, DerefMut, Clone, FromStr)]
 #[serde(try_from = "String", into = "String")]
 pub struct ParsedSchedule(pub Schedule);
@@ -86,41 +169,145 @@ impl Display for ParsedSchedule {
     }
 }
 
+/// Where a source's items go: a chain of stages separated by `->`, where a stage is a component
+/// name or a set of branches in braces, e.g. `a->{b->sink_x, sink_y}`. Components that have
+/// something after them are processors, the rest are sinks.
+///
+/// A component name that appears more than once refers to the same step, so its output is
+/// computed once and shared by every branch after it, and a step after several branches
+/// (`{a, b}->c`) receives their combined output. Branches can't start with the same step, so
+/// `{a->b, a->c}` has to be written as `a->{b, c}`.
+///
+/// A stage can also be `@name`, which stands for the stages of the [`NamedPipeline`] `name`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(try_from = "String", into = "String")]
 pub struct Pipeline {
-    pub processors: Vec<String>,
-    pub sink: String,
+    pub stages: Vec<Stage>,
+    text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    Component(String),
+    Reference(String),
+    Branches(Vec<Vec<Stage>>),
 }
 
-pub struct PipelineFromStrError;
+pub struct PipelineFromStrError {
+    pipeline: String,
+    rest: String,
+}
 
 impl Debug for PipelineFromStrError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        pipeline_err_fmt(f)
+        Display::fmt(self, f)
     }
 }
 
 impl Display for PipelineFromStrError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        pipeline_err_fmt(f)
+        write!(
+            f,
+            "Corrupt pipeline string \"{}\" at \"{}\"",
+            self.pipeline, self.rest
+        )
     }
 }
 
-#[inline]
-fn pipeline_err_fmt(f: &mut Formatter<'_>) -> std::fmt::Result {
-    f.write_str("Corrupt pipeline string")
+mod syntax {
+    use nom::{
+        branch::alt,
+        bytes::complete::tag,
+        character::complete::{char, multispace0, none_of},
+        combinator::{all_consuming, map, not, recognize, verify},
+        multi::{many1, separated_list1},
+        sequence::{delimited, preceded, terminated},
+        Finish, IResult,
+    };
+
+    use super::Stage;
+
+    /// Anything up to the next `->`, `,`, `{` or `}`. Names may contain single dashes, but not
+    /// start with `@`, which marks a reference.
+    fn name(input: &str) -> IResult<&str, &str> {
+        verify(
+            map(
+                recognize(many1(alt((
+                    recognize(none_of("-,{}")),
+                    terminated(tag("-"), not(char('>'))),
+                )))),
+                str::trim,
+            ),
+            |name: &str| !name.is_empty() && !name.starts_with('@'),
+        )(input)
+    }
+
+    fn stage(input: &str) -> IResult<&str, Stage> {
+        delimited(
+            multispace0,
+            alt((
+                map(
+                    verify(
+                        delimited(char('{'), separated_list1(char(','), chain), char('}')),
+                        distinct_heads,
+                    ),
+                    Stage::Branches,
+                ),
+                map(preceded(char('@'), name), |name| {
+                    Stage::Reference(name.to_owned())
+                }),
+                map(name, |name| Stage::Component(name.to_owned())),
+            )),
+            multispace0,
+        )(input)
+    }
+
+    /// Whether no two branches start with the same step, as in `{a->b, a}`, where it would be
+    /// unclear what `a` is followed by.
+    fn distinct_heads(chains: &[Vec<Stage>]) -> bool {
+        let heads = chains
+            .iter()
+            .flat_map(|chain| heads(chain))
+            .collect::<Vec<_>>();
+        heads
+            .iter()
+            .enumerate()
+            .all(|(idx, head)| !heads[..idx].contains(head))
+    }
+
+    /// The stages a chain starts with, which are several if it starts with branches.
+    fn heads(chain: &[Stage]) -> Vec<&Stage> {
+        match chain.first() {
+            Some(Stage::Branches(chains)) => chains.iter().flat_map(|chain| heads(chain)).collect(),
+            Some(stage) => vec![stage],
+            None => Vec::new(),
+        }
+    }
+
+    fn chain(input: &str) -> IResult<&str, Vec<Stage>> {
+        separated_list1(tag("->"), stage)(input)
+    }
+
+    /// Parses a pipeline, returning the unparsed remainder on failure.
+    pub fn parse(input: &str) -> Result<Vec<Stage>, &str> {
+        all_consuming(chain)(input)
+            .finish()
+            .map(|(_, stages)| stages)
+            .map_err(|err| err.input)
+    }
 }
 
 impl FromStr for Pipeline {
     type Err = PipelineFromStrError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut v = s.split("->").map(ToOwned::to_owned).collect::<Vec<_>>();
-        let sink = v.pop().ok_or(PipelineFromStrError)?;
+        let stages = syntax::parse(s).map_err(|rest| PipelineFromStrError {
+            pipeline: s.to_owned(),
+            rest: rest.to_owned(),
+        })?;
         Ok(Pipeline {
-            processors: v,
-            sink,
+            stages,
+            text: s.trim().to_owned(),
         })
     }
 }
@@ -133,13 +320,19 @@ impl TryFrom<String> for Pipeline {
     }
 }
 
+impl Pipeline {
+    /// The name of the named pipeline this refers to, if it is nothing but a reference.
+    pub fn reference(&self) -> Option<&str> {
+        match self.stages.as_slice() {
+            [Stage::Reference(name)] => Some(name),
+            _ => None,
+        }
+    }
+}
+
 impl Display for Pipeline {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut v = self.processors.join("->");
-        v.reserve(2 + self.sink.len());
-        v.push_str("->");
-        v.push_str(&self.sink);
-        f.write_str(&v)
+        f.write_str(&self.text)
     }
 }
 
@@ -162,7 +355,152 @@ pub fn load_from_toml(config_path: impl AsRef<Path>) -> anyhow::Result<Config> {
             config_path
         )
     })?;
-    toml::from_str(&config_toml)
+    let config: Config = toml::from_str(&config_toml)
         .tap_err(|err| error!("TOML parsing error: {err}"))
-        .context("Parsing toml")
+        .context("Parsing toml")?;
+    config
+        .validate()
+        .tap_err(|err| error!("Invalid config: {err}"))?;
+    Ok(config)
+}
+
+impl Config {
+    /// Checks what parsing alone does not.
+    fn validate(&self) -> anyhow::Result<()> {
+        #[cfg(not(unix))]
+        {
+            let isolated = self
+                .sources
+                .iter()
+                .map(|source| (&source.name, source.isolated))
+                .chain(self.sinks.iter().map(|sink| (&sink.name, sink.isolated)))
+                .chain(
+                    self.processors
+                        .iter()
+                        .map(|processor| (&processor.name, processor.isolated)),
+                )
+                .find(|(_, isolated)| *isolated == Some(true));
+            if let Some((name, _)) = isolated {
+                anyhow::bail!("\"{name}\" is `isolated`, which is only supported on Unix");
+            }
+            if self.isolate_plugins {
+                anyhow::bail!("`isolate_plugins` is only supported on Unix");
+            }
+        }
+        if self.isolation_timeout == 0 {
+            anyhow::bail!("`isolation_timeout` must be more than 0");
+        }
+        for sink in &self.sinks {
+            let name = &sink.name;
+            if matches!(&sink.rate_limit, Some(limit) if limit.limit == 0) {
+                anyhow::bail!("Sink \"{name}\": `rate_limit.limit` must be at least 1");
+            }
+            if matches!(&sink.batch, Some(batch) if batch.max_items == Some(0)) {
+                anyhow::bail!("Sink \"{name}\": `batch.max_items` must be at least 1");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(name: &str) -> Stage {
+        Stage::Component(name.to_owned())
+    }
+
+    fn error(pipeline: &str) -> String {
+        match pipeline.parse::<Pipeline>() {
+            Ok(parsed) => panic!("\"{pipeline}\" parsed as {:?}", parsed.stages),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_chains_branches_and_references() {
+        let pipeline = " fetch -> {only-new->discord webhook, @archive} -> log "
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            pipeline.stages,
+            vec![
+                component("fetch"),
+                Stage::Branches(vec![
+                    vec![component("only-new"), component("discord webhook")],
+                    vec![Stage::Reference("archive".to_owned())],
+                ]),
+                component("log"),
+            ]
+        );
+        assert_eq!(
+            pipeline.to_string(),
+            "fetch -> {only-new->discord webhook, @archive} -> log"
+        );
+        assert_eq!(pipeline.reference(), None);
+    }
+
+    #[test]
+    fn parses_nested_branches() {
+        let pipeline = "{a->{b, c}, d}".parse::<Pipeline>().unwrap();
+        assert_eq!(
+            pipeline.stages,
+            vec![Stage::Branches(vec![
+                vec![
+                    component("a"),
+                    Stage::Branches(vec![vec![component("b")], vec![component("c")]]),
+                ],
+                vec![component("d")],
+            ])]
+        );
+    }
+
+    #[test]
+    fn recognizes_plain_references() {
+        assert_eq!(
+            "@archive".parse::<Pipeline>().unwrap().reference(),
+            Some("archive")
+        );
+        assert_eq!(
+            "@archive->log".parse::<Pipeline>().unwrap().reference(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_pipelines() {
+        assert_eq!(error("a->"), "Corrupt pipeline string \"a->\" at \"->\"");
+        assert_eq!(error("a,b"), "Corrupt pipeline string \"a,b\" at \",b\"");
+        for pipeline in [
+            "", "->a", "a->->b", "{a, b", "a}", "{}", "{a,}", "@", "a->@",
+        ] {
+            error(pipeline);
+        }
+    }
+
+    #[test]
+    fn rejects_branches_starting_with_the_same_step() {
+        assert_eq!(
+            error("{a->s, a}"),
+            "Corrupt pipeline string \"{a->s, a}\" at \"{a->s, a}\""
+        );
+        for pipeline in ["{a, a}", "x->{a->b, a->c}", "{{a, b}, b->c}", "{@p, @p->c}"] {
+            error(pipeline);
+        }
+        for pipeline in ["{a->b, b}", "{a->c, b->c}", "{a, @a}", "a->{b, c}->{b, c}"] {
+            pipeline.parse::<Pipeline>().unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_malformed_pipelines_in_the_config() {
+        let err = toml::from_str::<NamedPipeline>("name = \"x\"\npipe = \"{a, b\"")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Corrupt pipeline string \"{a, b\" at \"{a, b\""),
+            "{err}"
+        );
+    }
 }
@@ -0,0 +1,620 @@
+//! Out-of-process plugin components.
+//!
+//! An isolated component runs in a child copy of this executable (started with `--plugin-host`),
+//! which loads the plugin library and instantiates exactly one component. The service talks to it
+//! over a Unix socket using length-prefixed frames, so a plugin that crashes only takes down its
+//! own process. Crashed children, and children that take longer than the configured timeout to
+//! answer, are respawned on the next call.
+//!
+//! The socket is created in a fresh directory only the service's user can access, so other users
+//! on the machine cannot connect in place of the child.
+
+use std::{
+    io,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, error, info};
+use sys_feed_plumber_plugin::wire::{Reader, Writer};
+use tempfile::TempDir;
+
+use crate::{
+    plugin_loader::load_plugin,
+    sys::{
+        FeedPlumberComponentError, Items, PluginProcessorInstance, PluginSinkInstance,
+        PluginSourceInstance, ProcessorComponent, SinkComponent, SourceComponent,
+    },
+};
+
+/// How long a child has to connect back before it is considered failed.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Frames larger than this are treated as a corrupt stream.
+const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+const REQUEST_CREATE: u8 = 0;
+const REQUEST_POLL: u8 = 1;
+const REQUEST_SINK: u8 = 2;
+const REQUEST_PROCESS: u8 = 3;
+
+const RESPONSE_CREATED: u8 = 0;
+const RESPONSE_CREATE_FAILED: u8 = 1;
+const RESPONSE_ITEMS: u8 = 2;
+const RESPONSE_ERROR: u8 = 3;
+
+const ERROR_WARN: u8 = 0;
+const ERROR_FATAL: u8 = 1;
+const ERROR_PANIC: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentKind {
+    Source,
+    Sink,
+    Processor,
+}
+
+impl ComponentKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            ComponentKind::Source => 0,
+            ComponentKind::Sink => 1,
+            ComponentKind::Processor => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(ComponentKind::Source),
+            1 => Ok(ComponentKind::Sink),
+            2 => Ok(ComponentKind::Processor),
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn human_name(self) -> &'static str {
+        match self {
+            ComponentKind::Source => "source",
+            ComponentKind::Sink => "sink",
+            ComponentKind::Processor => "processor",
+        }
+    }
+}
+
+/// A component living in a child process.
+pub struct RemoteComponent {
+    kind: ComponentKind,
+    plugin: PathBuf,
+    r#type: String,
+    name: String,
+    config: String,
+    /// How long the child gets to answer a call.
+    timeout: Duration,
+    child: Option<(Child, UnixStream)>,
+}
+
+impl RemoteComponent {
+    pub fn spawn(
+        kind: ComponentKind,
+        plugin: &Path,
+        r#type: &str,
+        name: &str,
+        config: &str,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut remote = Self {
+            kind,
+            plugin: plugin.to_path_buf(),
+            r#type: r#type.to_owned(),
+            name: name.to_owned(),
+            config: config.to_owned(),
+            timeout,
+            child: None,
+        };
+        remote.respawn()?;
+        Ok(remote)
+    }
+
+    fn respawn(&mut self) -> Result<(), String> {
+        // Created with mode 0700 under a random name
+        let socket_dir = tempfile::Builder::new()
+            .prefix("feed-plumber-")
+            .tempdir()
+            .map_err(|err| format!("Creating socket directory: {err}"))?;
+        let res = self.connect_child(&socket_dir);
+        // Removes the socket along with the directory, the connection stays
+        drop(socket_dir);
+        let (mut child, mut stream) =
+            res.map_err(|err| format!("Spawning plugin host: {err:#}"))?;
+
+        let mut request = message(REQUEST_CREATE);
+        request.u8(self.kind.to_byte());
+        request.str(&self.r#type);
+        request.str(&self.name);
+        request.str(&self.config);
+        let response = write_frame(&mut stream, &request.0).and_then(|_| read_frame(&mut stream));
+        let response = match response {
+            Ok(response) => response,
+            Err(err) if timed_out(&err) => {
+                kill(&mut child);
+                return Err(format!(
+                    "Plugin host did not answer within {} ms during creation",
+                    self.timeout.as_millis()
+                ));
+            }
+            Err(err) => {
+                kill(&mut child);
+                return Err(format!("Plugin host exited during creation: {err}"));
+            }
+        };
+        let mut decoder = Reader(&response);
+        match decoder.u8() {
+            Ok(RESPONSE_CREATED) => {
+                self.child = Some((child, stream));
+                Ok(())
+            }
+            Ok(RESPONSE_CREATE_FAILED) => {
+                kill(&mut child);
+                Err(decoder
+                    .str()
+                    .unwrap_or_else(|_| "Unknown error during creation".to_owned()))
+            }
+            _ => {
+                kill(&mut child);
+                Err("Plugin host sent an invalid response".to_owned())
+            }
+        }
+    }
+
+    fn connect_child(&self, socket_dir: &TempDir) -> anyhow::Result<(Child, UnixStream)> {
+        let socket_path = socket_dir.path().join("plugin.sock");
+        let listener = UnixListener::bind(&socket_path).context("Binding socket")?;
+        listener.set_nonblocking(true)?;
+        let mut child = host_command(&socket_path, &self.plugin)?
+            .stdin(Stdio::null())
+            .spawn()
+            .context("Starting child process")?;
+        let started = Instant::now();
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    // A child that hangs is treated like one that crashed
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    debug!(
+                        "Plugin host for {} \"{}\" connected (pid {})",
+                        self.kind.human_name(),
+                        self.name,
+                        child.id()
+                    );
+                    return Ok((child, stream));
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if let Some(status) = child.try_wait()? {
+                        return Err(anyhow!("Child exited before connecting ({status})"));
+                    }
+                    if started.elapsed() > CONNECT_TIMEOUT {
+                        kill(&mut child);
+                        return Err(anyhow!("Child did not connect in time"));
+                    }
+                    sleep(Duration::from_millis(10));
+                }
+                Err(err) => {
+                    kill(&mut child);
+                    return Err(err).context("Accepting connection");
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, request: &[u8]) -> Result<Items, FeedPlumberComponentError> {
+        if self.child.is_none() {
+            self.respawn().map_err(FeedPlumberComponentError::Fatal)?;
+            info!(
+                "Respawned plugin host for {} \"{}\"",
+                self.kind.human_name(),
+                self.name
+            );
+        }
+        let (child, stream) = self.child.as_mut().unwrap();
+        let response = write_frame(stream, request).and_then(|_| read_frame(stream));
+        match response.and_then(|frame| decode_response(&frame)) {
+            Ok(res) => res,
+            Err(err) => {
+                kill(child);
+                if timed_out(&err) {
+                    error!(
+                        "Plugin host for {} \"{}\" did not answer within {} ms and was killed. It will be respawned.",
+                        self.kind.human_name(),
+                        self.name,
+                        self.timeout.as_millis()
+                    );
+                    self.child = None;
+                    return Err(FeedPlumberComponentError::Warn(
+                        "Plugin host timed out".to_owned(),
+                    ));
+                }
+                let status = child
+                    .wait()
+                    .map(|status| status.to_string())
+                    .unwrap_or_else(|_| "unknown status".to_owned());
+                error!(
+                    "Plugin host for {} \"{}\" crashed ({status}). It will be respawned.",
+                    self.kind.human_name(),
+                    self.name
+                );
+                self.child = None;
+                Err(FeedPlumberComponentError::Warn(format!(
+                    "Plugin host crashed: {err}"
+                )))
+            }
+        }
+    }
+}
+
+impl Drop for RemoteComponent {
+    fn drop(&mut self) {
+        if let Some((child, _)) = &mut self.child {
+            kill(child);
+        }
+    }
+}
+
+impl SourceComponent for RemoteComponent {
+    fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError> {
+        self.call(&message(REQUEST_POLL).0)
+    }
+}
+
+impl SinkComponent for RemoteComponent {
+    fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError> {
+        let mut request = message(REQUEST_SINK);
+        request.items(items.items());
+        self.call(&request.0).map(drop)
+    }
+}
+
+impl ProcessorComponent for RemoteComponent {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let mut request = message(REQUEST_PROCESS);
+        request.items(items.items());
+        self.call(&request.0)
+    }
+}
+
+/// The command starting a plugin host connecting to `socket`.
+#[cfg(not(test))]
+fn host_command(socket: &Path, plugin: &Path) -> anyhow::Result<Command> {
+    let mut command = Command::new(std::env::current_exe().context("Locating executable")?);
+    command
+        .arg("--plugin-host")
+        .arg(socket)
+        .arg("--plugins")
+        .arg(plugin);
+    Ok(command)
+}
+
+/// Test binaries can't host plugins, so they run [`tests::fake_plugin_host`] instead.
+#[cfg(test)]
+fn host_command(socket: &Path, _plugin: &Path) -> anyhow::Result<Command> {
+    let mut command = Command::new(std::env::current_exe().context("Locating executable")?);
+    command
+        .args(["--exact", "isolation::tests::fake_plugin_host"])
+        .env(tests::FAKE_HOST_SOCKET, socket)
+        .stdout(Stdio::null());
+    Ok(command)
+}
+
+/// Whether a read or write failed because the socket's timeout passed.
+fn timed_out(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn decode_response(frame: &[u8]) -> io::Result<Result<Items, FeedPlumberComponentError>> {
+    let mut decoder = Reader(frame);
+    match decoder.u8().map_err(|_| corrupt())? {
+        RESPONSE_ITEMS => Ok(Ok(decoder.items().map_err(|_| corrupt())?.into())),
+        RESPONSE_ERROR => {
+            let severity = decoder.u8().map_err(|_| corrupt())?;
+            let message = decoder.str().map_err(|_| corrupt())?;
+            Ok(Err(match severity {
+                ERROR_WARN => FeedPlumberComponentError::Warn(message),
+                ERROR_FATAL => FeedPlumberComponentError::Fatal(message),
+                _ => FeedPlumberComponentError::Panic(message),
+            }))
+        }
+        _ => Err(corrupt()),
+    }
+}
+
+fn encode_response(res: Result<Items, FeedPlumberComponentError>) -> Vec<u8> {
+    match res {
+        Ok(items) => {
+            let mut response = message(RESPONSE_ITEMS);
+            response.items(items.items());
+            response.0
+        }
+        Err(err) => {
+            let mut response = message(RESPONSE_ERROR);
+            let (severity, message) = match err {
+                FeedPlumberComponentError::Warn(message) => (ERROR_WARN, message),
+                FeedPlumberComponentError::Fatal(message) => (ERROR_FATAL, message),
+                FeedPlumberComponentError::Panic(message) => (ERROR_PANIC, message),
+            };
+            response.u8(severity);
+            response.str(&message);
+            response.0
+        }
+    }
+}
+
+enum HostedComponent {
+    Source(PluginSourceInstance),
+    Sink(PluginSinkInstance),
+    Processor(PluginProcessorInstance),
+}
+
+/// Entry point of a child process started with `--plugin-host`.
+pub fn run_plugin_host(socket: &Path, plugin: &Path) -> anyhow::Result<()> {
+    let plugin = load_plugin(plugin).ok_or(anyhow!("Unable to load plugin {plugin:?}"))?;
+    let mut stream = UnixStream::connect(socket).context("Connecting to service")?;
+
+    let request = read_frame(&mut stream).context("Reading creation request")?;
+    let mut decoder = Reader(&request);
+    if decoder.u8()? != REQUEST_CREATE {
+        return Err(anyhow!("Expected a creation request"));
+    }
+    let kind = ComponentKind::from_byte(decoder.u8()?)?;
+    let r#type = decoder.str()?;
+    let name = decoder.str()?;
+    let config = decoder.str()?;
+    let component = match kind {
+        ComponentKind::Source => plugin
+            .instantiate_source(&r#type, name, &config)
+            .map(|res| res.map(HostedComponent::Source)),
+        ComponentKind::Sink => plugin
+            .instantiate_sink(&r#type, name, &config)
+            .map(|res| res.map(HostedComponent::Sink)),
+        ComponentKind::Processor => plugin
+            .instantiate_processor(&r#type, name, &config)
+            .map(|res| res.map(HostedComponent::Processor)),
+    }
+    .unwrap_or_else(|| Err(format!("Plugin does not supply {type}")));
+    let mut component = match component {
+        Ok(component) => {
+            write_frame(&mut stream, &message(RESPONSE_CREATED).0)?;
+            component
+        }
+        Err(err) => {
+            let mut response = message(RESPONSE_CREATE_FAILED);
+            response.str(&err);
+            write_frame(&mut stream, &response.0)?;
+            return Ok(());
+        }
+    };
+
+    loop {
+        let request = match read_frame(&mut stream) {
+            Ok(request) => request,
+            // The service hung up, which is how children are told to exit
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err).context("Reading request"),
+        };
+        let mut decoder = Reader(&request);
+        let res = match (decoder.u8()?, &mut component) {
+            (REQUEST_POLL, HostedComponent::Source(source)) => source.poll_source(),
+            (REQUEST_SINK, HostedComponent::Sink(sink)) => sink
+                .sink_items(&decoder.items()?.into())
+                .map(|_| Items::empty()),
+            (REQUEST_PROCESS, HostedComponent::Processor(processor)) => {
+                processor.process_items(&decoder.items()?.into())
+            }
+            _ => return Err(anyhow!("Request does not match hosted component")),
+        };
+        write_frame(&mut stream, &encode_response(res))?;
+    }
+}
+
+fn write_frame(stream: &mut UnixStream, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(&(frame.len() as u32).to_le_bytes())?;
+    stream.write_all(frame)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(corrupt());
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Corrupt plugin host frame")
+}
+
+fn message(tag: u8) -> Writer {
+    let mut writer = Writer::new();
+    writer.u8(tag);
+    writer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) const FAKE_HOST_SOCKET: &str = "FEED_PLUMBER_TEST_HOST_SOCKET";
+
+    /// Stands in for [`run_plugin_host`] in children of the test binary. Answers every request
+    /// with an item holding its pid, or never with the config `hang`, and fails creation with
+    /// the config `fail`. Does nothing when run as a test.
+    #[test]
+    fn fake_plugin_host() {
+        let Some(socket) = std::env::var_os(FAKE_HOST_SOCKET) else {
+            return;
+        };
+        let mut stream = UnixStream::connect(socket).unwrap();
+        let request = read_frame(&mut stream).unwrap();
+        let mut decoder = Reader(&request);
+        assert_eq!(decoder.u8().unwrap(), REQUEST_CREATE);
+        assert_eq!(decoder.u8().unwrap(), ComponentKind::Source.to_byte());
+        assert_eq!(decoder.str().unwrap(), "fake");
+        assert_eq!(decoder.str().unwrap(), "test");
+        let config = decoder.str().unwrap();
+        if config == "fail" {
+            let mut response = message(RESPONSE_CREATE_FAILED);
+            response.str("Invalid config");
+            write_frame(&mut stream, &response.0).unwrap();
+            return;
+        }
+        write_frame(&mut stream, &message(RESPONSE_CREATED).0).unwrap();
+        while let Ok(request) = read_frame(&mut stream) {
+            assert_eq!(request, [REQUEST_POLL]);
+            if config == "hang" {
+                sleep(Duration::from_secs(60));
+            }
+            let pid = vec![vec![("pid".to_owned(), std::process::id().to_string())]];
+            write_frame(&mut stream, &encode_response(Ok(pid.into()))).unwrap();
+        }
+    }
+
+    fn spawn(config: &str, timeout: Duration) -> Result<RemoteComponent, String> {
+        RemoteComponent::spawn(
+            ComponentKind::Source,
+            Path::new("fake.so"),
+            "fake",
+            "test",
+            config,
+            timeout,
+        )
+    }
+
+    /// The pid the host answered a poll with, or the error's severity and message.
+    fn poll(remote: &mut RemoteComponent) -> Result<u32, (u8, String)> {
+        let items = remote.poll_source().map_err(|err| {
+            let (severity, message) = severity(&err);
+            (severity, message.to_owned())
+        })?;
+        let item = items.items().next().unwrap();
+        Ok(item[0].1.parse().unwrap())
+    }
+
+    fn pid(remote: &RemoteComponent) -> Option<u32> {
+        remote.child.as_ref().map(|(child, _)| child.id())
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let (mut service, mut host) = UnixStream::pair().unwrap();
+        let items = vec![
+            vec![("title".to_owned(), "Ünïcode".to_owned())],
+            vec![],
+            vec![
+                ("".to_owned(), "".to_owned()),
+                ("a".to_owned(), "b".to_owned()),
+            ],
+        ];
+        write_frame(&mut host, &encode_response(Ok(items.clone().into()))).unwrap();
+        write_frame(&mut host, &[]).unwrap();
+        let frame = read_frame(&mut service).unwrap();
+        match decode_response(&frame).unwrap() {
+            Ok(decoded) => assert!(decoded.items().eq(&items)),
+            Err(_) => panic!("Expected items"),
+        }
+        assert!(read_frame(&mut service).unwrap().is_empty());
+
+        for err in [
+            FeedPlumberComponentError::Warn("Slow down".to_owned()),
+            FeedPlumberComponentError::Fatal("Gone".to_owned()),
+            FeedPlumberComponentError::Panic("Oops".to_owned()),
+        ] {
+            let (expected, message) = severity(&err);
+            let message = message.to_owned();
+            write_frame(&mut host, &encode_response(Err(err))).unwrap();
+            let frame = read_frame(&mut service).unwrap();
+            match decode_response(&frame).unwrap() {
+                Ok(_) => panic!("Expected an error"),
+                Err(err) => assert_eq!(severity(&err), (expected, message.as_str())),
+            }
+        }
+    }
+
+    fn severity(err: &FeedPlumberComponentError) -> (u8, &str) {
+        match err {
+            FeedPlumberComponentError::Warn(message) => (ERROR_WARN, message),
+            FeedPlumberComponentError::Fatal(message) => (ERROR_FATAL, message),
+            FeedPlumberComponentError::Panic(message) => (ERROR_PANIC, message),
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_frames() {
+        let (mut service, mut host) = UnixStream::pair().unwrap();
+        host.write_all(&(MAX_FRAME_LEN as u32 + 1).to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut service).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert!(decode_response(&[RESPONSE_CREATED]).is_err());
+        assert!(decode_response(&[RESPONSE_ITEMS, 1]).is_err());
+        assert!(decode_response(&[]).is_err());
+    }
+
+    #[test]
+    fn reports_failed_creation() {
+        let err = spawn("fail", Duration::from_secs(10)).err().unwrap();
+        assert_eq!(err, "Invalid config");
+    }
+
+    #[test]
+    fn respawns_crashed_hosts() {
+        let mut remote = spawn("", Duration::from_secs(10)).unwrap();
+        let first = poll(&mut remote).unwrap();
+        assert_eq!(Some(first), pid(&remote));
+        assert_eq!(poll(&mut remote).unwrap(), first);
+
+        kill(&mut remote.child.as_mut().unwrap().0);
+        let (severity, message) = poll(&mut remote).unwrap_err();
+        assert_eq!(severity, ERROR_WARN);
+        assert!(message.starts_with("Plugin host crashed"), "{message}");
+        assert!(remote.child.is_none());
+
+        let second = poll(&mut remote).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(Some(second), pid(&remote));
+    }
+
+    #[test]
+    fn kills_hosts_that_time_out() {
+        let mut remote = spawn("hang", Duration::from_millis(200)).unwrap();
+        let hung = pid(&remote).unwrap();
+        let started = Instant::now();
+        assert_eq!(
+            poll(&mut remote),
+            Err((ERROR_WARN, "Plugin host timed out".to_owned()))
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(remote.child.is_none());
+        // SAFETY: Signal 0 only checks whether the process exists. It was reaped, so it does not.
+        assert_eq!(unsafe { libc::kill(hung as libc::pid_t, 0) }, -1);
+
+        // Respawned on the next call, which times out again
+        assert!(poll(&mut remote).is_err());
+        assert!(remote.child.is_none());
+    }
+}
@@ -2,25 +2,42 @@ use std::{
     borrow::Cow,
     collections::HashMap,
     path::Path,
-    sync::{Arc, Condvar, Mutex},
+    sync::Arc,
     thread,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::config::Pipeline;
 use chrono::Local;
 use clap::Parser;
-use crossbeam::channel::Sender;
-use log::{debug, error, warn, LevelFilter};
-use tap::{TapFallible, TapOptional};
+use crossbeam::channel::RecvTimeoutError;
+use log::{debug, error, info, warn, LevelFilter};
+use tap::TapFallible;
 
-use crate::sys::{FeedPlumberComponentError, Items};
+use crate::{
+    pipeline::{
+        stream_processor_loop, Component, ProcessorMessage, Registry, StreamMessage, QUEUE_CAPACITY,
+    },
+    supervisor::{Exit, Health, Supervisor},
+    sys::{Emitter, FeedPlumberComponentError, Items, Shutdown},
+    throttle::Outlet,
+};
 
 mod args;
+mod builtin;
 mod config;
+#[cfg(unix)]
+mod isolation;
+mod pipeline;
 mod plugin_loader;
+mod supervisor;
 mod sys;
+mod throttle;
+mod wasm;
+
+/// How long sources get to stop once the service is asked to shut down, and then how long sinks
+/// get to hand over the items they hold.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() -> anyhow::Result<()> {
     pretty_env_logger::formatted_builder()
@@ -28,30 +45,83 @@ fn main() -> anyhow::Result<()> {
         .parse_env("FEED_PLUMBER_LOG")
         .init();
 
-    let condvar = Condvar::new();
-    let mutex = Mutex::new(true);
-    let mutex_guard = mutex.lock().unwrap();
-
     let opts = args::Opts::parse();
 
+    #[cfg(unix)]
+    if let Some(socket) = opts.plugin_host {
+        let plugin = opts
+            .plugins
+            .first()
+            .ok_or(anyhow::anyhow!("No plugin given to host"))?;
+        return isolation::run_plugin_host(&socket, plugin)
+            .tap_err(|err| error!("Plugin host failed: {err:#}"));
+    }
+
     let plugin_dir_path = opts
         .directory
         .map(Cow::from)
         .unwrap_or(Cow::Borrowed(Path::new("plugins")));
-    let plugin_manager = Arc::new(
-        plugin_loader::PluginManager::load(plugin_dir_path, opts.plugins)
-            .tap_err(|err| error!("Unable to load plugins: {err}"))?,
-    );
-
     let config_path = opts
         .config
         .map(Cow::from)
         .unwrap_or(Cow::Borrowed(Path::new("feedplumber.toml")));
     let config = config::load_from_toml(config_path)?;
 
+    let plugin_manager = Arc::new(
+        plugin_loader::PluginManager::load(
+            plugin_dir_path,
+            opts.plugins,
+            &config.wasm,
+            Duration::from_millis(config.isolation_timeout),
+        )
+        .tap_err(|err| error!("Unable to load plugins: {err}"))?,
+    );
+
+    // Only the plugins tell which sources are polled and need a schedule
+    if let Some(source) = config.sources.iter().find(|source| {
+        source.schedule.is_none()
+            && !plugin_manager.event_source_available(&source.r#type)
+            && plugin_manager.source_available(&source.r#type)
+    }) {
+        error!("Source \"{}\" has no schedule.", &source.name);
+        anyhow::bail!("Source \"{}\" has no `schedule`", &source.name);
+    }
+    // Event sources and stream processors always run in-process
+    if let Some(source) = config.sources.iter().find(|source| {
+        source.isolated == Some(true) && plugin_manager.event_source_available(&source.r#type)
+    }) {
+        error!("Event source \"{}\" cannot be isolated.", &source.name);
+        anyhow::bail!(
+            "Event source \"{}\" is `isolated`, which event sources don't support",
+            &source.name
+        );
+    }
+    if let Some(processor) = config.processors.iter().find(|processor| {
+        processor.isolated == Some(true)
+            && plugin_manager.stream_processor_available(&processor.r#type)
+    }) {
+        error!(
+            "Stream processor \"{}\" cannot be isolated.",
+            &processor.name
+        );
+        anyhow::bail!(
+            "Stream processor \"{}\" is `isolated`, which stream processors don't support",
+            &processor.name
+        );
+    }
+
+    let shutdown = Shutdown::default();
+    let handler_shutdown = shutdown.clone();
+    if let Err(err) = ctrlc::set_handler(move || handler_shutdown.signal()) {
+        warn!("Unable to handle termination signals, components will not be stopped. {err}");
+    }
+
     let print_plugin_warnings = config.print_plugin_warnings;
+    let isolate_plugins = config.isolate_plugins;
 
-    let mut sinks_map = HashMap::new();
+    let mut registry = Registry::default();
+    // Every sink thread holds a sender, so all of them hang up once the sinks stopped
+    let (sink_stopped, all_sinks_stopped) = crossbeam::channel::bounded::<()>(0);
     for sink in config.sinks {
         if !plugin_manager.sink_available(&sink.r#type) {
             error!(
@@ -64,31 +134,92 @@ fn main() -> anyhow::Result<()> {
             error!("Unable to reserialize config for {}, skipping.", &sink.name);
             continue;
         };
-        if sinks_map.contains_key(&sink.name) {
+        if registry.sinks.contains_key(&sink.name) {
             warn!("Duplicate sink name {}, skipping.", &sink.name);
             continue;
         }
-        let (send, recv) = crossbeam::channel::unbounded::<Items>(); // TODO bounded
-        sinks_map.insert(sink.name.clone(), send);
+        let (send, recv) = crossbeam::channel::bounded::<Items>(QUEUE_CAPACITY);
+        let health = Health::default();
+        registry.sinks.insert(
+            sink.name.clone(),
+            Component {
+                sender: send,
+                health: health.clone(),
+            },
+        );
+        let isolated = sink.isolated.unwrap_or(isolate_plugins);
         let plugin_manager = plugin_manager.clone();
+        let stopped = sink_stopped.clone();
+        let shutdown = shutdown.clone();
 
         thread::spawn(move || {
-            let sink_inst = plugin_manager
-                .instantiate_sink(&sink.r#type, sink.name.clone(), &toml)
-                .unwrap()
-                .tap_err(|err| error!("Plugin processor \"{}\" could not be created due to an error. Plugin said: {err}", &sink.name));
-            let Ok(mut sink_inst) = sink_inst else {
-                return;
-            };
-            for item in recv {
-                debug!("Sinking items to \"{}\"", sink_inst.name());
-                sink_inst.sink_items(&item);
-            }
+            Supervisor::new("sink", &sink.name, &sink.restart, health).supervise(
+                || {
+                    let sink_inst = plugin_manager
+                        .instantiate_sink(&sink.r#type, sink.name.clone(), &toml, isolated)
+                        .unwrap()
+                        .tap_err(|err| error!("Plugin sink \"{}\" could not be created due to an error. Plugin said: {err}", &sink.name));
+                    let Ok(mut sink_inst) = sink_inst else {
+                        return Exit::Failed;
+                    };
+                    let mut outlet = Outlet::new(
+                        sink.rate_limit.as_ref(),
+                        sink.batch.as_ref(),
+                        shutdown.clone(),
+                    );
+                    loop {
+                        let message = match outlet.deadline() {
+                            Some(deadline) => recv.recv_deadline(deadline),
+                            None => recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                        };
+                        let (flush, finished) = match message {
+                            Ok(items) => {
+                                if !outlet.push(items) {
+                                    continue;
+                                }
+                                (false, false)
+                            }
+                            Err(RecvTimeoutError::Timeout) => (true, false),
+                            Err(RecvTimeoutError::Disconnected) => (true, true),
+                        };
+                        for items in outlet.drain(flush) {
+                            debug!("Sinking items to \"{}\"", sink_inst.name());
+                            match sink_inst.sink_items(&items) {
+                                Ok(()) => {}
+                                Err(FeedPlumberComponentError::Warn(err)) => {
+                                    if print_plugin_warnings {
+                                        warn!("Plugin sink \"{}\" errored while sinking items: {err}", sink_inst.name());
+                                    }
+                                }
+                                Err(FeedPlumberComponentError::Fatal(err)) => {
+                                    error!("Plugin sink \"{}\" errored while sinking items: {err}", sink_inst.name());
+                                    return Exit::Failed;
+                                }
+                                Err(FeedPlumberComponentError::Panic(err)) => {
+                                    error!("Plugin sink \"{}\" panicked while sinking items: {err}", sink_inst.name());
+                                    return Exit::Failed;
+                                }
+                            }
+                        }
+                        if finished {
+                            return Exit::Finished;
+                        }
+                    }
+                },
+                sleep,
+            );
+            drop(stopped);
         });
     }
+    drop(sink_stopped);
 
-    let mut processors_map = HashMap::new();
-    for processor in config.processors {
+    // Stream processors are set up once everything their pipelines can refer to exists
+    let (stream_processors, processors): (Vec<_>, Vec<_>) = config
+        .processors
+        .into_iter()
+        .partition(|processor| plugin_manager.stream_processor_available(&processor.r#type));
+
+    for processor in processors {
         if !plugin_manager.processor_available(&processor.r#type) {
             error!(
                 "Processor type \"{}\" unavailable. Skipping \"{}\"",
@@ -103,61 +234,242 @@ fn main() -> anyhow::Result<()> {
             );
             continue;
         };
-        if processors_map.contains_key(&processor.name) {
+        if registry.processors.contains_key(&processor.name) {
             warn!("Duplicate processor name {}, skipping.", &processor.name);
             continue;
         }
-        let (send, recv) = crossbeam::channel::unbounded::<ProcessorMessage>(); // TODO bounded
-        processors_map.insert(processor.name.clone(), send);
+        if let Some(Err(err)) = builtin::validate_processor(&processor.r#type, &toml) {
+            error!(
+                "Processor \"{}\" is misconfigured, skipping. {err}",
+                &processor.name
+            );
+            continue;
+        }
+        if !processor.pipe.is_empty() || processor.schedule.is_some() {
+            warn!(
+                "Processor \"{}\" is not a stream processor, ignoring its pipe and schedule.",
+                &processor.name
+            );
+        }
+        let (send, recv) = crossbeam::channel::bounded::<ProcessorMessage>(QUEUE_CAPACITY);
+        let health = Health::default();
+        registry.processors.insert(
+            processor.name.clone(),
+            Component {
+                sender: send,
+                health: health.clone(),
+            },
+        );
+        let isolated = processor.isolated.unwrap_or(isolate_plugins);
         let plugin_manager = plugin_manager.clone();
 
         thread::spawn(move || {
-            let processor_inst = plugin_manager
-                .instantiate_processor(&processor.r#type, processor.name.clone(), &toml)
-                .unwrap()
-                .tap_err(|err| error!("Plugin processor \"{}\" could not be created due to an error. Plugin said: {err}", &processor.name));
-            let Ok(mut processor_inst) = processor_inst else {
-                return;
-            };
-            for ProcessorMessage {
-                incoming,
-                responder,
-            } in recv
-            {
-                debug!("Processing items with \"{}\"", processor_inst.name());
-                let res = processor_inst.process_items(&incoming);
-                let items = match res {
-                    Ok(items) => items,
-                    Err(err) => {
-                        match err {
-                            FeedPlumberComponentError::Warn(err) => {
-                                if print_plugin_warnings {
-                                    warn!("Plugin processor \"{}\" errored while processing items: {err}", processor_inst.name());
+            Supervisor::new("processor", &processor.name, &processor.restart, health).supervise(
+                || {
+                    let processor_inst = plugin_manager
+                        .instantiate_processor(
+                            &processor.r#type,
+                            processor.name.clone(),
+                            &toml,
+                            isolated,
+                        )
+                        .unwrap()
+                        .tap_err(|err| error!("Plugin processor \"{}\" could not be created due to an error. Plugin said: {err}", &processor.name));
+                    let Ok(mut processor_inst) = processor_inst else {
+                        return Exit::Failed;
+                    };
+                    for ProcessorMessage {
+                        incoming,
+                        responder,
+                    } in &recv
+                    {
+                        debug!("Processing items with \"{}\"", processor_inst.name());
+                        let res = processor_inst.process_items(&incoming);
+                        let items = match res {
+                            Ok(items) => items,
+                            Err(err) => {
+                                match err {
+                                    FeedPlumberComponentError::Warn(err) => {
+                                        if print_plugin_warnings {
+                                            warn!("Plugin processor \"{}\" errored while processing items: {err}", processor_inst.name());
+                                        }
+                                        Items::empty()
+                                    }
+                                    FeedPlumberComponentError::Fatal(err) => {
+                                        error!(
+                                            "Plugin processor \"{}\" errored while processing items: {err}",
+                                            processor_inst.name()
+                                        );
+                                        return Exit::Failed;
+                                    }
+                                    FeedPlumberComponentError::Panic(err) => {
+                                        error!(
+                                            "Plugin processor \"{}\" panicked while processing items: {err}",
+                                            processor_inst.name()
+                                        );
+                                        return Exit::Failed;
+                                    }
                                 }
-                                Items::empty()
-                            }
-                            FeedPlumberComponentError::Fatal(err) => {
-                                error!(
-                                    "Plugin processor \"{}\" errored while processing items: {err}",
-                                    processor_inst.name()
-                                );
-                                return;
                             }
+                        };
+                        if responder.send(items).is_err() {
+                            error!(
+                                "Processor \"{}\" responding to source that has hung up!",
+                                processor_inst.name()
+                            );
                         }
                     }
-                };
-                if responder.send(items).is_err() {
-                    error!(
-                        "Processor \"{}\" responding to source that has hung up!",
-                        processor_inst.name()
-                    );
-                }
-            }
+                    Exit::Finished
+                },
+                |delay| {
+                    // Items sent while restarting are dropped so sources are not left waiting.
+                    let deadline = Instant::now() + delay;
+                    while recv.recv_deadline(deadline).is_ok() {
+                        debug!(
+                            "Dropping items sent to restarting processor \"{}\"",
+                            &processor.name
+                        );
+                    }
+                },
+            );
+        });
+    }
+
+    for pipeline in config.pipelines {
+        if registry.pipelines.contains_key(&pipeline.name) {
+            warn!("Duplicate pipeline name {}, skipping.", &pipeline.name);
+            continue;
+        }
+        registry.pipelines.insert(pipeline.name, pipeline.pipe);
+    }
+
+    let mut stream_receivers = Vec::new();
+    for processor in stream_processors {
+        if registry.processors.contains_key(&processor.name)
+            || registry.stream_processors.contains_key(&processor.name)
+        {
+            warn!("Duplicate processor name {}, skipping.", &processor.name);
+            continue;
+        }
+        let (send, recv) = crossbeam::channel::bounded::<StreamMessage>(QUEUE_CAPACITY);
+        let health = Health::default();
+        registry.stream_processors.insert(
+            processor.name.clone(),
+            Component {
+                sender: send,
+                health: health.clone(),
+            },
+        );
+        stream_receivers.push((processor, recv, health));
+    }
+    let stream_pipes = stream_receivers
+        .iter()
+        .map(|(processor, _, _)| (processor.name.as_str(), processor.pipe.as_slice()))
+        .collect::<HashMap<_, _>>();
+    if let Some(steps) = stream_processor_loop(&registry.pipelines, &stream_pipes) {
+        let steps = steps.join(" -> ");
+        error!("Stream processors feed into themselves: {steps}");
+        anyhow::bail!("Stream processors feed into themselves: {steps}");
+    }
+
+    for (processor, recv, health) in stream_receivers {
+        let Ok(toml) = toml::to_string(&processor).tap_err(|err| debug!("{err}")) else {
+            error!(
+                "Unable to reserialize config for processor {}, skipping.",
+                &processor.name
+            );
+            continue;
+        };
+        let pipelines = registry.construct_all(&processor.name, &processor.pipe);
+        if pipelines.is_empty() {
+            error!(
+                "All pipelines for stream processor \"{}\" are invalid. Skipping.",
+                &processor.name
+            );
+            continue;
+        }
+        let plugin_manager = plugin_manager.clone();
+
+        thread::spawn(move || {
+            Supervisor::new("stream processor", &processor.name, &processor.restart, health)
+                .supervise(
+                    || {
+                        let processor_inst = plugin_manager
+                            .instantiate_stream_processor(
+                                &processor.r#type,
+                                processor.name.clone(),
+                                &toml,
+                            )
+                            .unwrap()
+                            .tap_err(|err| error!("Plugin stream processor \"{}\" could not be created due to an error. Plugin said: {err}", &processor.name));
+                        let Ok(mut processor_inst) = processor_inst else {
+                            return Exit::Failed;
+                        };
+                        let mut upcoming = processor.schedule.as_ref().map(|a| a.upcoming(Local));
+                        let mut next = upcoming.as_mut().and_then(Iterator::next);
+                        loop {
+                            let mut finished = false;
+                            let message = match next {
+                                Some(at) => {
+                                    let wait = (at - Local::now()).to_std().unwrap_or_default();
+                                    recv.recv_timeout(wait)
+                                }
+                                None => recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                            };
+                            let res = match message {
+                                Ok(StreamMessage { source, items }) => {
+                                    debug!("Receiving items from \"{source}\" with \"{}\"", processor_inst.name());
+                                    processor_inst.receive_items(&source, &items)
+                                }
+                                Err(RecvTimeoutError::Timeout) => {
+                                    next = upcoming.as_mut().and_then(Iterator::next);
+                                    debug!("Flushing \"{}\"", processor_inst.name());
+                                    processor_inst.flush()
+                                }
+                                Err(RecvTimeoutError::Disconnected) => {
+                                    // Whatever is held back goes out before stopping
+                                    finished = true;
+                                    debug!("Flushing \"{}\" before stopping", processor_inst.name());
+                                    processor_inst.flush()
+                                }
+                            };
+                            let items = match res {
+                                Ok(items) => items,
+                                Err(FeedPlumberComponentError::Warn(err)) => {
+                                    if print_plugin_warnings {
+                                        warn!("Plugin stream processor \"{}\" errored: {err}", processor_inst.name());
+                                    }
+                                    Items::empty()
+                                }
+                                Err(FeedPlumberComponentError::Fatal(err)) => {
+                                    error!("Plugin stream processor \"{}\" errored: {err}", processor_inst.name());
+                                    return Exit::Failed;
+                                }
+                                Err(FeedPlumberComponentError::Panic(err)) => {
+                                    error!("Plugin stream processor \"{}\" panicked: {err}", processor_inst.name());
+                                    return Exit::Failed;
+                                }
+                            };
+                            if !items.is_empty() {
+                                for pipe in &pipelines {
+                                    pipe.send(&items);
+                                }
+                            }
+                            if finished {
+                                return Exit::Finished;
+                            }
+                        }
+                    },
+                    sleep,
+                );
         });
     }
 
-    for source in config.sources {
-        if !plugin_manager.source_available(&source.r#type) {
+    // Every source thread holds a sender, so all of them hang up once they stopped
+    let (stopped, all_stopped) = crossbeam::channel::bounded::<()>(0);
+    for mut source in config.sources {
+        let event = plugin_manager.event_source_available(&source.r#type);
+        if !event && !plugin_manager.source_available(&source.r#type) {
             error!(
                 "Source type \"{}\" unavailable. Skipping \"{}\"",
                 &source.r#type, &source.name
@@ -171,37 +483,7 @@ fn main() -> anyhow::Result<()> {
             );
             continue;
         };
-        let mut senders = Vec::new();
-        'pipeloop: for pipe in source.pipe {
-            let mut proc_senders = Vec::new();
-            for proc in &pipe.processors {
-                if let Some(sender) = processors_map.get(proc) {
-                    proc_senders.push(sender.clone());
-                } else {
-                    warn!(
-                        "Pipeline invalid as processor {proc} does not exist. Pipeline: \"{pipe}\""
-                    );
-                    continue 'pipeloop;
-                }
-            }
-
-            let pipe_senders = sinks_map
-                .get(&pipe.sink)
-                .cloned()
-                .tap_none(|| {
-                    warn!(
-                        "Pipeline invalid as sink \"{}\" does not exist. Pipeline: \"{}\"",
-                        &pipe.sink, pipe
-                    )
-                })
-                .map(|a| ConstructedPipeline {
-                    processors: proc_senders,
-                    sink: a,
-                    original: pipe,
-                });
-
-            senders.extend(pipe_senders);
-        }
+        let senders = registry.construct_all(&source.name, &source.pipe);
         if senders.is_empty() {
             error!(
                 "All pipelines for source \"{}\" are invalid. Skipping.",
@@ -210,89 +492,143 @@ fn main() -> anyhow::Result<()> {
             continue;
         }
         let pm = plugin_manager.clone();
-        thread::spawn(move || {
-            let mut errored = Vec::new();
-            let source_inst = pm
-                .instantiate_source(&source.r#type, source.name.clone(), &toml)
-                .unwrap()
-                .tap_err(|err| error!("Plugin source \"{}\" could not be created due to an error. Plugin said: {err}", &source.name));
-            let Ok(mut source_inst) = source_inst else {
-                return;
-            };
-            let mut upcoming = source.schedule.upcoming(Local);
-            let mut next = upcoming.next().unwrap();
-            loop {
-                if next <= Local::now() {
-                    next = upcoming.next().unwrap();
-                    let source_items = source_inst.poll_source();
-                    let source_items = match source_items {
-                        Ok(source_items) => source_items,
-                        Err(err) => match err {
-                            FeedPlumberComponentError::Warn(err) => {
-                                if print_plugin_warnings {
-                                    warn!("Plugin source \"{}\" has errored while polling items. Skipping this batch. Plugin said {err}", &source.name);
-                                }
-                                Items::empty()
-                            }
-                            FeedPlumberComponentError::Fatal(err) => {
-                                error!("Plugin source \"{}\" has errored while polling items. Disabling source. Plugin said {err}", &source.name);
+        if event {
+            if source.schedule.is_some() {
+                warn!(
+                    "Source \"{}\" emits items as they arrive, ignoring its schedule.",
+                    &source.name
+                );
+            }
+            let shutdown = shutdown.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                Supervisor::new("source", &source.name, &source.restart, Health::default()).supervise(
+                    || {
+                        if shutdown.is_signalled() {
+                            return Exit::Finished;
+                        }
+                        let source_inst = pm
+                            .instantiate_event_source(&source.r#type, source.name.clone(), &toml)
+                            .unwrap()
+                            .tap_err(|err| error!("Plugin source \"{}\" could not be created due to an error. Plugin said: {err}", &source.name));
+                        let Ok(mut source_inst) = source_inst else {
+                            return Exit::Failed;
+                        };
+                        let emit = |items: Items| {
+                            if items.is_empty() {
                                 return;
                             }
-                        },
-                    };
-                    if !source_items.is_empty() {
-                        'pipeline: for (idx, pipe) in senders.iter().enumerate() {
-                            if errored.contains(&idx) {
-                                continue;
+                            debug!("Source \"{}\" emitted {} items.", &source.name, items.len());
+                            for pipe in &senders {
+                                pipe.send(&items);
+                            }
+                        };
+                        match source_inst.run(&Emitter::new(&emit, &shutdown)) {
+                            Ok(()) => {
+                                debug!("Source \"{}\" stopped.", source_inst.name());
+                                Exit::Finished
+                            }
+                            Err(FeedPlumberComponentError::Warn(err) | FeedPlumberComponentError::Fatal(err)) => {
+                                error!("Plugin source \"{}\" has errored. Plugin said {err}", source_inst.name());
+                                Exit::Failed
+                            }
+                            Err(FeedPlumberComponentError::Panic(err)) => {
+                                error!("Plugin source \"{}\" has panicked. Plugin said {err}", source_inst.name());
+                                Exit::Failed
                             }
-                            let mut final_items = source_items.clone();
-                            for (idx, proc) in pipe.processors.iter().enumerate() {
-                                let (response_sender, response_receiver) =
-                                    crossbeam::channel::bounded(0);
-                                match proc.send(ProcessorMessage {
-                                    incoming: final_items.clone(),
-                                    responder: response_sender,
-                                }) {
-                                    Ok(_) => {
-                                        final_items = response_receiver.recv().unwrap();
-                                        if final_items.is_empty() {
-                                            continue 'pipeline;
+                        }
+                    },
+                    |delay| {
+                        shutdown.wait_timeout(delay);
+                    },
+                );
+                drop(stopped);
+            });
+            continue;
+        }
+        let schedule = source
+            .schedule
+            .take()
+            .expect("polled sources were checked for a schedule");
+        let isolated = source.isolated.unwrap_or(isolate_plugins);
+        let shutdown = shutdown.clone();
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            Supervisor::new("source", &source.name, &source.restart, Health::default()).supervise(
+                || {
+                    if shutdown.is_signalled() {
+                        return Exit::Finished;
+                    }
+                    let source_inst = pm
+                        .instantiate_source(&source.r#type, source.name.clone(), &toml, isolated)
+                        .unwrap()
+                        .tap_err(|err| error!("Plugin source \"{}\" could not be created due to an error. Plugin said: {err}", &source.name));
+                    let Ok(mut source_inst) = source_inst else {
+                        return Exit::Failed;
+                    };
+                    let mut upcoming = schedule.upcoming(Local);
+                    let mut next = upcoming.next().unwrap();
+                    loop {
+                        if next <= Local::now() {
+                            next = upcoming.next().unwrap();
+                            let source_items = source_inst.poll_source();
+                            let source_items = match source_items {
+                                Ok(source_items) => source_items,
+                                Err(err) => match err {
+                                    FeedPlumberComponentError::Warn(err) => {
+                                        if print_plugin_warnings {
+                                            warn!("Plugin source \"{}\" has errored while polling items. Skipping this batch. Plugin said {err}", &source.name);
                                         }
+                                        Items::empty()
                                     }
-                                    Err(_) => {
-                                        errored.push(idx);
-                                        warn!("Pipeline \"{}\" on source \"{}\" has errored. Skipping for future polls.", &pipe.original, &source.name);
-                                        continue 'pipeline;
+                                    FeedPlumberComponentError::Fatal(err) => {
+                                        error!("Plugin source \"{}\" has errored while polling items. Plugin said {err}", &source.name);
+                                        return Exit::Failed;
                                     }
+                                    FeedPlumberComponentError::Panic(err) => {
+                                        error!("Plugin source \"{}\" has panicked while polling items. Plugin said {err}", &source.name);
+                                        return Exit::Failed;
+                                    }
+                                },
+                            };
+                            if !source_items.is_empty() {
+                                for pipe in &senders {
+                                    pipe.send(&source_items);
                                 }
+                            } else {
+                                debug!("Source \"{}\" returned no items.", &source.name);
                             }
-                            if final_items.is_empty() {
-                                continue 'pipeline;
-                            }
-                            if pipe.sink.send(final_items).is_err() {
-                                warn!("Pipeline \"{}\" on source \"{}\" has errored. Skipping for future polls.", &pipe.original, &source.name);
-                                errored.push(idx);
-                            }
                         }
-                    } else {
-                        debug!("Source \"{}\" returned no items.", &source.name);
+                        if shutdown.wait_timeout(Duration::from_millis(config.time_between_ticks as u64)) {
+                            return Exit::Finished;
+                        }
                     }
-                }
-                sleep(Duration::from_millis(config.time_between_ticks as u64));
-            }
+                },
+                |delay| {
+                    shutdown.wait_timeout(delay);
+                },
+            );
+            drop(stopped);
         });
     }
-    drop(condvar.wait(mutex_guard).unwrap());
-    Ok(())
-}
-
-struct ConstructedPipeline {
-    processors: Vec<Sender<ProcessorMessage>>,
-    sink: Sender<Items>,
-    original: Pipeline,
-}
+    drop(stopped);
 
-struct ProcessorMessage {
-    incoming: Items,
-    responder: Sender<Items>,
+    shutdown.wait();
+    info!("Shutting down.");
+    if let Err(RecvTimeoutError::Timeout) = all_stopped.recv_timeout(SHUTDOWN_TIMEOUT) {
+        warn!(
+            "Sources did not stop within {} s, exiting anyway.",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+        return Ok(());
+    }
+    // With the sources' pipelines gone, this hangs up on the sinks, which send what they hold
+    drop(registry);
+    if let Err(RecvTimeoutError::Timeout) = all_sinks_stopped.recv_timeout(SHUTDOWN_TIMEOUT) {
+        warn!(
+            "Sinks did not stop within {} s, exiting anyway.",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,628 @@
+//! Pipelines as they run: the graph described by a [`Pipeline`], bound to the channels of the
+//! processors and sinks in it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use crossbeam::channel::Sender;
+use log::{debug, info, warn};
+use tap::TapFallible;
+
+use crate::{
+    config::{Pipeline, Stage},
+    supervisor::{Health, HealthState},
+    sys::Items,
+};
+
+/// How many batches can wait for a pipeline, processor or sink before whatever sends them has to
+/// wait as well.
+pub const QUEUE_CAPACITY: usize = 64;
+
+pub struct Component<T> {
+    pub sender: Sender<T>,
+    pub health: Health,
+}
+
+impl<T> Clone for Component<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            health: self.health.clone(),
+        }
+    }
+}
+
+pub struct ProcessorMessage {
+    pub incoming: Items,
+    pub responder: Sender<Items>,
+}
+
+pub struct StreamMessage {
+    pub source: String,
+    pub items: Items,
+}
+
+/// Everything pipelines can refer to by name.
+#[derive(Default)]
+pub struct Registry {
+    pub pipelines: HashMap<String, Pipeline>,
+    pub processors: HashMap<String, Component<ProcessorMessage>>,
+    pub stream_processors: HashMap<String, Component<StreamMessage>>,
+    pub sinks: HashMap<String, Component<Items>>,
+}
+
+impl Registry {
+    /// Constructs and starts the pipelines of `owner`, logging and leaving out the invalid ones.
+    pub fn construct_all(&self, owner: &str, pipes: &[Pipeline]) -> Vec<PipelineHandle> {
+        pipes
+            .iter()
+            .filter_map(|pipe| {
+                ConstructedPipeline::construct(pipe, self)
+                    .tap_err(|err| warn!("Pipeline invalid as {err}. Pipeline: \"{pipe}\""))
+                    .ok()
+            })
+            .map(|pipeline| pipeline.spawn(owner.to_owned()))
+            .collect()
+    }
+}
+
+/// Finds a stream processor whose pipelines lead back into it, directly or through other stream
+/// processors, which would block on its own full queue for good. Returns the loop, starting and
+/// ending with the same stream processor.
+pub fn stream_processor_loop(
+    named: &HashMap<String, Pipeline>,
+    pipes: &HashMap<&str, &[Pipeline]>,
+) -> Option<Vec<String>> {
+    fn visit<'a>(
+        name: &'a str,
+        next: &HashMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        visited: &mut HashSet<&'a str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|step| *step == name) {
+            let mut steps = path[start..]
+                .iter()
+                .map(|step| step.to_string())
+                .collect::<Vec<_>>();
+            steps.push(name.to_owned());
+            return Some(steps);
+        }
+        if !visited.insert(name) {
+            return None;
+        }
+        path.push(name);
+        for to in &next[name] {
+            if let Some(steps) = visit(to, next, path, visited) {
+                return Some(steps);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    let next = pipes
+        .iter()
+        .map(|(&name, processor_pipes)| {
+            let mut graph = Graph::new(named);
+            for pipe in processor_pipes.iter() {
+                // Invalid pipelines are reported once they are constructed
+                let _ = graph.chain(&pipe.stages);
+            }
+            let to = graph
+                .names
+                .iter()
+                .filter_map(|step| pipes.get_key_value(step.as_str()).map(|(to, _)| *to))
+                .collect::<Vec<_>>();
+            (name, to)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut names = pipes.keys().copied().collect::<Vec<_>>();
+    names.sort_unstable();
+    let mut visited = HashSet::new();
+    names
+        .into_iter()
+        .find_map(|name| visit(name, &next, &mut Vec::new(), &mut visited))
+}
+
+/// A pipeline running on its own thread. Batches sent to it are run in order, while the sender
+/// and other pipelines carry on.
+pub struct PipelineHandle {
+    name: String,
+    owner: String,
+    sender: Sender<Items>,
+    /// Whether the queue was full at the last send, so that is logged once.
+    full: AtomicBool,
+}
+
+impl PipelineHandle {
+    pub fn send(&self, items: &Items) {
+        let waiting = self.sender.len();
+        if waiting > 0 {
+            debug!(
+                "Pipeline \"{}\" on source \"{}\" is busy, queueing a batch behind {waiting} others.",
+                &self.name, &self.owner
+            );
+        }
+        if self.sender.is_full() {
+            if !self.full.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Pipeline \"{}\" on source \"{}\" has {QUEUE_CAPACITY} batches queued, holding up the source until it catches up.",
+                    &self.name, &self.owner
+                );
+            }
+        } else if self.full.swap(false, Ordering::Relaxed) {
+            info!(
+                "Pipeline \"{}\" on source \"{}\" has caught up.",
+                &self.name, &self.owner
+            );
+        }
+        if self.sender.send(items.clone()).is_err() {
+            warn!(
+                "Pipeline \"{}\" on source \"{}\" has stopped.",
+                &self.name, &self.owner
+            );
+        }
+    }
+}
+
+enum Step {
+    Processor(Component<ProcessorMessage>),
+    StreamProcessor(Component<StreamMessage>),
+    Sink(Component<Items>),
+}
+
+impl Step {
+    fn health(&self) -> &Health {
+        match self {
+            Step::Processor(component) => &component.health,
+            Step::StreamProcessor(component) => &component.health,
+            Step::Sink(component) => &component.health,
+        }
+    }
+
+    fn queue_full(&self) -> bool {
+        match self {
+            Step::Processor(component) => component.sender.is_full(),
+            Step::StreamProcessor(component) => component.sender.is_full(),
+            Step::Sink(component) => component.sender.is_full(),
+        }
+    }
+}
+
+struct Node {
+    name: String,
+    step: Step,
+    /// Indices of the nodes this node's output goes to. These always come later in the pipeline.
+    next: Vec<usize>,
+    /// Whether the node receives the source's items.
+    entry: bool,
+}
+
+pub struct ConstructedPipeline {
+    /// In topological order.
+    nodes: Vec<Node>,
+    /// What the pipeline is called in logs: the name of the named pipeline it refers to, or its
+    /// text.
+    name: String,
+    errored: HashSet<usize>,
+    suspended: HashSet<usize>,
+    /// The nodes whose queue was full at the last batch.
+    congested: HashSet<usize>,
+}
+
+/// The graph of a pipeline by component name, before it is bound to components.
+struct Graph<'a> {
+    named: &'a HashMap<String, Pipeline>,
+    /// The named pipelines currently being expanded, to catch pipelines that refer to themselves.
+    expanding: Vec<&'a str>,
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+    next: Vec<Vec<usize>>,
+}
+
+impl<'a> Graph<'a> {
+    fn new(named: &'a HashMap<String, Pipeline>) -> Self {
+        Self {
+            named,
+            expanding: Vec::new(),
+            names: Vec::new(),
+            index: HashMap::new(),
+            next: Vec::new(),
+        }
+    }
+
+    fn node(&mut self, name: &str) -> usize {
+        if let Some(&idx) = self.index.get(name) {
+            return idx;
+        }
+        self.names.push(name.to_owned());
+        self.next.push(Vec::new());
+        self.index.insert(name.to_owned(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    /// Adds a chain of stages, returning the nodes it starts and ends with.
+    fn chain(&mut self, stages: &'a [Stage]) -> Result<(Vec<usize>, Vec<usize>), String> {
+        let mut entries = None;
+        let mut exits: Vec<usize> = Vec::new();
+        for stage in stages {
+            let (stage_entries, stage_exits) = match stage {
+                Stage::Component(name) => {
+                    let idx = self.node(name);
+                    (vec![idx], vec![idx])
+                }
+                Stage::Reference(name) => {
+                    let pipeline = self
+                        .named
+                        .get(name)
+                        .ok_or_else(|| format!("pipeline \"{name}\" does not exist"))?;
+                    if self.expanding.contains(&name.as_str()) {
+                        return Err(format!("pipeline \"{name}\" refers to itself"));
+                    }
+                    self.expanding.push(name);
+                    let res = self.chain(&pipeline.stages)?;
+                    self.expanding.pop();
+                    res
+                }
+                Stage::Branches(chains) => {
+                    let mut stage_entries = Vec::new();
+                    let mut stage_exits = Vec::new();
+                    for chain in chains {
+                        let (chain_entries, chain_exits) = self.chain(chain)?;
+                        stage_entries.extend(chain_entries);
+                        stage_exits.extend(chain_exits);
+                    }
+                    (stage_entries, stage_exits)
+                }
+            };
+            for &from in &exits {
+                for &to in &stage_entries {
+                    if !self.next[from].contains(&to) {
+                        self.next[from].push(to);
+                    }
+                }
+            }
+            entries.get_or_insert(stage_entries);
+            exits = stage_exits;
+        }
+        Ok((entries.unwrap_or_default(), exits))
+    }
+
+    /// Orders the nodes so that every node comes after the nodes feeding it.
+    fn topological_order(&self) -> Result<Vec<usize>, String> {
+        let mut incoming = vec![0usize; self.names.len()];
+        for next in self.next.iter().flatten() {
+            incoming[*next] += 1;
+        }
+        let mut ready = (0..self.names.len())
+            .filter(|idx| incoming[*idx] == 0)
+            .collect::<Vec<_>>();
+        let mut order = Vec::new();
+        while let Some(idx) = ready.pop() {
+            order.push(idx);
+            for &next in &self.next[idx] {
+                incoming[next] -= 1;
+                if incoming[next] == 0 {
+                    ready.push(next);
+                }
+            }
+        }
+        match (0..self.names.len()).find(|idx| incoming[*idx] > 0) {
+            Some(idx) => Err(format!("\"{}\" feeds into itself", self.names[idx])),
+            None => Ok(order),
+        }
+    }
+}
+
+impl ConstructedPipeline {
+    pub fn construct(pipeline: &Pipeline, registry: &Registry) -> Result<Self, String> {
+        let mut graph = Graph::new(&registry.pipelines);
+        let (entries, _) = graph.chain(&pipeline.stages)?;
+        let order = graph.topological_order()?;
+        let position = order
+            .iter()
+            .enumerate()
+            .map(|(position, idx)| (*idx, position))
+            .collect::<HashMap<_, _>>();
+
+        let mut nodes = Vec::new();
+        for idx in order {
+            let name = graph.names[idx].clone();
+            let next = graph.next[idx]
+                .iter()
+                .map(|next| position[next])
+                .collect::<Vec<_>>();
+            // Stream processors have their own pipelines, so they can only end this one
+            let step = if next.is_empty() {
+                if let Some(sink) = registry.sinks.get(&name) {
+                    Step::Sink(sink.clone())
+                } else if let Some(processor) = registry.stream_processors.get(&name) {
+                    Step::StreamProcessor(processor.clone())
+                } else {
+                    return Err(format!("sink \"{name}\" does not exist"));
+                }
+            } else if let Some(processor) = registry.processors.get(&name) {
+                Step::Processor(processor.clone())
+            } else if registry.stream_processors.contains_key(&name) {
+                return Err(format!(
+                    "stream processor \"{name}\" is followed by other steps"
+                ));
+            } else {
+                return Err(format!("processor \"{name}\" does not exist"));
+            };
+            nodes.push(Node {
+                name,
+                step,
+                next,
+                entry: entries.contains(&idx),
+            });
+        }
+        Ok(Self {
+            nodes,
+            name: pipeline
+                .reference()
+                .map(ToOwned::to_owned)
+                .unwrap_or_else(|| pipeline.to_string()),
+            errored: HashSet::new(),
+            suspended: HashSet::new(),
+            congested: HashSet::new(),
+        })
+    }
+
+    fn spawn(mut self, owner: String) -> PipelineHandle {
+        let (sender, receiver) = crossbeam::channel::bounded::<Items>(QUEUE_CAPACITY);
+        let handle = PipelineHandle {
+            name: self.name.clone(),
+            owner: owner.clone(),
+            sender,
+            full: AtomicBool::new(false),
+        };
+        thread::spawn(move || {
+            for items in receiver {
+                self.run(&items, &owner);
+            }
+        });
+        handle
+    }
+
+    /// Whether a node can take items right now, logging when that changes.
+    fn available(&mut self, idx: usize, source: &str) -> bool {
+        if self.errored.contains(&idx) {
+            return false;
+        }
+        let name = &self.nodes[idx].name;
+        match self.nodes[idx].step.health().state() {
+            HealthState::Stopped => {
+                warn!(
+                    "Pipeline \"{}\" on source \"{source}\" has errored at \"{name}\". Skipping it for future polls.",
+                    &self.name
+                );
+                self.errored.insert(idx);
+                false
+            }
+            HealthState::Restarting => {
+                if self.suspended.insert(idx) {
+                    warn!(
+                        "Pipeline \"{}\" on source \"{source}\" is suspended at \"{name}\" while it restarts.",
+                        &self.name
+                    );
+                }
+                false
+            }
+            HealthState::Running => {
+                if self.suspended.remove(&idx) {
+                    info!(
+                        "Pipeline \"{}\" on source \"{source}\" has been re-enabled at \"{name}\".",
+                        &self.name
+                    );
+                }
+                true
+            }
+        }
+    }
+
+    /// Logs when a node's queue fills up, as the pipeline then waits for it, and when it has room
+    /// again.
+    fn check_queue(&mut self, idx: usize, source: &str) {
+        let name = &self.nodes[idx].name;
+        if self.nodes[idx].step.queue_full() {
+            if self.congested.insert(idx) {
+                warn!(
+                    "Pipeline \"{}\" on source \"{source}\" is waiting for \"{name}\", which has {QUEUE_CAPACITY} batches queued.",
+                    &self.name
+                );
+            }
+        } else if self.congested.remove(&idx) {
+            info!(
+                "Pipeline \"{}\" on source \"{source}\" no longer waits for \"{name}\".",
+                &self.name
+            );
+        }
+    }
+
+    /// Sends a batch from `source` through the pipeline. Each processor runs at most once, on the
+    /// combined output of the steps before it.
+    fn run(&mut self, items: &Items, source: &str) {
+        let mut inputs = vec![Items::empty(); self.nodes.len()];
+        for idx in 0..self.nodes.len() {
+            let mut input = mem::replace(&mut inputs[idx], Items::empty());
+            if self.nodes[idx].entry {
+                input.extend(items.clone());
+            }
+            if input.is_empty() || !self.available(idx, source) {
+                continue;
+            }
+            self.check_queue(idx, source);
+            let node = &self.nodes[idx];
+            match &node.step {
+                Step::Processor(processor) => {
+                    let (response_sender, response_receiver) = crossbeam::channel::bounded(1);
+                    let sent = processor.sender.send(ProcessorMessage {
+                        incoming: input,
+                        responder: response_sender,
+                    });
+                    if sent.is_err() {
+                        warn!(
+                            "Pipeline \"{}\" on source \"{source}\" has errored at \"{}\". Skipping it for future polls.",
+                            &self.name, &node.name
+                        );
+                        self.errored.insert(idx);
+                        continue;
+                    }
+                    let Ok(output) = response_receiver.recv() else {
+                        warn!(
+                            "Pipeline \"{}\" on source \"{source}\" lost a batch to failing processor \"{}\".",
+                            &self.name, &node.name
+                        );
+                        continue;
+                    };
+                    if output.is_empty() {
+                        continue;
+                    }
+                    for &next in &node.next {
+                        inputs[next].extend(output.clone());
+                    }
+                }
+                Step::StreamProcessor(processor) => {
+                    let sent = processor.sender.send(StreamMessage {
+                        source: source.to_owned(),
+                        items: input,
+                    });
+                    if sent.is_err() {
+                        warn!(
+                            "Pipeline \"{}\" on source \"{source}\" has errored at \"{}\". Skipping it for future polls.",
+                            &self.name, &node.name
+                        );
+                        self.errored.insert(idx);
+                    }
+                }
+                Step::Sink(sink) => {
+                    if sink.sender.send(input).is_err() {
+                        warn!(
+                            "Pipeline \"{}\" on source \"{source}\" has errored at \"{}\". Skipping it for future polls.",
+                            &self.name, &node.name
+                        );
+                        self.errored.insert(idx);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The steps of `pipeline` in the order they run, with `named` as the named pipelines.
+    fn order(pipeline: &str, named: &[(&str, &str)]) -> Result<Vec<String>, String> {
+        let named = named
+            .iter()
+            .map(|(name, pipe)| (name.to_string(), pipe.parse().unwrap()))
+            .collect::<HashMap<_, Pipeline>>();
+        let pipeline = pipeline.parse::<Pipeline>().unwrap();
+        let mut graph = Graph::new(&named);
+        graph.chain(&pipeline.stages)?;
+        let order = graph.topological_order()?;
+        Ok(order
+            .into_iter()
+            .map(|idx| graph.names[idx].clone())
+            .collect())
+    }
+
+    #[test]
+    fn runs_each_step_once_after_everything_feeding_it() {
+        let order = order("a->{b->d, c->d}->e", &[]).unwrap();
+        assert_eq!(order.len(), 5, "{order:?}");
+        let position = |name: &str| order.iter().position(|step| step == name).unwrap();
+        assert_eq!(position("a"), 0);
+        assert!(position("b") < position("d") && position("c") < position("d"));
+        assert_eq!(position("e"), 4);
+    }
+
+    #[test]
+    fn expands_named_pipelines() {
+        assert_eq!(
+            order("a->@rest", &[("rest", "b->@end"), ("end", "c")]).unwrap(),
+            ["a", "b", "c"]
+        );
+        // The same pipeline twice is not a cycle
+        assert_eq!(
+            order("a->{@end, b->@end}", &[("end", "c")]).unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn rejects_named_pipelines_referring_to_themselves() {
+        assert_eq!(
+            order("a->@loop", &[("loop", "b->@loop")]),
+            Err("pipeline \"loop\" refers to itself".to_owned())
+        );
+        assert_eq!(
+            order("@x", &[("x", "a->@y"), ("y", "{b, @x}")]),
+            Err("pipeline \"x\" refers to itself".to_owned())
+        );
+        assert_eq!(
+            order("a->@missing", &[]),
+            Err("pipeline \"missing\" does not exist".to_owned())
+        );
+    }
+
+    /// The loop among stream processors with `pipes`, with `named` as the named pipelines.
+    fn stream_loop(pipes: &[(&str, &str)], named: &[(&str, &str)]) -> Option<String> {
+        let named = named
+            .iter()
+            .map(|(name, pipe)| (name.to_string(), pipe.parse().unwrap()))
+            .collect::<HashMap<_, Pipeline>>();
+        let parsed = pipes
+            .iter()
+            .map(|(name, pipe)| (*name, vec![pipe.parse::<Pipeline>().unwrap()]))
+            .collect::<Vec<_>>();
+        let pipes = parsed
+            .iter()
+            .map(|(name, pipes)| (*name, pipes.as_slice()))
+            .collect::<HashMap<_, _>>();
+        stream_processor_loop(&named, &pipes).map(|steps| steps.join(" -> "))
+    }
+
+    #[test]
+    fn rejects_stream_processors_feeding_into_themselves() {
+        assert_eq!(
+            stream_loop(&[("digest", "dedup->digest")], &[]).as_deref(),
+            Some("digest -> digest")
+        );
+        assert_eq!(
+            stream_loop(
+                &[("a", "{log, b}"), ("b", "x->@back"), ("c", "log")],
+                &[("back", "{c, a}")]
+            )
+            .as_deref(),
+            Some("a -> b -> a")
+        );
+        assert_eq!(
+            stream_loop(&[("a", "{b, c}"), ("b", "c"), ("c", "log")], &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_steps_feeding_into_themselves() {
+        assert_eq!(
+            order("a->b->a", &[]),
+            Err("\"a\" feeds into itself".to_owned())
+        );
+        assert_eq!(
+            order("a->b->{c->b, d}", &[]),
+            Err("\"b\" feeds into itself".to_owned())
+        );
+        assert_eq!(
+            order("a->@back", &[("back", "b->a")]),
+            Err("\"a\" feeds into itself".to_owned())
+        );
+    }
+}
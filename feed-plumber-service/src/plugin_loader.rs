@@ -1,25 +1,47 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
 use anyhow::Context;
 use log::{debug, error, info, warn};
 use tap::{Tap, TapFallible, TapOptional};
 
-use sys_feed_plumber_plugin::{InitializationFunction, INITIALIZATION_FUNCTION_NAME};
+use sys_feed_plumber_plugin::{
+    InitializationFunction, ABI_VERSION, ABI_VERSION_SYMBOL_NAME, INITIALIZATION_FUNCTION_NAME,
+};
 
-use crate::sys::{Plugin, PluginProcessorInstance, PluginSinkInstance, PluginSourceInstance};
+use crate::{
+    builtin,
+    config::WasmLimits,
+    sys::{
+        Plugin, PluginEventSourceInstance, PluginProcessorInstance, PluginSinkInstance,
+        PluginSourceInstance, PluginStreamProcessorInstance,
+    },
+    wasm::WasmPlugin,
+};
+
+const WASM_EXTENSION: &str = "wasm";
+
+#[cfg(unix)]
+use crate::isolation::{ComponentKind, RemoteComponent};
 
 pub struct PluginManager {
     plugins: Vec<Plugin>,
+    wasm_plugins: Vec<WasmPlugin>,
+    /// How long isolated components get to answer a call.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    isolation_timeout: Duration,
 }
 
 impl PluginManager {
     pub fn load(
         plugin_path: impl AsRef<Path>,
         additional: Vec<impl AsRef<Path>>,
+        wasm_limits: &WasmLimits,
+        isolation_timeout: Duration,
     ) -> anyhow::Result<Self> {
         info!("Loading plugins from {:?}", plugin_path.as_ref());
         let dir = std::fs::read_dir(plugin_path).context("Unable to read plugin directory.")?;
         let mut plugins = Vec::new();
+        let mut wasm_plugins = Vec::new();
         for item in dir
             .filter_map(|res| {
                 res.tap_err(|e| warn!("Unable to read directory entry, skipping. Details: {}", e))
@@ -56,38 +78,57 @@ impl PluginManager {
             })
             .chain(additional.into_iter().map(|a| a.as_ref().to_path_buf()))
         {
-            let item_str = item.file_name().unwrap().to_string_lossy();
-            info!("Attempting to load plugin from {item_str}");
-            let plugin = unsafe {
-                let initializer = libloading::Library::new(&item)
-                    .tap_err(|err| warn!("Unable to load library {item_str}. Error: {err}"))
-                    .and_then(|library| {
-                        let library = Box::leak(Box::new(library));
-                        library.get::<InitializationFunction>(INITIALIZATION_FUNCTION_NAME.as_bytes())
-                    })
-                    .tap_err(|err| warn!("Unable to find plugin initializer function in {item_str}. Error: {err}"));
-                if let Ok(initializer) = initializer {
-                    let raw_plugin = initializer();
-                    Some(Plugin::from_raw(raw_plugin))
-                } else {
-                    None
-                }
-            };
-            plugins.extend(plugin);
+            if item.extension().is_some_and(|ext| ext == WASM_EXTENSION) {
+                wasm_plugins.extend(load_wasm_plugin(&item, wasm_limits));
+            } else {
+                plugins.extend(load_plugin(&item));
+            }
         }
-        Ok(Self { plugins })
+        Ok(Self {
+            plugins,
+            wasm_plugins,
+            isolation_timeout,
+        })
     }
 
+    #[cfg_attr(not(unix), allow(unused_variables))]
     pub fn instantiate_source(
         &self,
         r#type: &str,
         name: String,
         config: &str,
+        isolated: bool,
     ) -> Option<Result<PluginSourceInstance, String>> {
+        if let Some(res) = builtin::instantiate_source(r#type, &name, config) {
+            return Some(res);
+        }
+        // WebAssembly plugins are sandboxed already, so they are never isolated
+        if let Some(plugin) = self
+            .wasm_plugins
+            .iter()
+            .find(|plugin| plugin.supplies_source(r#type))
+        {
+            return Some(plugin.instantiate_source(r#type, name, config));
+        }
         let plugin = self
             .plugins
             .iter()
             .find(|plugin| plugin.supplies_source(r#type))?;
+        // Only ever set on Unix, as the config is rejected otherwise
+        #[cfg(unix)]
+        if isolated {
+            return Some(
+                RemoteComponent::spawn(
+                    ComponentKind::Source,
+                    plugin.path(),
+                    r#type,
+                    &name,
+                    config,
+                    self.isolation_timeout,
+                )
+                .map(|remote| PluginSourceInstance::new(name, Box::new(remote))),
+            );
+        }
         plugin
             .instantiate_source(r#type, name, config)
             .tap_none(|| {
@@ -98,16 +139,43 @@ impl PluginManager {
             })
     }
 
+    #[cfg_attr(not(unix), allow(unused_variables))]
     pub fn instantiate_sink(
         &self,
         r#type: &str,
         name: String,
         config: &str,
+        isolated: bool,
     ) -> Option<Result<PluginSinkInstance, String>> {
+        if let Some(res) = builtin::instantiate_sink(r#type, &name, config) {
+            return Some(res);
+        }
+        // WebAssembly plugins are sandboxed already, so they are never isolated
+        if let Some(plugin) = self
+            .wasm_plugins
+            .iter()
+            .find(|plugin| plugin.supplies_sink(r#type))
+        {
+            return Some(plugin.instantiate_sink(r#type, name, config));
+        }
         let plugin = self
             .plugins
             .iter()
             .find(|plugin| plugin.supplies_sink(r#type))?;
+        #[cfg(unix)]
+        if isolated {
+            return Some(
+                RemoteComponent::spawn(
+                    ComponentKind::Sink,
+                    plugin.path(),
+                    r#type,
+                    &name,
+                    config,
+                    self.isolation_timeout,
+                )
+                .map(|remote| PluginSinkInstance::new(name, Box::new(remote))),
+            );
+        }
         plugin.instantiate_sink(r#type, name, config).tap_none(|| {
             error!(
                 "Plugin declared sink \"{}\" was available but was not able to instantiate.",
@@ -116,16 +184,43 @@ impl PluginManager {
         })
     }
 
+    #[cfg_attr(not(unix), allow(unused_variables))]
     pub fn instantiate_processor(
         &self,
         r#type: &str,
         name: String,
         config: &str,
+        isolated: bool,
     ) -> Option<Result<PluginProcessorInstance, String>> {
+        if let Some(res) = builtin::instantiate_processor(r#type, &name, config) {
+            return Some(res);
+        }
+        // WebAssembly plugins are sandboxed already, so they are never isolated
+        if let Some(plugin) = self
+            .wasm_plugins
+            .iter()
+            .find(|plugin| plugin.supplies_processor(r#type))
+        {
+            return Some(plugin.instantiate_processor(r#type, name, config));
+        }
         let plugin = self
             .plugins
             .iter()
             .find(|plugin| plugin.supplies_processor(r#type))?;
+        #[cfg(unix)]
+        if isolated {
+            return Some(
+                RemoteComponent::spawn(
+                    ComponentKind::Processor,
+                    plugin.path(),
+                    r#type,
+                    &name,
+                    config,
+                    self.isolation_timeout,
+                )
+                .map(|remote| PluginProcessorInstance::new(name, Box::new(remote))),
+            );
+        }
         plugin
             .instantiate_processor(r#type, name, config)
             .tap_none(|| {
@@ -136,15 +231,114 @@ impl PluginManager {
             })
     }
 
+    /// Stream processors are only supplied by native plugins, and always run in-process.
+    pub fn instantiate_stream_processor(
+        &self,
+        r#type: &str,
+        name: String,
+        config: &str,
+    ) -> Option<Result<PluginStreamProcessorInstance, String>> {
+        self.plugins
+            .iter()
+            .find(|plugin| plugin.supplies_stream_processor(r#type))?
+            .instantiate_stream_processor(r#type, name, config)
+    }
+
+    /// Event sources are built in or supplied by native plugins, and always run in-process.
+    pub fn instantiate_event_source(
+        &self,
+        r#type: &str,
+        name: String,
+        config: &str,
+    ) -> Option<Result<PluginEventSourceInstance, String>> {
+        if let Some(res) = builtin::instantiate_event_source(r#type, &name, config) {
+            return Some(res);
+        }
+        self.plugins
+            .iter()
+            .find(|plugin| plugin.supplies_event_source(r#type))?
+            .instantiate_event_source(r#type, name, config)
+    }
+
+    pub fn event_source_available(&self, r#type: &str) -> bool {
+        builtin::supplies_event_source(r#type)
+            || self.plugins.iter().any(|a| a.supplies_event_source(r#type))
+    }
+
     pub fn source_available(&self, r#type: &str) -> bool {
-        self.plugins.iter().any(|a| a.supplies_source(r#type))
+        builtin::supplies_source(r#type)
+            || self.plugins.iter().any(|a| a.supplies_source(r#type))
+            || self.wasm_plugins.iter().any(|a| a.supplies_source(r#type))
     }
 
     pub fn sink_available(&self, r#type: &str) -> bool {
-        self.plugins.iter().any(|a| a.supplies_sink(r#type))
+        builtin::supplies_sink(r#type)
+            || self.plugins.iter().any(|a| a.supplies_sink(r#type))
+            || self.wasm_plugins.iter().any(|a| a.supplies_sink(r#type))
     }
 
     pub fn processor_available(&self, r#type: &str) -> bool {
-        self.plugins.iter().any(|a| a.supplies_processor(r#type))
+        builtin::supplies_processor(r#type)
+            || self.plugins.iter().any(|a| a.supplies_processor(r#type))
+            || self
+                .wasm_plugins
+                .iter()
+                .any(|a| a.supplies_processor(r#type))
+    }
+
+    pub fn stream_processor_available(&self, r#type: &str) -> bool {
+        self.plugins
+            .iter()
+            .any(|a| a.supplies_stream_processor(r#type))
+    }
+}
+
+/// Loads a single plugin library. Failures are logged and yield `None`.
+pub fn load_plugin(item: &Path) -> Option<Plugin> {
+    let item_str = item
+        .file_name()
+        .unwrap_or(item.as_os_str())
+        .to_string_lossy();
+    info!("Attempting to load plugin from {item_str}");
+    unsafe {
+        let library = libloading::Library::new(item)
+            .tap_err(|err| warn!("Unable to load library {item_str}. Error: {err}"))
+            .ok()?;
+        // The structs of a plugin built against another ABI would be misread
+        let version = library
+            .get::<*const u32>(ABI_VERSION_SYMBOL_NAME.as_bytes())
+            .map(|version| **version);
+        match version {
+            Ok(ABI_VERSION) => {}
+            Ok(version) => {
+                warn!("Plugin {item_str} was built for ABI version {version}, but this service has version {ABI_VERSION}. Skipping it, it needs to be rebuilt.");
+                return None;
+            }
+            Err(_) => {
+                warn!("Plugin {item_str} does not export its ABI version, so it was built for an older service. Skipping it, it needs to be rebuilt.");
+                return None;
+            }
+        }
+        let library = Box::leak(Box::new(library));
+        let initializer = library
+            .get::<InitializationFunction>(INITIALIZATION_FUNCTION_NAME.as_bytes())
+            .tap_err(|err| {
+                warn!("Unable to find plugin initializer function in {item_str}. Error: {err}")
+            })
+            .ok()?;
+        let raw_plugin = initializer();
+        Some(Plugin::from_raw(raw_plugin, item.to_path_buf()))
     }
 }
+
+/// Loads a single plugin compiled to WebAssembly. Failures are logged and yield `None`.
+fn load_wasm_plugin(item: &Path, limits: &WasmLimits) -> Option<WasmPlugin> {
+    let item_str = item
+        .file_name()
+        .unwrap_or(item.as_os_str())
+        .to_string_lossy();
+    info!("Attempting to load WebAssembly plugin from {item_str}");
+    WasmPlugin::load(item, limits)
+        .tap_err(|err| warn!("Unable to load WebAssembly plugin {item_str}. Error: {err:#}"))
+        .ok()
+}
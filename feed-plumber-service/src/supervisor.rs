@@ -0,0 +1,351 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_RESTART_DELAY: u64 = 1000;
+const DEFAULT_MAX_RESTART_DELAY: u64 = 300_000;
+const DEFAULT_RESTART_WINDOW: u64 = 3600;
+
+/// How a component is brought back after a fatal error.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RestartStrategy {
+    /// The component stays down. (Default)
+    #[default]
+    Never,
+    /// The component is restarted after a fixed `delay`.
+    Always,
+    /// The component is restarted after `delay`, doubling on each consecutive failure up to
+    /// `max_delay`.
+    Backoff,
+}
+
+/// Per-component restart policy, configured as e.g.
+/// `restart = { policy = "backoff", delay = 1000, max_restarts = 5, window = 3600 }`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RestartPolicy {
+    #[serde(default)]
+    pub policy: RestartStrategy,
+    /// Delay before restarting, in milliseconds.
+    #[serde(default = "default_restart_delay")]
+    pub delay: u64,
+    /// Upper bound of the backoff delay, in milliseconds.
+    #[serde(default = "default_max_restart_delay")]
+    pub max_delay: u64,
+    /// Give up once this many restarts happened within `window`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_restarts: Option<usize>,
+    /// Window for `max_restarts`, in seconds.
+    #[serde(default = "default_restart_window")]
+    pub window: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            policy: RestartStrategy::default(),
+            delay: DEFAULT_RESTART_DELAY,
+            max_delay: DEFAULT_MAX_RESTART_DELAY,
+            max_restarts: None,
+            window: DEFAULT_RESTART_WINDOW,
+        }
+    }
+}
+
+#[inline]
+const fn default_restart_delay() -> u64 {
+    DEFAULT_RESTART_DELAY
+}
+
+#[inline]
+const fn default_max_restart_delay() -> u64 {
+    DEFAULT_MAX_RESTART_DELAY
+}
+
+#[inline]
+const fn default_restart_window() -> u64 {
+    DEFAULT_RESTART_WINDOW
+}
+
+const HEALTH_RUNNING: u8 = 0;
+const HEALTH_RESTARTING: u8 = 1;
+const HEALTH_STOPPED: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+/// Shared view of whether a component is currently able to take items.
+#[derive(Debug, Clone, Default)]
+pub struct Health(Arc<AtomicU8>);
+
+impl Health {
+    pub fn state(&self) -> HealthState {
+        match self.0.load(Ordering::Acquire) {
+            HEALTH_RUNNING => HealthState::Running,
+            HEALTH_RESTARTING => HealthState::Restarting,
+            _ => HealthState::Stopped,
+        }
+    }
+
+    fn set(&self, state: HealthState) {
+        let state = match state {
+            HealthState::Running => HEALTH_RUNNING,
+            HealthState::Restarting => HEALTH_RESTARTING,
+            HealthState::Stopped => HEALTH_STOPPED,
+        };
+        self.0.store(state, Ordering::Release);
+    }
+}
+
+/// Why a single run of a component ended.
+pub enum Exit {
+    /// Nothing left to do (e.g. all senders hung up). Never restarted.
+    Finished,
+    /// The component failed to instantiate or hit a fatal error.
+    Failed,
+}
+
+pub struct Supervisor<'a> {
+    kind: &'static str,
+    name: &'a str,
+    policy: &'a RestartPolicy,
+    health: Health,
+    restarts: VecDeque<Instant>,
+    consecutive: u32,
+}
+
+impl<'a> Supervisor<'a> {
+    pub fn new(
+        kind: &'static str,
+        name: &'a str,
+        policy: &'a RestartPolicy,
+        health: Health,
+    ) -> Self {
+        Self {
+            kind,
+            name,
+            policy,
+            health,
+            restarts: VecDeque::new(),
+            consecutive: 0,
+        }
+    }
+
+    /// Runs the component until it finishes or the policy gives up on it. `wait` is called with
+    /// the restart delay instead of sleeping, so components can keep draining their inputs.
+    pub fn supervise(&mut self, mut run: impl FnMut() -> Exit, mut wait: impl FnMut(Duration)) {
+        loop {
+            self.health.set(HealthState::Running);
+            let started = Instant::now();
+            let exit = run();
+            if matches!(exit, Exit::Finished) {
+                self.health.set(HealthState::Stopped);
+                return;
+            }
+            // A component that stayed up for longer than the maximum delay is not crash looping.
+            if started.elapsed() >= Duration::from_millis(self.policy.max_delay) {
+                self.consecutive = 0;
+            }
+            let Some(delay) = self.next_delay() else {
+                self.health.set(HealthState::Stopped);
+                return;
+            };
+            self.health.set(HealthState::Restarting);
+            info!(
+                "Restarting {} \"{}\" in {} ms.",
+                self.kind,
+                self.name,
+                delay.as_millis()
+            );
+            wait(delay);
+        }
+    }
+
+    fn next_delay(&mut self) -> Option<Duration> {
+        match self.policy.policy {
+            RestartStrategy::Never => {
+                error!(
+                    "{} \"{}\" has failed and has no restart policy. Disabling.",
+                    capitalize(self.kind),
+                    self.name
+                );
+                return None;
+            }
+            RestartStrategy::Always | RestartStrategy::Backoff => {}
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(self.policy.window);
+        while self
+            .restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > window)
+        {
+            self.restarts.pop_front();
+        }
+        if let Some(max) = self.policy.max_restarts {
+            if self.restarts.len() >= max {
+                error!(
+                    "{} \"{}\" has restarted {} times in the last {} s. Disabling.",
+                    capitalize(self.kind),
+                    self.name,
+                    self.restarts.len(),
+                    self.policy.window
+                );
+                return None;
+            }
+        }
+        self.restarts.push_back(now);
+        let delay = match self.policy.policy {
+            RestartStrategy::Backoff => self
+                .policy
+                .delay
+                .saturating_mul(1u64.checked_shl(self.consecutive).unwrap_or(u64::MAX))
+                .min(self.policy.max_delay),
+            _ => self.policy.delay,
+        };
+        self.consecutive = self.consecutive.saturating_add(1);
+        if self.consecutive > 1 {
+            warn!(
+                "{} \"{}\" has failed {} times in a row.",
+                capitalize(self.kind),
+                self.name,
+                self.consecutive
+            );
+        }
+        Some(Duration::from_millis(delay))
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn policy(policy: RestartStrategy, delay: u64, max_delay: u64) -> RestartPolicy {
+        RestartPolicy {
+            policy,
+            delay,
+            max_delay,
+            ..Default::default()
+        }
+    }
+
+    /// Supervises a component failing `failures` times before finishing, sleeping for
+    /// `run_for(n)` on the n-th run. Returns the number of runs, the delays waited for and the
+    /// final health.
+    fn supervise(
+        policy: &RestartPolicy,
+        failures: usize,
+        run_for: impl Fn(usize) -> Duration,
+        wait_for: Duration,
+    ) -> (usize, Vec<u64>, HealthState) {
+        let health = Health::default();
+        let mut runs = 0;
+        let mut delays = Vec::new();
+        Supervisor::new("source", "test", policy, health.clone()).supervise(
+            || {
+                assert_eq!(health.state(), HealthState::Running);
+                thread::sleep(run_for(runs));
+                runs += 1;
+                if runs > failures {
+                    Exit::Finished
+                } else {
+                    Exit::Failed
+                }
+            },
+            |delay| {
+                assert_eq!(health.state(), HealthState::Restarting);
+                delays.push(delay.as_millis() as u64);
+                thread::sleep(wait_for);
+            },
+        );
+        (runs, delays, health.state())
+    }
+
+    fn instantly(_: usize) -> Duration {
+        Duration::ZERO
+    }
+
+    #[test]
+    fn never_restarts_by_default() {
+        let policy = RestartPolicy::default();
+        let (runs, delays, state) = supervise(&policy, 3, instantly, Duration::ZERO);
+        assert_eq!((runs, delays, state), (1, vec![], HealthState::Stopped));
+    }
+
+    #[test]
+    fn never_restarts_finished_components() {
+        let policy = policy(RestartStrategy::Always, 10, 100);
+        let (runs, delays, state) = supervise(&policy, 0, instantly, Duration::ZERO);
+        assert_eq!((runs, delays, state), (1, vec![], HealthState::Stopped));
+    }
+
+    #[test]
+    fn always_restarts_after_the_same_delay() {
+        let policy = policy(RestartStrategy::Always, 10, 100);
+        let (runs, delays, state) = supervise(&policy, 4, instantly, Duration::ZERO);
+        assert_eq!((runs, state), (5, HealthState::Stopped));
+        assert_eq!(delays, [10; 4]);
+    }
+
+    #[test]
+    fn backs_off_up_to_the_max_delay() {
+        let policy = policy(RestartStrategy::Backoff, 100, 1000);
+        let (runs, delays, _) = supervise(&policy, 6, instantly, Duration::ZERO);
+        assert_eq!(runs, 7);
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn backs_off_from_the_start_after_a_long_run() {
+        let policy = policy(RestartStrategy::Backoff, 1, 5);
+        let run_for = |run| Duration::from_millis(if run == 2 { 10 } else { 0 });
+        let (_, delays, _) = supervise(&policy, 4, run_for, Duration::ZERO);
+        assert_eq!(delays, [1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn gives_up_after_max_restarts_within_the_window() {
+        let policy = RestartPolicy {
+            max_restarts: Some(2),
+            ..policy(RestartStrategy::Always, 10, 100)
+        };
+        let (runs, delays, state) = supervise(&policy, 5, instantly, Duration::ZERO);
+        assert_eq!(
+            (runs, delays, state),
+            (3, vec![10, 10], HealthState::Stopped)
+        );
+    }
+
+    #[test]
+    fn forgets_restarts_outside_the_window() {
+        let policy = RestartPolicy {
+            max_restarts: Some(1),
+            window: 0,
+            ..policy(RestartStrategy::Always, 10, 100)
+        };
+        let (runs, delays, _) = supervise(&policy, 3, instantly, Duration::from_millis(5));
+        assert_eq!((runs, delays), (4, vec![10; 3]));
+    }
+}
@@ -3,23 +3,30 @@ use std::{
     collections::HashMap,
     ffi::{c_void, CStr, CString},
     fmt::{Debug, Formatter},
+    path::{Path, PathBuf},
     ptr::NonNull,
     slice::from_raw_parts,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
 };
 
 use log::{debug, warn};
 use tap::TapFallible;
 
 use sys_feed_plumber_plugin::{
-    FeedPlumberPlugin, FeedPlumberProcessorMeta, FeedPlumberSinkMeta, FeedPlumberSourceMeta, Item,
-    Items as ItemsRaw, KeyValuePair,
+    Emitter as EmitterRaw, FeedPlumberEventSourceMeta, FeedPlumberPlugin, FeedPlumberProcessorMeta,
+    FeedPlumberSinkMeta, FeedPlumberSourceMeta, FeedPlumberStreamProcessorMeta, Item,
+    Items as ItemsRaw, KeyValuePair, ERROR_KEY_FATAL, ERROR_KEY_PANIC, ERROR_KEY_WARN,
 };
 
 #[allow(dead_code)]
 pub struct Plugin {
+    path: PathBuf,
     sources: HashMap<String, PluginSourceMeta>,
     sinks: HashMap<String, PluginSinkMeta>,
     processors: HashMap<String, PluginProcessorMeta>,
+    stream_processors: HashMap<String, PluginStreamProcessorMeta>,
+    event_sources: HashMap<String, PluginEventSourceMeta>,
     inner: FeedPlumberPlugin,
 }
 
@@ -35,13 +42,20 @@ impl Debug for Plugin {
 }
 
 impl Plugin {
-    pub fn from_raw(raw: FeedPlumberPlugin) -> Self {
+    pub fn from_raw(raw: FeedPlumberPlugin, path: PathBuf) -> Self {
         Self {
+            path,
             sources: Self::sources(&raw).map(|a| (a.name.clone(), a)).collect(),
             sinks: Self::sinks(&raw).map(|a| (a.name.clone(), a)).collect(),
             processors: Self::processors(&raw)
                 .map(|a| (a.name.clone(), a))
                 .collect(),
+            stream_processors: Self::stream_processors(&raw)
+                .map(|a| (a.name.clone(), a))
+                .collect(),
+            event_sources: Self::event_sources(&raw)
+                .map(|a| (a.name.clone(), a))
+                .collect(),
             inner: raw,
         }
     }
@@ -79,6 +93,33 @@ impl Plugin {
             .map(|a| a.instantiate_new(name, config))
     }
 
+    pub fn instantiate_stream_processor(
+        &self,
+        r#type: &str,
+        name: String,
+        config: &str,
+    ) -> Option<Result<PluginStreamProcessorInstance, String>> {
+        self.stream_processors
+            .get(r#type)
+            .map(|a| a.instantiate_new(name, config))
+    }
+
+    pub fn instantiate_event_source(
+        &self,
+        r#type: &str,
+        name: String,
+        config: &str,
+    ) -> Option<Result<PluginEventSourceInstance, String>> {
+        self.event_sources
+            .get(r#type)
+            .map(|a| a.instantiate_new(name, config))
+    }
+
+    /// The library this plugin was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn supplies_source(&self, src: &str) -> bool {
         self.sources.contains_key(src)
     }
@@ -91,6 +132,14 @@ impl Plugin {
         self.processors.contains_key(processor)
     }
 
+    pub fn supplies_stream_processor(&self, processor: &str) -> bool {
+        self.stream_processors.contains_key(processor)
+    }
+
+    pub fn supplies_event_source(&self, src: &str) -> bool {
+        self.event_sources.contains_key(src)
+    }
+
     fn sources(plugin: &FeedPlumberPlugin) -> impl Iterator<Item = PluginSourceMeta> {
         // Safety: We are relying on FFI to be good, but sources_len corresponds to sources
         let sources_slice = unsafe { slice_from_ptr(plugin.sources, plugin.sources_len) };
@@ -146,6 +195,48 @@ impl Plugin {
                 inner: *inner,
             })
     }
+
+    fn stream_processors(
+        plugin: &FeedPlumberPlugin,
+    ) -> impl Iterator<Item = PluginStreamProcessorMeta> {
+        // Safety: We are relying on FFI to be good, but stream_processors_len corresponds to
+        // stream_processors
+        let stream_processors_slice =
+            unsafe { slice_from_ptr(plugin.stream_processors, plugin.stream_processors_len) };
+        stream_processors_slice
+            .iter()
+            .filter_map(|a| {
+                a.name
+                    .as_cstr()
+                    .to_str()
+                    .ok()
+                    .map(|name| (a, name.to_owned()))
+            })
+            .map(|(inner, name)| PluginStreamProcessorMeta {
+                name,
+                inner: *inner,
+            })
+    }
+
+    fn event_sources(plugin: &FeedPlumberPlugin) -> impl Iterator<Item = PluginEventSourceMeta> {
+        // Safety: We are relying on FFI to be good, but event_sources_len corresponds to
+        // event_sources
+        let event_sources_slice =
+            unsafe { slice_from_ptr(plugin.event_sources, plugin.event_sources_len) };
+        event_sources_slice
+            .iter()
+            .filter_map(|a| {
+                a.name
+                    .as_cstr()
+                    .to_str()
+                    .ok()
+                    .map(|name| (a, name.to_owned()))
+            })
+            .map(|(inner, name)| PluginEventSourceMeta {
+                name,
+                inner: *inner,
+            })
+    }
 }
 
 unsafe fn slice_from_ptr<'a, T>(ptr: *const T, mut len: usize) -> &'a [T] {
@@ -163,18 +254,22 @@ unsafe fn slice_from_ptr<'a, T>(ptr: *const T, mut len: usize) -> &'a [T] {
 pub enum FeedPlumberComponentError {
     Warn(String),
     Fatal(String),
+    /// The plugin panicked. Treated like [`FeedPlumberComponentError::Fatal`].
+    Panic(String),
 }
 
-fn items_with_error_to_result(mut items: Items) -> Result<Items, FeedPlumberComponentError> {
+pub fn items_with_error_to_result(mut items: Items) -> Result<Items, FeedPlumberComponentError> {
     match items
         .0
         .get_mut(0)
         .and_then(|a| a.get_mut(0))
         .and_then(|(key, value)| {
-            if key == "FEED_PLUMBER_FATAL" {
+            if key == ERROR_KEY_FATAL {
                 Some(FeedPlumberComponentError::Fatal(std::mem::take(value)))
-            } else if key == "FEED_PLUMBER_WARN" {
+            } else if key == ERROR_KEY_WARN {
                 Some(FeedPlumberComponentError::Warn(std::mem::take(value)))
+            } else if key == ERROR_KEY_PANIC {
+                Some(FeedPlumberComponentError::Panic(std::mem::take(value)))
             } else {
                 None
             }
@@ -185,28 +280,156 @@ fn items_with_error_to_result(mut items: Items) -> Result<Items, FeedPlumberComp
 }
 
 macro_rules! plugin_component_instantiation {
-    ($typ:tt,$comp_name:ident,$config:ident,$inner:expr => $human_name:literal) => {{
+    ($typ:tt,$native:tt,$comp_name:ident,$config:ident,$inner:expr => $human_name:literal) => {{
         let config = CString::new($config).unwrap();
         // Safety: FFI
         let res = unsafe { ($inner.create)(config.as_ptr()) };
         drop(config); // Ensures config lives at least as long as FFI
         if !res.handle.is_null() {
-            Ok($typ {
-                name: $comp_name,
-                handle: res.handle,
-                meta: $inner,
-            })
+            Ok($typ::new(
+                $comp_name,
+                Box::new($native {
+                    handle: res.handle,
+                    meta: $inner,
+                }),
+            ))
         } else {
-            if !res.message.is_null() {
+            let message = if !res.message.is_null() {
                 let cstr = unsafe { CStr::from_ptr(res.message) };
-                Err(cstr.to_string_lossy().into_owned())
+                let message = cstr.to_string_lossy().into_owned();
+                // Safety: FFI
+                unsafe { (res.destroy_message)(res.message) };
+                message
+            } else {
+                concat!("Unknown error during ", $human_name, " creation").to_owned()
+            };
+            if res.panicked {
+                Err(format!("Plugin panicked: {message}"))
             } else {
-                Err(concat!("Unknown error during ", $human_name, " creation").to_owned())
+                Err(message)
             }
         }
     }};
 }
 
+/// A source, regardless of where it is running.
+pub trait SourceComponent {
+    fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError>;
+}
+
+/// A sink, regardless of where it is running.
+pub trait SinkComponent {
+    fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError>;
+}
+
+/// A processor, regardless of where it is running.
+pub trait ProcessorComponent {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError>;
+}
+
+/// A stream processor, regardless of where it is running.
+pub trait StreamProcessorComponent {
+    fn receive_items(
+        &mut self,
+        source: &str,
+        items: &Items,
+    ) -> Result<Items, FeedPlumberComponentError>;
+    fn flush(&mut self) -> Result<Items, FeedPlumberComponentError>;
+}
+
+/// A source that runs on its own thread and emits items as they arrive, rather than being polled.
+pub trait EventSourceComponent {
+    /// Runs until the source fails or `emitter` says to stop, passing what arrives to it.
+    fn run(&mut self, emitter: &Emitter) -> Result<(), FeedPlumberComponentError>;
+}
+
+/// Signalled once when the service shuts down, so components can stop what they are doing.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+    pub fn signal(&self) {
+        let (signalled, condvar) = &*self.0;
+        *signalled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_signalled(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    /// Waits up to `timeout` for the signal, returning whether it came.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (signalled, condvar) = &*self.0;
+        let guard = signalled.lock().unwrap();
+        *condvar
+            .wait_timeout_while(guard, timeout, |signalled| !*signalled)
+            .unwrap()
+            .0
+    }
+
+    pub fn wait(&self) {
+        let (signalled, condvar) = &*self.0;
+        let guard = signalled.lock().unwrap();
+        drop(condvar.wait_while(guard, |signalled| !*signalled).unwrap());
+    }
+}
+
+/// What a running event source emits its items through.
+pub struct Emitter<'a> {
+    emit: &'a dyn Fn(Items),
+    shutdown: &'a Shutdown,
+}
+
+impl<'a> Emitter<'a> {
+    pub fn new(emit: &'a dyn Fn(Items), shutdown: &'a Shutdown) -> Self {
+        Self { emit, shutdown }
+    }
+
+    /// Sends items into the source's pipelines. Returns false once the service is shutting down,
+    /// in which case the items are dropped.
+    pub fn emit(&self, items: Items) -> bool {
+        if self.stopping() {
+            return false;
+        }
+        if !items.is_empty() {
+            (self.emit)(items);
+        }
+        true
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.shutdown.is_signalled()
+    }
+
+    /// Waits up to `timeout` for the source to be asked to stop, returning whether it was.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        self.shutdown.wait_timeout(timeout)
+    }
+
+    fn as_raw(&self) -> EmitterRaw {
+        EmitterRaw {
+            context: self as *const Self as *mut c_void,
+            emit: emitter_emit,
+            wait_stop: emitter_wait_stop,
+        }
+    }
+}
+
+/// # Safety
+/// `context` must come from [`Emitter::as_raw`] of an emitter that is still alive.
+unsafe extern "C" fn emitter_emit(context: *mut c_void, items: ItemsRaw) -> bool {
+    let emitter = &*(context as *const Emitter);
+    emitter.emit(Items::from_raw(items))
+}
+
+/// # Safety
+/// `context` must come from [`Emitter::as_raw`] of an emitter that is still alive.
+unsafe extern "C" fn emitter_wait_stop(context: *mut c_void, millis: u64) -> bool {
+    let emitter = &*(context as *const Emitter);
+    emitter.wait(Duration::from_millis(millis))
+}
+
 pub struct PluginSourceMeta {
     pub name: String,
     inner: FeedPlumberSourceMeta,
@@ -218,29 +441,100 @@ impl PluginSourceMeta {
         name: String,
         config: &str,
     ) -> Result<PluginSourceInstance, String> {
-        plugin_component_instantiation!(PluginSourceInstance, name, config, self.inner => "source")
+        plugin_component_instantiation!(PluginSourceInstance, NativeSource, name, config, self.inner => "source")
     }
 }
 
-#[allow(dead_code)]
 pub struct PluginSourceInstance {
     name: String,
-    handle: *mut c_void,
-    meta: FeedPlumberSourceMeta,
+    inner: Box<dyn SourceComponent>,
 }
 
 impl PluginSourceInstance {
+    pub fn new(name: String, inner: Box<dyn SourceComponent>) -> Self {
+        Self { name, inner }
+    }
+
     #[allow(dead_code)]
     pub fn name(&self) -> &str {
         &self.name
     }
-    fn poll_source_raw(&mut self) -> ItemsRaw {
-        // Safety: FFI
-        unsafe { (self.meta.poll_source)(self.handle) }
-    }
 
     pub fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError> {
-        let items = Items::from_raw(self.poll_source_raw());
+        self.inner.poll_source()
+    }
+}
+
+pub struct PluginEventSourceMeta {
+    pub name: String,
+    inner: FeedPlumberEventSourceMeta,
+}
+
+impl PluginEventSourceMeta {
+    pub fn instantiate_new(
+        &self,
+        name: String,
+        config: &str,
+    ) -> Result<PluginEventSourceInstance, String> {
+        plugin_component_instantiation!(PluginEventSourceInstance, NativeEventSource, name, config, self.inner => "event source")
+    }
+}
+
+pub struct PluginEventSourceInstance {
+    name: String,
+    inner: Box<dyn EventSourceComponent>,
+}
+
+impl PluginEventSourceInstance {
+    pub fn new(name: String, inner: Box<dyn EventSourceComponent>) -> Self {
+        Self { name, inner }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn run(&mut self, emitter: &Emitter) -> Result<(), FeedPlumberComponentError> {
+        self.inner.run(emitter)
+    }
+}
+
+struct NativeEventSource {
+    handle: *mut c_void,
+    meta: FeedPlumberEventSourceMeta,
+}
+
+impl Drop for NativeEventSource {
+    fn drop(&mut self) {
+        // Safety: FFI, handle is not used after this
+        unsafe { (self.meta.destroy)(self.handle) };
+    }
+}
+
+impl EventSourceComponent for NativeEventSource {
+    fn run(&mut self, emitter: &Emitter) -> Result<(), FeedPlumberComponentError> {
+        // Safety: FFI, the emitter outlives the call
+        let items = Items::from_raw(unsafe { (self.meta.run)(self.handle, emitter.as_raw()) });
+        items_with_error_to_result(items).map(drop)
+    }
+}
+
+struct NativeSource {
+    handle: *mut c_void,
+    meta: FeedPlumberSourceMeta,
+}
+
+impl Drop for NativeSource {
+    fn drop(&mut self) {
+        // Safety: FFI, handle is not used after this
+        unsafe { (self.meta.destroy)(self.handle) };
+    }
+}
+
+impl SourceComponent for NativeSource {
+    fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError> {
+        // Safety: FFI
+        let items = Items::from_raw(unsafe { (self.meta.poll_source)(self.handle) });
         items_with_error_to_result(items)
     }
 }
@@ -256,29 +550,51 @@ impl PluginSinkMeta {
         name: String,
         config: &str,
     ) -> Result<PluginSinkInstance, String> {
-        plugin_component_instantiation!(PluginSinkInstance, name, config, self.inner => "sink")
+        plugin_component_instantiation!(PluginSinkInstance, NativeSink, name, config, self.inner => "sink")
     }
 }
 
 pub struct PluginSinkInstance {
     name: String,
-    handle: *mut c_void,
-    meta: FeedPlumberSinkMeta,
+    inner: Box<dyn SinkComponent>,
 }
 
 impl PluginSinkInstance {
+    pub fn new(name: String, inner: Box<dyn SinkComponent>) -> Self {
+        Self { name, inner }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
-    fn sink_items_raw(&mut self, items: ItemsRaw) {
-        // Safety: FFI
-        unsafe { (self.meta.sink_items)(self.handle, items) };
+
+    pub fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError> {
+        self.inner.sink_items(items)
+    }
+}
+
+struct NativeSink {
+    handle: *mut c_void,
+    meta: FeedPlumberSinkMeta,
+}
+
+impl Drop for NativeSink {
+    fn drop(&mut self) {
+        // Safety: FFI, handle is not used after this
+        unsafe { (self.meta.destroy)(self.handle) };
     }
+}
 
-    pub fn sink_items(&mut self, items: &Items) {
+impl SinkComponent for NativeSink {
+    fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError> {
+        let cell = OnceCell::new();
         items.with_raw(|items| {
-            self.sink_items_raw(items);
+            // Safety: FFI
+            let items = unsafe { (self.meta.sink_items)(self.handle, items) };
+            cell.set(Items::from_raw(items)).ok().unwrap();
         });
+        let items = cell.into_inner().unwrap();
+        items_with_error_to_result(items).map(drop)
     }
 }
 
@@ -293,42 +609,143 @@ impl PluginProcessorMeta {
         name: String,
         config: &str,
     ) -> Result<PluginProcessorInstance, String> {
-        plugin_component_instantiation!(PluginProcessorInstance, name, config, self.inner => "processor")
+        plugin_component_instantiation!(PluginProcessorInstance, NativeProcessor, name, config, self.inner => "processor")
     }
 }
 
 pub struct PluginProcessorInstance {
     name: String,
+    inner: Box<dyn ProcessorComponent>,
+}
+
+impl PluginProcessorInstance {
+    pub fn new(name: String, inner: Box<dyn ProcessorComponent>) -> Self {
+        Self { name, inner }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        self.inner.process_items(items)
+    }
+}
+
+struct NativeProcessor {
     handle: *mut c_void,
     meta: FeedPlumberProcessorMeta,
 }
 
-impl PluginProcessorInstance {
+impl Drop for NativeProcessor {
+    fn drop(&mut self) {
+        // Safety: FFI, handle is not used after this
+        unsafe { (self.meta.destroy)(self.handle) };
+    }
+}
+
+impl ProcessorComponent for NativeProcessor {
+    fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+        let cell = OnceCell::new();
+        items.with_raw(|items| {
+            // Safety: FFI
+            let items = unsafe { (self.meta.process_items)(self.handle, items) };
+            cell.set(Items::from_raw(items)).ok().unwrap();
+        });
+        let items = cell.into_inner().unwrap();
+        items_with_error_to_result(items)
+    }
+}
+
+pub struct PluginStreamProcessorMeta {
+    pub name: String,
+    inner: FeedPlumberStreamProcessorMeta,
+}
+
+impl PluginStreamProcessorMeta {
+    pub fn instantiate_new(
+        &self,
+        name: String,
+        config: &str,
+    ) -> Result<PluginStreamProcessorInstance, String> {
+        plugin_component_instantiation!(PluginStreamProcessorInstance, NativeStreamProcessor, name, config, self.inner => "stream processor")
+    }
+}
+
+pub struct PluginStreamProcessorInstance {
+    name: String,
+    inner: Box<dyn StreamProcessorComponent>,
+}
+
+impl PluginStreamProcessorInstance {
+    pub fn new(name: String, inner: Box<dyn StreamProcessorComponent>) -> Self {
+        Self { name, inner }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
-    fn process_items_raw(&mut self, items: ItemsRaw) -> ItemsRaw {
-        // Safety: FFI
-        unsafe { (self.meta.process_items)(self.handle, items) }
+
+    pub fn receive_items(
+        &mut self,
+        source: &str,
+        items: &Items,
+    ) -> Result<Items, FeedPlumberComponentError> {
+        self.inner.receive_items(source, items)
     }
 
-    pub fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+    pub fn flush(&mut self) -> Result<Items, FeedPlumberComponentError> {
+        self.inner.flush()
+    }
+}
+
+struct NativeStreamProcessor {
+    handle: *mut c_void,
+    meta: FeedPlumberStreamProcessorMeta,
+}
+
+impl Drop for NativeStreamProcessor {
+    fn drop(&mut self) {
+        // Safety: FFI, handle is not used after this
+        unsafe { (self.meta.destroy)(self.handle) };
+    }
+}
+
+impl StreamProcessorComponent for NativeStreamProcessor {
+    fn receive_items(
+        &mut self,
+        source: &str,
+        items: &Items,
+    ) -> Result<Items, FeedPlumberComponentError> {
+        let source = CString::new(source).unwrap_or_default();
         let cell = OnceCell::new();
         items.with_raw(|items| {
-            let items = self.process_items_raw(items);
+            // Safety: FFI
+            let items = unsafe { (self.meta.receive_items)(self.handle, source.as_ptr(), items) };
             cell.set(Items::from_raw(items)).ok().unwrap();
         });
         let items = cell.into_inner().unwrap();
         items_with_error_to_result(items)
     }
+
+    fn flush(&mut self) -> Result<Items, FeedPlumberComponentError> {
+        // Safety: FFI
+        let items = Items::from_raw(unsafe { (self.meta.flush)(self.handle) });
+        items_with_error_to_result(items)
+    }
 }
 
 #[derive(Clone)]
 pub struct Items(Vec<Vec<(String, String)>>);
 
+impl From<Vec<Vec<(String, String)>>> for Items {
+    fn from(value: Vec<Vec<(String, String)>>) -> Self {
+        Items(value)
+    }
+}
+
 impl Items {
-    #[allow(dead_code)]
-    pub fn items(&self) -> impl Iterator<Item = &Vec<(String, String)>> {
+    pub fn items(&self) -> impl ExactSizeIterator<Item = &Vec<(String, String)>> {
         self.0.iter()
     }
 
@@ -336,10 +753,28 @@ impl Items {
         Items(Vec::new())
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
+    /// Splits into batches of at most `size` items.
+    pub fn into_chunks(self, size: usize) -> Vec<Items> {
+        let mut chunks = Vec::new();
+        let mut items = self.0.into_iter().peekable();
+        while items.peek().is_some() {
+            chunks.push(Items(items.by_ref().take(size).collect()));
+        }
+        chunks
+    }
+
+    pub fn extend(&mut self, other: Items) {
+        self.0.extend(other.0);
+    }
+
     fn from_raw(raw: ItemsRaw) -> Self {
         let ret = if raw.items.is_null() || raw.len == 0 {
             return Self(Vec::new());
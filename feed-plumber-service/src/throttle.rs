@@ -0,0 +1,318 @@
+//! Rate limiting and batching of the items going into a sink, so plugins don't each have to.
+
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::sys::{Items, Shutdown};
+
+const DEFAULT_RATE_INTERVAL: u64 = 1000;
+
+/// What a rate limit counts.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateUnit {
+    /// Calls into the sink. (Default)
+    #[default]
+    Batches,
+    /// Items going into the sink.
+    Items,
+}
+
+/// Per-sink token bucket, configured as e.g. `rate_limit = { limit = 5, interval = 2000 }`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RateLimit {
+    /// How many batches or items are let through every `interval`.
+    pub limit: u32,
+    /// In milliseconds.
+    #[serde(default = "default_rate_interval")]
+    pub interval: u64,
+    /// How many may go through at once after a quiet period. Defaults to `limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub per: RateUnit,
+}
+
+#[inline]
+const fn default_rate_interval() -> u64 {
+    DEFAULT_RATE_INTERVAL
+}
+
+/// Per-sink batching, configured as e.g. `batch = { max_items = 10, max_wait = 5000 }`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BatchPolicy {
+    /// The most items given to the sink in one call. Unlimited if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    /// How long items wait for others to fill up the batch, in milliseconds.
+    #[serde(default)]
+    pub max_wait: u64,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    /// Tokens per second.
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+    per: RateUnit,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        let capacity = f64::from(limit.burst.unwrap_or(limit.limit).max(1));
+        Self {
+            capacity,
+            rate: f64::from(limit.limit)
+                / Duration::from_millis(limit.interval.max(1)).as_secs_f64(),
+            tokens: capacity,
+            updated: Instant::now(),
+            per: limit.per,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + self.rate * (now - self.updated).as_secs_f64()).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Blocks until `tokens` are available, and takes them. Returns false without taking any if
+    /// the service shuts down in the meantime.
+    fn take(&mut self, tokens: f64, shutdown: &Shutdown) -> bool {
+        self.refill();
+        if self.tokens < tokens {
+            let wait = Duration::from_secs_f64((tokens - self.tokens) / self.rate);
+            debug!("Rate limited, waiting {}ms", wait.as_millis());
+            if shutdown.wait_timeout(wait) {
+                return false;
+            }
+            self.refill();
+        }
+        self.tokens -= tokens;
+        true
+    }
+}
+
+/// Collects the items going into a sink and lets them out in batches, at the configured rate.
+pub struct Outlet {
+    bucket: Option<TokenBucket>,
+    batch: Option<BatchPolicy>,
+    /// The most items in one call, from `batch.max_items` and an item-based `rate_limit`.
+    max_items: usize,
+    pending: Items,
+    deadline: Option<Instant>,
+    shutdown: Shutdown,
+}
+
+impl Outlet {
+    pub fn new(
+        rate_limit: Option<&RateLimit>,
+        batch: Option<&BatchPolicy>,
+        shutdown: Shutdown,
+    ) -> Self {
+        // Zero limits are rejected with the config
+        let bucket = rate_limit.map(TokenBucket::new);
+        let mut max_items = batch
+            .and_then(|batch| batch.max_items)
+            .unwrap_or(usize::MAX);
+        if let Some(bucket) = bucket
+            .as_ref()
+            .filter(|bucket| bucket.per == RateUnit::Items)
+        {
+            // Larger batches could never get enough tokens at once
+            max_items = max_items.min(bucket.capacity as usize);
+        }
+        Self {
+            bucket,
+            batch: batch.cloned(),
+            max_items,
+            pending: Items::empty(),
+            deadline: None,
+            shutdown,
+        }
+    }
+
+    /// When the pending items are due, if they are waiting for more.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn max_wait(&self) -> u64 {
+        self.batch.as_ref().map_or(0, |batch| batch.max_wait)
+    }
+
+    fn overdue(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Adds items, returning whether a batch is due.
+    pub fn push(&mut self, items: Items) -> bool {
+        self.pending.extend(items);
+        // Items arriving steadily would otherwise keep the pending ones waiting past the deadline
+        if self.max_wait() == 0 || self.pending.len() >= self.max_items || self.overdue() {
+            return true;
+        }
+        let max_wait = Duration::from_millis(self.max_wait());
+        self.deadline
+            .get_or_insert_with(|| Instant::now() + max_wait);
+        false
+    }
+
+    /// Takes the pending items in batches of at most `max_items`, waiting for the rate limit before
+    /// handing out each one. Unless `flush` is set or the deadline has passed, items that don't
+    /// fill a batch keep waiting until the deadline. Batches still waiting for the rate limit when
+    /// the service shuts down are dropped.
+    pub fn drain(&mut self, flush: bool) -> impl Iterator<Item = Items> + '_ {
+        let flush = flush || self.overdue();
+        let mut batches =
+            mem::replace(&mut self.pending, Items::empty()).into_chunks(self.max_items);
+        let partial = batches
+            .last()
+            .is_some_and(|batch| batch.len() < self.max_items);
+        if !flush && self.max_wait() > 0 && partial {
+            self.pending = batches.pop().unwrap_or_else(Items::empty);
+            let max_wait = Duration::from_millis(self.max_wait());
+            self.deadline
+                .get_or_insert_with(|| Instant::now() + max_wait);
+        } else {
+            self.deadline = None;
+        }
+        let bucket = &mut self.bucket;
+        let shutdown = &self.shutdown;
+        batches.into_iter().filter(move |batch| {
+            let Some(bucket) = bucket else {
+                return true;
+            };
+            let tokens = match bucket.per {
+                RateUnit::Batches => 1.0,
+                RateUnit::Items => batch.len() as f64,
+            };
+            let taken = bucket.take(tokens, shutdown);
+            if !taken {
+                warn!(
+                    "Dropping {} items held back by a rate limit, as the service is shutting down.",
+                    batch.len()
+                );
+            }
+            taken
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn items(count: usize) -> Items {
+        Items::from(vec![vec![("key".to_owned(), "value".to_owned())]; count])
+    }
+
+    fn sizes(outlet: &mut Outlet, flush: bool) -> Vec<usize> {
+        outlet.drain(flush).map(|batch| batch.len()).collect()
+    }
+
+    fn rate_limit(limit: u32, interval: u64, burst: Option<u32>, per: RateUnit) -> RateLimit {
+        RateLimit {
+            limit,
+            interval,
+            burst,
+            per,
+        }
+    }
+
+    #[test]
+    fn refills_tokens_at_the_rate() {
+        let shutdown = Shutdown::default();
+        // 20 per second, so a token every 50 ms
+        let mut bucket = TokenBucket::new(&rate_limit(20, 1000, Some(2), RateUnit::Batches));
+        let started = Instant::now();
+        assert!(bucket.take(1.0, &shutdown));
+        assert!(bucket.take(1.0, &shutdown));
+        assert!(started.elapsed() < Duration::from_millis(40));
+        assert!(bucket.take(2.0, &shutdown));
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(95) && elapsed < Duration::from_millis(500),
+            "{elapsed:?}"
+        );
+        // Tokens build up to the burst at most
+        sleep(Duration::from_millis(200));
+        bucket.refill();
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn stops_waiting_for_tokens_on_shutdown() {
+        let shutdown = Shutdown::default();
+        let mut bucket = TokenBucket::new(&rate_limit(1, 3_600_000, None, RateUnit::Batches));
+        assert!(bucket.take(1.0, &shutdown));
+        shutdown.signal();
+        let started = Instant::now();
+        assert!(!bucket.take(1.0, &shutdown));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let mut outlet = Outlet::new(
+            Some(&rate_limit(1, 3_600_000, None, RateUnit::Batches)),
+            Some(&BatchPolicy {
+                max_items: Some(1),
+                max_wait: 0,
+            }),
+            shutdown,
+        );
+        outlet.push(items(3));
+        assert_eq!(sizes(&mut outlet, true), [1]);
+    }
+
+    #[test]
+    fn clamps_batches_to_an_item_rate_limit() {
+        let outlet = |max_items| {
+            Outlet::new(
+                Some(&rate_limit(10, 1000, Some(3), RateUnit::Items)),
+                Some(&BatchPolicy {
+                    max_items,
+                    max_wait: 0,
+                }),
+                Shutdown::default(),
+            )
+        };
+        let mut clamped = outlet(Some(10));
+        assert!(clamped.push(items(7)));
+        assert_eq!(sizes(&mut clamped, true), [3, 3, 1]);
+        let mut smaller = outlet(Some(2));
+        assert!(smaller.push(items(3)));
+        assert_eq!(sizes(&mut smaller, true), [2, 1]);
+    }
+
+    #[test]
+    fn holds_partial_batches_until_the_deadline() {
+        let mut outlet = Outlet::new(
+            None,
+            Some(&BatchPolicy {
+                max_items: Some(3),
+                max_wait: 50,
+            }),
+            Shutdown::default(),
+        );
+        assert!(!outlet.push(items(1)));
+        let deadline = outlet.deadline().unwrap();
+        assert!(outlet.push(items(3)));
+        assert_eq!(sizes(&mut outlet, false), [3]);
+        // The one left over keeps the first item's deadline
+        assert_eq!(outlet.deadline(), Some(deadline));
+        sleep(Duration::from_millis(60));
+        // A steady trickle below `max_items` doesn't hold them back past it
+        assert!(outlet.push(items(1)));
+        assert_eq!(sizes(&mut outlet, false), [2]);
+        assert_eq!(outlet.deadline(), None);
+    }
+}
@@ -0,0 +1,427 @@
+//! Hosting of plugins compiled to WebAssembly, using the ABI in
+//! [`sys_feed_plumber_plugin::wasm`].
+//!
+//! Every component gets its own instance of the module, so components of one plugin share no
+//! state and a trapping component can be restarted without affecting the others. Instances are
+//! limited in memory and, optionally, in the fuel available to each call.
+//!
+//! Support is compiled in with the `wasm` feature. Without it, `.wasm` files are skipped with a
+//! warning.
+
+#[cfg(feature = "wasm")]
+pub use host::WasmPlugin;
+
+#[cfg(not(feature = "wasm"))]
+pub use stub::WasmPlugin;
+
+#[cfg(feature = "wasm")]
+mod host {
+    use std::path::Path;
+
+    use anyhow::{anyhow, bail, Context};
+    use log::warn;
+    use sys_feed_plumber_plugin::{
+        wasm::{
+            unpack, ALLOC, CREATE, CREATED, CREATE_PANICKED, DESTROY, FREE, KIND_PROCESSOR,
+            KIND_SINK, KIND_SOURCE, MANIFEST, POLL_SOURCE, PROCESS_ITEMS, SINK_ITEMS,
+        },
+        wire::{Reader, Writer},
+    };
+    use wasmtime::{
+        Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    };
+    use wasmtime_wasi::{preview1, preview1::WasiP1Ctx, WasiCtxBuilder};
+
+    use crate::{
+        config::WasmLimits,
+        sys::{
+            items_with_error_to_result, FeedPlumberComponentError, Items, PluginProcessorInstance,
+            PluginSinkInstance, PluginSourceInstance, ProcessorComponent, SinkComponent,
+            SourceComponent,
+        },
+    };
+
+    struct State {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+    }
+
+    pub struct WasmPlugin {
+        module: Module,
+        linker: Linker<State>,
+        limits: WasmLimits,
+        sources: Vec<String>,
+        sinks: Vec<String>,
+        processors: Vec<String>,
+    }
+
+    impl WasmPlugin {
+        pub fn load(path: &Path, limits: &WasmLimits) -> anyhow::Result<Self> {
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(limits.fuel.is_some());
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, path).context("Unable to compile module")?;
+            let mut linker = Linker::new(&engine);
+            preview1::add_to_linker_sync(&mut linker, |state: &mut State| &mut state.wasi)?;
+            let mut plugin = Self {
+                module,
+                linker,
+                limits: limits.clone(),
+                sources: Vec::new(),
+                sinks: Vec::new(),
+                processors: Vec::new(),
+            };
+
+            let mut guest = Guest::instantiate(&plugin)?;
+            let manifest = guest.call_for_buffer(MANIFEST, ())?;
+            let mut reader = Reader(&manifest);
+            let mut names = || -> anyhow::Result<Vec<String>> {
+                (0..reader.u32()?)
+                    .map(|_| Ok(reader.str()?))
+                    .collect::<anyhow::Result<_>>()
+            };
+            plugin.sources = names()?;
+            plugin.sinks = names()?;
+            plugin.processors = names()?;
+            Ok(plugin)
+        }
+
+        pub fn supplies_source(&self, r#type: &str) -> bool {
+            self.sources.iter().any(|a| a == r#type)
+        }
+
+        pub fn supplies_sink(&self, r#type: &str) -> bool {
+            self.sinks.iter().any(|a| a == r#type)
+        }
+
+        pub fn supplies_processor(&self, r#type: &str) -> bool {
+            self.processors.iter().any(|a| a == r#type)
+        }
+
+        pub fn instantiate_source(
+            &self,
+            r#type: &str,
+            name: String,
+            config: &str,
+        ) -> Result<PluginSourceInstance, String> {
+            WasmComponent::create(self, KIND_SOURCE, r#type, config)
+                .map(|component| PluginSourceInstance::new(name, Box::new(component)))
+        }
+
+        pub fn instantiate_sink(
+            &self,
+            r#type: &str,
+            name: String,
+            config: &str,
+        ) -> Result<PluginSinkInstance, String> {
+            WasmComponent::create(self, KIND_SINK, r#type, config)
+                .map(|component| PluginSinkInstance::new(name, Box::new(component)))
+        }
+
+        pub fn instantiate_processor(
+            &self,
+            r#type: &str,
+            name: String,
+            config: &str,
+        ) -> Result<PluginProcessorInstance, String> {
+            WasmComponent::create(self, KIND_PROCESSOR, r#type, config)
+                .map(|component| PluginProcessorInstance::new(name, Box::new(component)))
+        }
+    }
+
+    /// One instance of a plugin module.
+    struct Guest {
+        store: Store<State>,
+        instance: Instance,
+        memory: Memory,
+        fuel: Option<u64>,
+    }
+
+    impl Guest {
+        fn instantiate(plugin: &WasmPlugin) -> anyhow::Result<Self> {
+            let state = State {
+                wasi: WasiCtxBuilder::new()
+                    .inherit_stdout()
+                    .inherit_stderr()
+                    .build_p1(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(plugin.limits.max_memory)
+                    .build(),
+            };
+            let mut store = Store::new(plugin.module.engine(), state);
+            store.limiter(|state| &mut state.limits);
+            let fuel = plugin.limits.fuel;
+            if let Some(fuel) = fuel {
+                store.set_fuel(fuel)?;
+            }
+            let instance = plugin.linker.instantiate(&mut store, &plugin.module)?;
+            // Runs static constructors of modules built as WASI reactors
+            if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+                initialize.call(&mut store, ())?;
+            }
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow!("Module does not export its memory"))?;
+            Ok(Self {
+                store,
+                instance,
+                memory,
+                fuel,
+            })
+        }
+
+        fn func<P, R>(&mut self, name: &str) -> anyhow::Result<TypedFunc<P, R>>
+        where
+            P: wasmtime::WasmParams,
+            R: wasmtime::WasmResults,
+        {
+            self.instance
+                .get_typed_func(&mut self.store, name)
+                .with_context(|| format!("Missing export {name}"))
+        }
+
+        fn call<P, R>(&mut self, name: &str, params: P) -> anyhow::Result<R>
+        where
+            P: wasmtime::WasmParams,
+            R: wasmtime::WasmResults,
+        {
+            if let Some(fuel) = self.fuel {
+                self.store.set_fuel(fuel)?;
+            }
+            self.func::<P, R>(name)?.call(&mut self.store, params)
+        }
+
+        /// Copies `bytes` into a buffer allocated in the guest.
+        fn write_buffer(&mut self, bytes: &[u8]) -> anyhow::Result<(u32, u32)> {
+            let len = bytes.len() as u32;
+            let ptr = self.call::<u32, u32>(ALLOC, len)?;
+            self.memory.write(&mut self.store, ptr as usize, bytes)?;
+            Ok((ptr, len))
+        }
+
+        /// Copies out and frees a buffer returned by the guest. The guest says how long it is, so
+        /// it is checked against the guest's memory before anything is allocated for it.
+        fn read_buffer(&mut self, packed: u64) -> anyhow::Result<Vec<u8>> {
+            let (ptr, len) = unpack(packed);
+            let end = ptr as u64 + len as u64;
+            if end > self.memory.data_size(&self.store) as u64 {
+                bail!("Buffer of {len} bytes at {ptr} is outside the module's memory");
+            }
+            let buffer = self.memory.data(&self.store)[ptr as usize..end as usize].to_vec();
+            self.call::<(u32, u32), ()>(FREE, (ptr, len))?;
+            Ok(buffer)
+        }
+
+        fn free_buffer(&mut self, (ptr, len): (u32, u32)) -> anyhow::Result<()> {
+            self.call::<(u32, u32), ()>(FREE, (ptr, len))
+        }
+
+        fn call_for_buffer<P>(&mut self, name: &str, params: P) -> anyhow::Result<Vec<u8>>
+        where
+            P: wasmtime::WasmParams,
+        {
+            let packed = self.call::<P, u64>(name, params)?;
+            self.read_buffer(packed)
+        }
+    }
+
+    /// Traps carry the guest's backtrace as context, which is too noisy to log for every failure.
+    fn describe(err: &anyhow::Error) -> String {
+        err.root_cause().to_string()
+    }
+
+    struct WasmComponent {
+        guest: Guest,
+        handle: u32,
+    }
+
+    impl WasmComponent {
+        fn create(
+            plugin: &WasmPlugin,
+            kind: u32,
+            r#type: &str,
+            config: &str,
+        ) -> Result<Self, String> {
+            let mut guest = Guest::instantiate(plugin).map_err(|err| describe(&err))?;
+            let created = (|| -> anyhow::Result<Result<u32, (u8, String)>> {
+                let type_buffer = guest.write_buffer(r#type.as_bytes())?;
+                let config_buffer = guest.write_buffer(config.as_bytes())?;
+                let packed = guest.call::<(u32, u32, u32, u32, u32), u64>(
+                    CREATE,
+                    (
+                        kind,
+                        type_buffer.0,
+                        type_buffer.1,
+                        config_buffer.0,
+                        config_buffer.1,
+                    ),
+                )?;
+                guest.free_buffer(type_buffer)?;
+                guest.free_buffer(config_buffer)?;
+                let response = guest.read_buffer(packed)?;
+                let mut reader = Reader(&response);
+                Ok(match reader.u8()? {
+                    CREATED => Ok(reader.u32()?),
+                    status => Err((status, reader.str()?)),
+                })
+            })()
+            .map_err(|err| format!("WebAssembly plugin failed: {}", describe(&err)))?;
+            match created {
+                Ok(handle) => Ok(Self { guest, handle }),
+                Err((CREATE_PANICKED, message)) => Err(format!("Plugin panicked: {message}")),
+                Err((_, message)) => Err(message),
+            }
+        }
+
+        /// Calls an export taking the handle and, optionally, items, and decodes the items it
+        /// returns. Traps, including running out of fuel or memory, are fatal: the instance may
+        /// be left in any state, so it needs to be recreated.
+        fn call_with_items(
+            &mut self,
+            name: &str,
+            items: Option<&Items>,
+        ) -> Result<Items, FeedPlumberComponentError> {
+            let handle = self.handle;
+            let guest = &mut self.guest;
+            (|| -> anyhow::Result<Items> {
+                let response = match items {
+                    Some(items) => {
+                        let mut writer = Writer::new();
+                        writer.items(items.items());
+                        let buffer = guest.write_buffer(&writer.0)?;
+                        let packed = guest
+                            .call::<(u32, u32, u32), u64>(name, (handle, buffer.0, buffer.1))?;
+                        guest.free_buffer(buffer)?;
+                        guest.read_buffer(packed)?
+                    }
+                    None => guest.call_for_buffer(name, handle)?,
+                };
+                Ok(Items::from(Reader(&response).items()?))
+            })()
+            .map_err(|err| {
+                FeedPlumberComponentError::Fatal(format!(
+                    "WebAssembly plugin trapped: {}",
+                    describe(&err)
+                ))
+            })
+        }
+    }
+
+    impl Drop for WasmComponent {
+        fn drop(&mut self) {
+            if let Err(err) = self.guest.call::<u32, ()>(DESTROY, self.handle) {
+                warn!(
+                    "Unable to destroy WebAssembly component: {}",
+                    describe(&err)
+                );
+            }
+        }
+    }
+
+    impl SourceComponent for WasmComponent {
+        fn poll_source(&mut self) -> Result<Items, FeedPlumberComponentError> {
+            items_with_error_to_result(self.call_with_items(POLL_SOURCE, None)?)
+        }
+    }
+
+    impl SinkComponent for WasmComponent {
+        fn sink_items(&mut self, items: &Items) -> Result<(), FeedPlumberComponentError> {
+            items_with_error_to_result(self.call_with_items(SINK_ITEMS, Some(items))?).map(|_| ())
+        }
+    }
+
+    impl ProcessorComponent for WasmComponent {
+        fn process_items(&mut self, items: &Items) -> Result<Items, FeedPlumberComponentError> {
+            items_with_error_to_result(self.call_with_items(PROCESS_ITEMS, Some(items))?)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{env, fs, process};
+
+        use super::*;
+
+        #[test]
+        fn rejects_buffers_outside_the_module_memory() {
+            // A manifest claiming to be 4 GiB long, in a memory of one 64 KiB page
+            let module = r#"(module
+                (memory (export "memory") 1)
+                (func (export "feed_plumber_alloc") (param i32) (result i32) i32.const 0)
+                (func (export "feed_plumber_free") (param i32 i32))
+                (func (export "feed_plumber_manifest") (result i64) i64.const 0xffffffff))"#;
+            let path = env::temp_dir().join(format!("oversized-{}.wat", process::id()));
+            fs::write(&path, module).unwrap();
+            let res = WasmPlugin::load(&path, &WasmLimits::default());
+            fs::remove_file(&path).unwrap();
+            let Err(err) = res else {
+                panic!("Loaded a plugin with an oversized manifest");
+            };
+            assert_eq!(
+                err.to_string(),
+                "Buffer of 4294967295 bytes at 0 is outside the module's memory"
+            );
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod stub {
+    use std::{convert::Infallible, path::Path};
+
+    use crate::{
+        config::WasmLimits,
+        sys::{PluginProcessorInstance, PluginSinkInstance, PluginSourceInstance},
+    };
+
+    /// Stands in for WebAssembly plugins when support is not compiled in. It cannot be
+    /// constructed.
+    pub struct WasmPlugin(Infallible);
+
+    impl WasmPlugin {
+        pub fn load(_path: &Path, _limits: &WasmLimits) -> anyhow::Result<Self> {
+            Err(anyhow::anyhow!(
+                "WebAssembly plugin support was not compiled in (the \"wasm\" feature)"
+            ))
+        }
+
+        pub fn supplies_source(&self, _type: &str) -> bool {
+            match self.0 {}
+        }
+
+        pub fn supplies_sink(&self, _type: &str) -> bool {
+            match self.0 {}
+        }
+
+        pub fn supplies_processor(&self, _type: &str) -> bool {
+            match self.0 {}
+        }
+
+        pub fn instantiate_source(
+            &self,
+            _type: &str,
+            _name: String,
+            _config: &str,
+        ) -> Result<PluginSourceInstance, String> {
+            match self.0 {}
+        }
+
+        pub fn instantiate_sink(
+            &self,
+            _type: &str,
+            _name: String,
+            _config: &str,
+        ) -> Result<PluginSinkInstance, String> {
+            match self.0 {}
+        }
+
+        pub fn instantiate_processor(
+            &self,
+            _type: &str,
+            _name: String,
+            _config: &str,
+        ) -> Result<PluginProcessorInstance, String> {
+            match self.0 {}
+        }
+    }
+}
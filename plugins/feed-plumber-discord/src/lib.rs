@@ -0,0 +1,402 @@
+use std::{thread::sleep, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use chrono::DateTime;
+use feed_plumber_plugin_rs::{feed_plumber_plugin, FeedPlumberProcessor, FeedPlumberSink};
+use reqwest::{blocking::Client, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+feed_plumber_plugin! {
+    sources:;
+    sinks: "discord-webhook" => WebhookSink;
+    processors: "discord-feed" => FeedProcessor;
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_TIMEOUT: u64 = 10_000;
+
+/// Discord's limits on embed fields, in characters.
+const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+const EMBED_FOOTER_LIMIT: usize = 2048;
+const EMBED_AUTHOR_LIMIT: usize = 256;
+const USERNAME_LIMIT: usize = 80;
+
+#[derive(Deserialize)]
+struct WebhookConfig {
+    url: String,
+    /// Replaces the webhook's name on messages that don't set their own.
+    username: Option<String>,
+    /// Replaces the webhook's avatar on messages that don't set their own.
+    avatar_url: Option<String>,
+    /// Posts into this thread of the webhook's channel, unless an item has a `thread_id`.
+    thread_id: Option<String>,
+    /// How often a rate-limited message is retried.
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+#[inline]
+const fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+#[inline]
+const fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+/// Posts each item as a message. Items either have a `json` key with the whole message payload
+/// (as made by `discord-feed`), or a `content` key with the text to post. Either way an item's
+/// `thread_id` picks the thread it goes to.
+struct WebhookSink {
+    client: Client,
+    url: Url,
+    username: Option<String>,
+    avatar_url: Option<String>,
+    thread_id: Option<String>,
+    max_retries: u32,
+}
+
+impl FeedPlumberSink for WebhookSink {
+    type ConfigType = WebhookConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url).context("`url` property invalid URL")?;
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.timeout))
+            .build()
+            .context("Creating HTTP client")?;
+        Ok(WebhookSink {
+            client,
+            url,
+            username: config.username,
+            avatar_url: config.avatar_url,
+            thread_id: config.thread_id,
+            max_retries: config.max_retries,
+        })
+    }
+
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()> {
+        let total = items.len();
+        let mut failed = Vec::new();
+        for item in items {
+            if let Err(err) = self.post(&item) {
+                failed.push(format!("{err:#}"));
+            }
+        }
+        match failed.first() {
+            None => Ok(()),
+            Some(first) => Err(anyhow!(
+                "{} of {total} messages failed, first: {first}",
+                failed.len()
+            )),
+        }
+    }
+}
+
+impl WebhookSink {
+    fn payload(&self, item: &[(&str, &str)]) -> anyhow::Result<Map<String, Value>> {
+        let mut payload = if let Some(json) = get(item, "json") {
+            match serde_json::from_str(json).context("Parsing `json`")? {
+                Value::Object(payload) => payload,
+                _ => bail!("`json` is not an object"),
+            }
+        } else if let Some(content) = get(item, "content") {
+            let mut payload = Map::new();
+            payload.insert("content".to_owned(), content.into());
+            payload
+        } else {
+            bail!("Item has neither `json` nor `content`");
+        };
+        // Only defaults, so the item's own, e.g. set by `discord-feed`, wins
+        if let Some(username) = &self.username {
+            payload
+                .entry("username")
+                .or_insert_with(|| username.as_str().into());
+        }
+        if let Some(avatar_url) = &self.avatar_url {
+            payload
+                .entry("avatar_url")
+                .or_insert_with(|| avatar_url.as_str().into());
+        }
+        Ok(payload)
+    }
+
+    fn post(&self, item: &[(&str, &str)]) -> anyhow::Result<()> {
+        let payload = self.payload(item)?;
+        let mut url = self.url.clone();
+        if let Some(thread_id) = get(item, "thread_id").or(self.thread_id.as_deref()) {
+            url.query_pairs_mut().append_pair("thread_id", thread_id);
+        }
+        let mut retries = 0;
+        loop {
+            let res = self
+                .client
+                .post(url.clone())
+                .json(&payload)
+                .send()
+                .context("Posting to webhook")?;
+            if res.status() != StatusCode::TOO_MANY_REQUESTS {
+                return match res.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(anyhow!(
+                        "Webhook returned {status}: {}",
+                        res.text().unwrap_or_default()
+                    )),
+                };
+            }
+            if retries >= self.max_retries {
+                bail!("Still rate limited after {retries} retries");
+            }
+            retries += 1;
+            sleep(retry_after(res));
+        }
+    }
+}
+
+/// How long Discord asks to wait, from the body's `retry_after` or the `Retry-After` header, in
+/// seconds.
+fn retry_after(res: reqwest::blocking::Response) -> Duration {
+    let header = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok());
+    let body = res
+        .json::<Value>()
+        .ok()
+        .and_then(|body| body.get("retry_after").and_then(Value::as_f64));
+    Duration::from_secs_f64(body.or(header).unwrap_or(1.0).clamp(0.0, 3600.0))
+}
+
+#[derive(Deserialize)]
+struct FeedConfig {
+    /// Text posted above the embed.
+    content: Option<String>,
+    /// Posts as the name of the feed an item comes from.
+    #[serde(default)]
+    replace_username: bool,
+    /// The embed's side color, as e.g. `0xdea584`.
+    color: Option<u32>,
+}
+
+/// Turns feed items into `json` message payloads with an embed, for `discord-webhook`.
+struct FeedProcessor {
+    content: Option<String>,
+    replace_username: bool,
+    color: Option<u32>,
+}
+
+impl FeedPlumberProcessor for FeedProcessor {
+    type ConfigType = FeedConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        Ok(FeedProcessor {
+            content: config.content,
+            replace_username: config.replace_username,
+            color: config.color,
+        })
+    }
+
+    fn process_items(
+        &mut self,
+        items: Vec<Vec<(&str, &str)>>,
+    ) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        Ok(items
+            .iter()
+            .map(|item| {
+                let mut pairs = vec![("json".to_owned(), self.payload(item).to_string())];
+                if let Some(thread_id) = get(item, "thread_id") {
+                    pairs.push(("thread_id".to_owned(), thread_id.to_owned()));
+                }
+                pairs
+            })
+            .collect())
+    }
+}
+
+impl FeedProcessor {
+    fn payload(&self, item: &[(&str, &str)]) -> Value {
+        let mut embed = Map::new();
+        if let Some(title) = get(item, "title") {
+            embed.insert(
+                "title".to_owned(),
+                truncate(title, EMBED_TITLE_LIMIT).into(),
+            );
+        }
+        if let Some(url) = get(item, "link").or(get(item, "source")) {
+            embed.insert("url".to_owned(), url.into());
+        }
+        if let Some(description) = get(item, "description").or(get(item, "summary")) {
+            embed.insert(
+                "description".to_owned(),
+                truncate(description, EMBED_DESCRIPTION_LIMIT).into(),
+            );
+        }
+        if let Some(author) = get(item, "author") {
+            embed.insert(
+                "author".to_owned(),
+                json!({ "name": truncate(author, EMBED_AUTHOR_LIMIT) }),
+            );
+        }
+        if let Some(feed_title) = get(item, "feed_title") {
+            embed.insert(
+                "footer".to_owned(),
+                json!({ "text": truncate(feed_title, EMBED_FOOTER_LIMIT) }),
+            );
+        }
+        if let Some(image) = get(item, "image") {
+            embed.insert("image".to_owned(), json!({ "url": image }));
+        }
+        let published = get(item, "published").and_then(|date| {
+            DateTime::parse_from_rfc3339(date)
+                .or_else(|_| DateTime::parse_from_rfc2822(date))
+                .ok()
+        });
+        if let Some(published) = published {
+            embed.insert("timestamp".to_owned(), published.to_rfc3339().into());
+        }
+        if let Some(color) = self.color {
+            embed.insert("color".to_owned(), color.into());
+        }
+
+        let mut payload = Map::new();
+        if let Some(content) = &self.content {
+            payload.insert("content".to_owned(), content.as_str().into());
+        }
+        if self.replace_username {
+            if let Some(feed_title) = get(item, "feed_title") {
+                payload.insert(
+                    "username".to_owned(),
+                    truncate(feed_title, USERNAME_LIMIT).into(),
+                );
+            }
+        }
+        payload.insert("embeds".to_owned(), json!([embed]));
+        payload.into()
+    }
+}
+
+fn get<'a>(item: &[(&str, &'a str)], key: &str) -> Option<&'a str> {
+    item.iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| *value)
+}
+
+fn truncate(value: &str, limit: usize) -> String {
+    if value.chars().count() <= limit {
+        return value.to_owned();
+    }
+    value.chars().take(limit - 1).chain(['…']).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// A stand-in for Discord answering the requests it gets, one connection each, with the
+    /// given statuses and bodies. Returns the bodies of the requests.
+    fn stub(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/webhooks/1/token",
+            listener.local_addr().unwrap()
+        );
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                reader.read_exact(&mut request).unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn sink(url: &str, max_retries: u32) -> WebhookSink {
+        let config = serde_json::from_value(json!({
+            "url": url,
+            "username": "Feeds",
+            "max_retries": max_retries,
+        }))
+        .unwrap();
+        WebhookSink::new(config).unwrap()
+    }
+
+    #[test]
+    fn retries_rate_limited_messages() {
+        let (url, stub) = stub(vec![
+            (
+                429,
+                r#"{"message": "You are being rate limited.", "retry_after": 0.2, "global": false}"#,
+            ),
+            (204, ""),
+        ]);
+        let started = Instant::now();
+        sink(&url, 3)
+            .sink_items(vec![vec![("content", "Hello")]])
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        let requests = stub.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], requests[1]);
+        let payload: Value = serde_json::from_str(&requests[1]).unwrap();
+        assert_eq!(payload, json!({ "content": "Hello", "username": "Feeds" }));
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let limited = r#"{"retry_after": 0.01}"#;
+        let (url, stub) = stub(vec![(429, limited), (429, limited)]);
+        let err = sink(&url, 1)
+            .sink_items(vec![vec![("content", "Hello")]])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Still rate limited after 1 retries"));
+        assert_eq!(stub.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn item_username_wins() {
+        let sink = sink("http://127.0.0.1/", 0);
+        let payload = sink
+            .payload(&[("json", r#"{"content": "Hi", "username": "Rust Blog"}"#)])
+            .unwrap();
+        assert_eq!(payload["username"], "Rust Blog");
+    }
+}
@@ -0,0 +1,400 @@
+//! The `imap` source emits messages arriving in a mailbox on an IMAP server.
+//!
+//! Every poll connects, fetches the messages with a UID past the highest one emitted so far, and
+//! saves that UID to `state_file`. Messages are fetched without marking them as read. Should the
+//! server renumber the mailbox (a new `UIDVALIDITY`), the source starts over as on its first poll.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    FeedPlumberSource,
+};
+use imap::{Client, Session};
+use native_tls::{Certificate, TlsConnector, TlsStream};
+use serde::{Deserialize, Serialize};
+
+use crate::{default_timeout, message::message_item, TlsConfig};
+
+const DEFAULT_MAILBOX: &str = "INBOX";
+
+#[derive(Deserialize)]
+pub struct ImapConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    host: String,
+    /// 993 with `security = "tls"`, 143 otherwise, if not set.
+    port: Option<u16>,
+    #[serde(default)]
+    security: ImapSecurity,
+    #[serde(default)]
+    tls: TlsConfig,
+    username: String,
+    password: String,
+    #[serde(default = "default_mailbox")]
+    mailbox: String,
+    /// Whether the messages already there when the source first polls are emitted.
+    #[serde(default)]
+    existing: bool,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    state_file: Option<PathBuf>,
+}
+
+#[inline]
+fn default_mailbox() -> String {
+    DEFAULT_MAILBOX.to_owned()
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ImapSecurity {
+    /// Connects with TLS right away.
+    #[default]
+    Tls,
+    /// Upgrades the connection with STARTTLS.
+    Starttls,
+    /// Logs in in plain text, e.g. to a server on the same machine.
+    None,
+}
+
+/// What is saved to the state file.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct State {
+    uid_validity: u32,
+    last_uid: u32,
+}
+
+pub struct ImapSource {
+    host: String,
+    port: u16,
+    security: ImapSecurity,
+    connector: TlsConnector,
+    username: String,
+    password: String,
+    mailbox: String,
+    existing: bool,
+    timeout: Duration,
+    state_file: PathBuf,
+    state: Option<State>,
+}
+
+impl FeedPlumberSource for ImapSource {
+    type ConfigType = ImapConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let mut connector = TlsConnector::builder();
+        connector.danger_accept_invalid_certs(config.tls.accept_invalid_certs);
+        if let Some(path) = &config.tls.ca_certificate {
+            let pem = fs::read(path)
+                .with_context(|| format!("Reading CA certificate \"{}\"", path.display()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .with_context(|| format!("Parsing CA certificate \"{}\"", path.display()))?;
+            connector.add_root_certificate(certificate);
+        }
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("imap-{}.json", config.name)));
+        Ok(ImapSource {
+            port: config.port.unwrap_or(match config.security {
+                ImapSecurity::Tls => 993,
+                _ => 143,
+            }),
+            host: config.host,
+            security: config.security,
+            connector: connector.build().context("Setting up TLS")?,
+            username: config.username,
+            password: config.password,
+            mailbox: config.mailbox,
+            existing: config.existing,
+            timeout: Duration::from_millis(config.timeout),
+            state: load_state(&state_file)?,
+            state_file,
+        })
+    }
+
+    fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Resolving \"{}\"", self.host))?
+            .next()
+            .ok_or(anyhow!("\"{}\" has no address", self.host))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)
+            .with_context(|| format!("Connecting to {}:{}", self.host, self.port))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        match self.security {
+            ImapSecurity::Tls => {
+                let stream = self
+                    .connector
+                    .connect(&self.host, stream)
+                    .context("Connecting with TLS")?;
+                let mut client = Client::new(stream);
+                client.read_greeting().context("Connecting")?;
+                self.fetch(client)
+            }
+            ImapSecurity::Starttls => {
+                let stream = self.starttls(stream)?;
+                let mut client = Client::new(stream);
+                client.greeting_read = true;
+                self.fetch(client)
+            }
+            ImapSecurity::None => {
+                let mut client = Client::new(stream);
+                client.read_greeting().context("Connecting")?;
+                self.fetch(client)
+            }
+        }
+    }
+}
+
+impl ImapSource {
+    /// Reads the greeting and upgrades the connection with STARTTLS. The `imap` crate only does so
+    /// for connections it opens itself, which could not use `tls` or `timeout`.
+    fn starttls(&self, stream: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).context("Connecting")?;
+        if !line.starts_with("* OK") {
+            bail!("Unexpected greeting: {}", line.trim_end());
+        }
+        (&stream)
+            .write_all(b"a0 STARTTLS\r\n")
+            .context("Upgrading with STARTTLS")?;
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .context("Upgrading with STARTTLS")?;
+            if read == 0 {
+                bail!("Connection closed while upgrading with STARTTLS");
+            }
+            // Untagged responses, like capabilities, may come first
+            if let Some(status) = line.strip_prefix("a0 ") {
+                if !status.starts_with("OK") {
+                    bail!("STARTTLS refused: {}", status.trim_end());
+                }
+                break;
+            }
+        }
+        // The server sends nothing more until the handshake, so nothing is left in the buffer
+        drop(reader);
+        self.connector
+            .connect(&self.host, stream)
+            .context("Upgrading with STARTTLS")
+    }
+
+    /// Logs in and fetches the new messages.
+    fn fetch<T: Read + Write>(
+        &mut self,
+        client: Client<T>,
+    ) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let mut session = client
+            .login(&self.username, &self.password)
+            .map_err(|(err, _)| err)
+            .context("Logging in")?;
+        let res = self.fetch_new(&mut session);
+        // Already done with the server either way
+        let _ = session.logout();
+        res
+    }
+
+    fn fetch_new<T: Read + Write>(
+        &mut self,
+        session: &mut Session<T>,
+    ) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let mailbox = session
+            .select(&self.mailbox)
+            .with_context(|| format!("Opening mailbox \"{}\"", self.mailbox))?;
+        let uid_validity = mailbox.uid_validity.unwrap_or_default();
+        let state = match self.state {
+            Some(state) if state.uid_validity == uid_validity => state,
+            _ if self.existing => State {
+                uid_validity,
+                last_uid: 0,
+            },
+            _ => {
+                let state = State {
+                    uid_validity,
+                    last_uid: mailbox.uid_next.unwrap_or(1).saturating_sub(1),
+                };
+                save_state(&self.state_file, &state)?;
+                self.state = Some(state);
+                return Ok(Vec::new());
+            }
+        };
+
+        // `n:*` includes the last message even if its UID is below `n`
+        let fetches = session
+            .uid_fetch(format!("{}:*", state.last_uid + 1), "(UID BODY.PEEK[])")
+            .context("Fetching messages")?;
+        let mut messages = fetches
+            .iter()
+            .filter_map(|fetch| Some((fetch.uid?, fetch.body()?)))
+            .filter(|(uid, _)| *uid > state.last_uid)
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|(uid, _)| *uid);
+        let items = messages
+            .iter()
+            .filter_map(|(_, raw)| message_item(raw))
+            .collect();
+        let last_uid = messages.last().map_or(state.last_uid, |(uid, _)| *uid);
+        let state = State {
+            uid_validity,
+            last_uid,
+        };
+        if self.state != Some(state) {
+            save_state(&self.state_file, &state)?;
+        }
+        self.state = Some(state);
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// A stand-in for an IMAP server with one mailbox, answering one connection. Messages are
+    /// given with their UIDs.
+    fn serve(listener: &TcpListener, uid_validity: u32, messages: &[(u32, String)]) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        write!(reader.get_mut(), "* OK IMAP4rev1 stub ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                return;
+            }
+            let mut words = line.split_whitespace();
+            let tag = words.next().unwrap();
+            let mut response = String::new();
+            match words.next().unwrap().to_ascii_uppercase().as_str() {
+                "LOGIN" => {}
+                "SELECT" => {
+                    let uid_next = messages.iter().map(|(uid, _)| uid + 1).max().unwrap_or(1);
+                    response = format!(
+                        "* {} EXISTS\r\n* OK [UIDVALIDITY {uid_validity}] UIDs valid\r\n\
+                         * OK [UIDNEXT {uid_next}] Predicted next UID\r\n",
+                        messages.len()
+                    );
+                }
+                "UID" => {
+                    let range = words.nth(1).unwrap();
+                    let from = range.strip_suffix(":*").unwrap().parse::<u32>().unwrap();
+                    // Like real servers, `n:*` includes the last message even below `n`
+                    let fetched = messages
+                        .iter()
+                        .enumerate()
+                        .filter(|(idx, (uid, _))| *uid >= from || idx + 1 == messages.len());
+                    for (idx, (uid, message)) in fetched {
+                        response += &format!(
+                            "* {} FETCH (UID {uid} BODY[] {{{}}}\r\n{message})\r\n",
+                            idx + 1,
+                            message.len()
+                        );
+                    }
+                }
+                "LOGOUT" => {
+                    write!(reader.get_mut(), "* BYE\r\n{tag} OK LOGOUT completed\r\n").unwrap();
+                    return;
+                }
+                command => panic!("Unexpected command {command}"),
+            }
+            write!(reader.get_mut(), "{response}{tag} OK completed\r\n").unwrap();
+        }
+    }
+
+    fn message(subject: &str) -> String {
+        format!("From: a@example.com\r\nSubject: {subject}\r\n\r\nHello\r\n")
+    }
+
+    /// Polls the source while the stub serves the messages, returning the subjects emitted.
+    fn poll(
+        source: &mut ImapSource,
+        listener: &TcpListener,
+        uid_validity: u32,
+        messages: &[(u32, &str)],
+    ) -> Vec<String> {
+        let messages = messages
+            .iter()
+            .map(|(uid, subject)| (*uid, message(subject)))
+            .collect::<Vec<_>>();
+        thread::scope(|scope| {
+            scope.spawn(|| serve(listener, uid_validity, &messages));
+            source
+                .poll_source()
+                .unwrap()
+                .into_iter()
+                .map(|item| {
+                    item.into_iter()
+                        .find(|(key, _)| key == "subject")
+                        .unwrap()
+                        .1
+                })
+                .collect()
+        })
+    }
+
+    fn source(port: u16, state_file: &std::path::Path, existing: bool) -> ImapSource {
+        let config = serde_json::json!({
+            "name": "test",
+            "host": "127.0.0.1",
+            "port": port,
+            "security": "none",
+            "username": "user",
+            "password": "secret",
+            "existing": existing,
+            "state_file": state_file,
+        });
+        ImapSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fetches_messages_past_the_last_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut source = self::source(port, &state_file, false);
+        let old = [(3, "Old"), (7, "Older")];
+        assert_eq!(poll(&mut source, &listener, 1, &old), Vec::<String>::new());
+        let new = [(3, "Old"), (7, "Older"), (9, "New"), (8, "Newer")];
+        assert_eq!(poll(&mut source, &listener, 1, &new), ["Newer", "New"]);
+        assert_eq!(poll(&mut source, &listener, 1, &new), Vec::<String>::new());
+
+        // Picking up from the saved state
+        let mut source = self::source(port, &state_file, false);
+        let newest = [(3, "Old"), (7, "Older"), (9, "New"), (10, "Newest")];
+        assert_eq!(poll(&mut source, &listener, 1, &newest), ["Newest"]);
+    }
+
+    #[test]
+    fn starts_over_when_the_mailbox_is_renumbered() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut source = self::source(port, &state_file, true);
+        let messages = [(1, "One"), (2, "Two")];
+        assert_eq!(poll(&mut source, &listener, 1, &messages), ["One", "Two"]);
+        assert_eq!(
+            poll(&mut source, &listener, 1, &messages),
+            Vec::<String>::new()
+        );
+        assert_eq!(poll(&mut source, &listener, 2, &messages), ["One", "Two"]);
+    }
+}
@@ -0,0 +1,379 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use feed_plumber_plugin_rs::{feed_plumber_plugin, FeedPlumberSink};
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart, SinglePart},
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Certificate, Tls, TlsParameters},
+    },
+    Message, SmtpTransport, Transport,
+};
+use minijinja::{context, AutoEscape, Environment};
+use serde::{Deserialize, Serialize};
+
+use crate::{imap::ImapSource, maildir::MaildirSource};
+
+mod imap;
+mod maildir;
+mod message;
+
+feed_plumber_plugin! {
+    sources: "maildir" => MaildirSource, "imap" => ImapSource;
+    sinks: "email" => EmailSink;
+}
+
+const DEFAULT_TIMEOUT: u64 = 30_000;
+const DEFAULT_ITEM_SUBJECT: &str = "{{ title }}";
+const DEFAULT_DIGEST_SUBJECT: &str = "{{ count }} new items";
+/// The key digests group items by, as emitted by the `feed` source.
+const DIGEST_GROUP_KEY: &str = "feed_title";
+
+#[derive(Deserialize)]
+struct EmailConfig {
+    host: String,
+    /// 465 with `security = "tls"`, 587 with `"starttls"` and `"opportunistic"`, 25 otherwise, if
+    /// not set.
+    port: Option<u16>,
+    #[serde(default)]
+    security: Security,
+    #[serde(default)]
+    tls: TlsConfig,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+    reply_to: Option<String>,
+    #[serde(default)]
+    mode: Mode,
+    /// A minijinja template, defaulting to the item's title or the digest's size.
+    subject: Option<String>,
+    /// The plain-text part, a minijinja template.
+    text: Option<String>,
+    /// The HTML part, a minijinja template with HTML escaping.
+    html: Option<String>,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+#[inline]
+const fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Security {
+    /// Upgrades the connection with STARTTLS, failing if the server doesn't support it.
+    #[default]
+    Starttls,
+    /// Upgrades the connection with STARTTLS if the server supports it.
+    Opportunistic,
+    /// Connects with TLS right away.
+    Tls,
+    /// Sends in plain text, e.g. to a local relay.
+    None,
+}
+
+#[derive(Deserialize, Default)]
+struct TlsConfig {
+    /// Trusts any certificate. Only for testing.
+    #[serde(default)]
+    accept_invalid_certs: bool,
+    /// A PEM file with an extra root certificate, e.g. of an internal CA.
+    ca_certificate: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    /// An email per item.
+    #[default]
+    Item,
+    /// An email per batch, with the items grouped by `feed_title`.
+    Digest,
+}
+
+/// The items of a digest from one feed.
+#[derive(Serialize)]
+struct Feed<'a> {
+    title: &'a str,
+    items: Vec<BTreeMap<&'a str, &'a str>>,
+}
+
+/// Sends items as emails, one each or a digest per batch.
+struct EmailSink {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    reply_to: Option<Mailbox>,
+    mode: Mode,
+    templates: Environment<'static>,
+    text: bool,
+    html: bool,
+}
+
+impl FeedPlumberSink for EmailSink {
+    type ConfigType = EmailConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let mut tls = TlsParameters::builder(config.host.clone())
+            .dangerous_accept_invalid_certs(config.tls.accept_invalid_certs);
+        if let Some(path) = &config.tls.ca_certificate {
+            let pem = fs::read(path)
+                .with_context(|| format!("Reading CA certificate \"{}\"", path.display()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .with_context(|| format!("Parsing CA certificate \"{}\"", path.display()))?;
+            tls = tls.add_root_certificate(certificate);
+        }
+        let tls = tls.build().context("Setting up TLS")?;
+        let (tls, port) = match config.security {
+            Security::Starttls => (Tls::Required(tls), 587),
+            Security::Opportunistic => (Tls::Opportunistic(tls), 587),
+            Security::Tls => (Tls::Wrapper(tls), 465),
+            Security::None => (Tls::None, 25),
+        };
+        let mut transport = SmtpTransport::builder_dangerous(&config.host)
+            .port(config.port.unwrap_or(port))
+            .tls(tls)
+            .timeout(Some(Duration::from_millis(config.timeout)));
+        match (config.username, config.password) {
+            (Some(username), password) => {
+                transport =
+                    transport.credentials(Credentials::new(username, password.unwrap_or_default()))
+            }
+            (None, Some(_)) => bail!("`password` needs a `username`"),
+            (None, None) => {}
+        }
+
+        let from = config.from.parse().context("Invalid `from` address")?;
+        if config.to.is_empty() {
+            bail!("`to` has no addresses");
+        }
+        let to = config
+            .to
+            .iter()
+            .map(|to| {
+                to.parse()
+                    .with_context(|| format!("Invalid `to` address \"{to}\""))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let reply_to = config
+            .reply_to
+            .map(|reply_to| reply_to.parse())
+            .transpose()
+            .context("Invalid `reply_to` address")?;
+
+        if config.text.is_none() && config.html.is_none() {
+            bail!("Neither `text` nor `html` set");
+        }
+        let mut templates = Environment::new();
+        templates.set_auto_escape_callback(|name| match name {
+            "html" => AutoEscape::Html,
+            _ => AutoEscape::None,
+        });
+        let subject = config.subject.unwrap_or_else(|| {
+            match config.mode {
+                Mode::Item => DEFAULT_ITEM_SUBJECT,
+                Mode::Digest => DEFAULT_DIGEST_SUBJECT,
+            }
+            .to_owned()
+        });
+        templates
+            .add_template_owned("subject", subject)
+            .context("Invalid `subject` template")?;
+        let (text, html) = (config.text.is_some(), config.html.is_some());
+        if let Some(text) = config.text {
+            templates
+                .add_template_owned("text", text)
+                .context("Invalid `text` template")?;
+        }
+        if let Some(html) = config.html {
+            templates
+                .add_template_owned("html", html)
+                .context("Invalid `html` template")?;
+        }
+
+        Ok(EmailSink {
+            transport: transport.build(),
+            from,
+            to,
+            reply_to,
+            mode: config.mode,
+            templates,
+            text,
+            html,
+        })
+    }
+
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let items = items
+            .iter()
+            .map(|item| {
+                // The first of duplicate keys wins
+                item.iter()
+                    .rev()
+                    .map(|(key, value)| (*key, *value))
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        if self.mode == Mode::Digest {
+            let mut feeds: Vec<Feed> = Vec::new();
+            for item in &items {
+                let title = item.get(DIGEST_GROUP_KEY).copied().unwrap_or_default();
+                match feeds.iter_mut().find(|feed| feed.title == title) {
+                    Some(feed) => feed.items.push(item.clone()),
+                    None => feeds.push(Feed {
+                        title,
+                        items: vec![item.clone()],
+                    }),
+                }
+            }
+            let context = context! { feeds, items, count => items.len() };
+            return self.send(context);
+        }
+        let total = items.len();
+        let mut failed = Vec::new();
+        for item in &items {
+            if let Err(err) = self.send(minijinja::Value::from_serialize(item)) {
+                failed.push(format!("{err:#}"));
+            }
+        }
+        match failed.first() {
+            None => Ok(()),
+            Some(first) => Err(anyhow!(
+                "{} of {total} emails failed, first: {first}",
+                failed.len()
+            )),
+        }
+    }
+}
+
+impl EmailSink {
+    fn send(&self, context: minijinja::Value) -> anyhow::Result<()> {
+        let subject = self.render("subject", &context)?;
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            // Line breaks are not allowed in headers
+            .subject(subject.lines().collect::<Vec<_>>().join(" ").trim());
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        if let Some(reply_to) = &self.reply_to {
+            message = message.reply_to(reply_to.clone());
+        }
+        let text = self
+            .text
+            .then(|| self.render("text", &context))
+            .transpose()?;
+        let html = self
+            .html
+            .then(|| self.render("html", &context))
+            .transpose()?;
+        let message = match (text, html) {
+            (Some(text), Some(html)) => {
+                message.multipart(MultiPart::alternative_plain_html(text, html))
+            }
+            (Some(text), None) => message.singlepart(SinglePart::plain(text)),
+            (None, Some(html)) => message.singlepart(SinglePart::html(html)),
+            (None, None) => message.header(ContentType::TEXT_PLAIN).body(String::new()),
+        }
+        .context("Building email")?;
+        self.transport.send(&message).context("Sending email")?;
+        Ok(())
+    }
+
+    fn render(&self, name: &str, context: &minijinja::Value) -> anyhow::Result<String> {
+        self.templates
+            .get_template(name)
+            .and_then(|template| template.render(context))
+            .with_context(|| format!("Rendering `{name}`"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A stand-in for an SMTP server accepting one email. Returns its port and, once the email is
+    /// in, what came after `DATA`.
+    fn stub() -> (u16, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            write!(reader.get_mut(), "220 stub\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let command = line.get(..4).unwrap_or_default().to_ascii_uppercase();
+                let reply = match command.as_str() {
+                    "DATA" => {
+                        write!(reader.get_mut(), "354 go ahead\r\n").unwrap();
+                        let mut data = String::new();
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&line);
+                        }
+                        write!(reader.get_mut(), "250 queued\r\n").unwrap();
+                        return data.replace("\r\n", "\n");
+                    }
+                    "QUIT" => "221 bye",
+                    _ => "250 ok",
+                };
+                write!(reader.get_mut(), "{reply}\r\n").unwrap();
+            }
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn digest_groups_items_by_feed() {
+        let (port, stub) = stub();
+        let config = serde_json::from_value(json!({
+            "host": "127.0.0.1",
+            "port": port,
+            "security": "none",
+            "from": "feeds@example.com",
+            "to": ["me@example.com"],
+            "mode": "digest",
+            "text": "{% for feed in feeds %}# {{ feed.title }}\n\
+                {% for item in feed.items %}- {{ item.title }}\n{% endfor %}{% endfor %}",
+        }))
+        .unwrap();
+        let mut sink = EmailSink::new(config).unwrap();
+        sink.sink_items(vec![
+            vec![("feed_title", "xkcd"), ("title", "Standards")],
+            vec![("feed_title", "LWN"), ("title", "Kernel release")],
+            vec![("feed_title", "xkcd"), ("title", "Exploits of a Mom")],
+            vec![("title", "Untitled feed")],
+        ])
+        .unwrap();
+        let data = stub.join().unwrap();
+        assert!(data.contains("Subject: 4 new items\n"), "{data}");
+        assert!(
+            data.contains(
+                "# xkcd\n- Standards\n- Exploits of a Mom\n# LWN\n- Kernel release\n# \n- Untitled feed\n"
+            ),
+            "{data}"
+        );
+    }
+}
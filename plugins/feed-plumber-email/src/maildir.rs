@@ -0,0 +1,213 @@
+//! The `maildir` source emits messages delivered to a local Maildir.
+//!
+//! Messages are recognized by the unique part of their file name, which stays the same when a mail
+//! client moves them from `new` to `cur` or changes their flags. The messages emitted so far are
+//! saved to `state_file`, and forgotten once they are deleted. Messages are only read, never moved.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    FeedPlumberSource,
+};
+use serde::Deserialize;
+
+use crate::message::message_item;
+
+/// The directories of a Maildir holding messages.
+const FOLDERS: [&str; 2] = ["new", "cur"];
+
+#[derive(Deserialize)]
+pub struct MaildirConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    path: PathBuf,
+    /// Whether the messages already there when the source first polls are emitted.
+    #[serde(default)]
+    existing: bool,
+    state_file: Option<PathBuf>,
+}
+
+pub struct MaildirSource {
+    path: PathBuf,
+    existing: bool,
+    state_file: PathBuf,
+    /// The unique names of the messages emitted so far. `None` until the first poll if there was
+    /// no saved state.
+    seen: Option<HashSet<String>>,
+}
+
+impl FeedPlumberSource for MaildirSource {
+    type ConfigType = MaildirConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        if FOLDERS
+            .iter()
+            .any(|folder| !config.path.join(folder).is_dir())
+        {
+            bail!(
+                "\"{}\" is not a Maildir, it needs `new` and `cur` directories",
+                config.path.display()
+            );
+        }
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("maildir-{}.json", config.name)));
+        Ok(MaildirSource {
+            path: config.path,
+            existing: config.existing,
+            seen: load_state(&state_file)?,
+            state_file,
+        })
+    }
+
+    fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let mut messages = Vec::new();
+        for folder in FOLDERS {
+            scan(&self.path.join(folder), &mut messages)?;
+        }
+        // Maildir names start with the time of delivery
+        messages.sort_by(|(_, a), (_, b)| a.file_name().cmp(&b.file_name()));
+        let mut seen = self.seen.take().unwrap_or_else(|| {
+            if self.existing {
+                HashSet::new()
+            } else {
+                messages.iter().map(|(unique, _)| unique.clone()).collect()
+            }
+        });
+
+        let before = seen.len();
+        seen.retain(|unique| messages.iter().any(|(message, _)| message == unique));
+        let mut changed = seen.len() != before;
+        let mut items = Vec::new();
+        for (unique, path) in messages {
+            if seen.contains(&unique) {
+                continue;
+            }
+            let raw = match fs::read(&path) {
+                Ok(raw) => raw,
+                // Moved to `cur` meanwhile, found there next time
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Reading \"{}\"", path.display()))
+                }
+            };
+            items.extend(message_item(&raw));
+            seen.insert(unique);
+            changed = true;
+        }
+        if changed {
+            save_state(&self.state_file, &seen)?;
+        }
+        self.seen = Some(seen);
+        Ok(items)
+    }
+}
+
+/// Adds the messages in `folder` along with their unique names.
+fn scan(folder: &Path, messages: &mut Vec<(String, PathBuf)>) -> anyhow::Result<()> {
+    let entries = fs::read_dir(folder)
+        .with_context(|| format!("Reading directory \"{}\"", folder.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Reading directory \"{}\"", folder.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || !entry.file_type().is_ok_and(|kind| kind.is_file()) {
+            continue;
+        }
+        // Flags follow the unique part after a colon
+        let unique = name.split_once(':').map_or(&*name, |(unique, _)| unique);
+        messages.push((unique.to_owned(), entry.path()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maildir(path: &Path, state_file: &Path, existing: bool) -> MaildirSource {
+        let config = serde_json::json!({
+            "name": "test",
+            "path": path,
+            "existing": existing,
+            "state_file": state_file,
+        });
+        MaildirSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn deliver(path: &Path, name: &str, subject: &str) {
+        let message = format!("From: a@example.com\r\nSubject: {subject}\r\n\r\nHello\r\n");
+        fs::write(path.join("new").join(name), message).unwrap();
+    }
+
+    /// The subjects of the messages emitted by the next poll.
+    fn poll(source: &mut MaildirSource) -> Vec<String> {
+        source
+            .poll_source()
+            .unwrap()
+            .into_iter()
+            .map(|item| {
+                item.into_iter()
+                    .find(|(key, _)| key == "subject")
+                    .unwrap()
+                    .1
+            })
+            .collect()
+    }
+
+    #[test]
+    fn emits_each_message_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("mail"), dir.path().join("state.json"));
+        for folder in FOLDERS.iter().chain(&["tmp"]) {
+            fs::create_dir_all(path.join(folder)).unwrap();
+        }
+        deliver(&path, "1.a.host", "Old");
+
+        let mut source = maildir(&path, &state_file, false);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        deliver(&path, "3.c.host", "Third");
+        deliver(&path, "2.b.host", "Second");
+        assert_eq!(poll(&mut source), ["Second", "Third"]);
+
+        // Read by a mail client, which moves it to `cur` and flags it
+        fs::rename(
+            path.join("new").join("2.b.host"),
+            path.join("cur").join("2.b.host:2,S"),
+        )
+        .unwrap();
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        drop(source);
+
+        let mut source = maildir(&path, &state_file, false);
+        deliver(&path, "4.d.host", "Fourth");
+        assert_eq!(poll(&mut source), ["Fourth"]);
+    }
+
+    #[test]
+    fn forgets_deleted_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("mail"), dir.path().join("state.json"));
+        for folder in FOLDERS {
+            fs::create_dir_all(path.join(folder)).unwrap();
+        }
+        deliver(&path, "1.a.host", "First");
+
+        let mut source = maildir(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["First"]);
+        fs::remove_file(path.join("new").join("1.a.host")).unwrap();
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        let seen: HashSet<String> = load_state(&state_file).unwrap().unwrap();
+        assert!(seen.is_empty());
+
+        // Restoring it from a backup makes it new again
+        deliver(&path, "1.a.host", "First");
+        assert_eq!(poll(&mut source), ["First"]);
+    }
+}
@@ -0,0 +1,40 @@
+//! Turning email messages into items.
+
+use mail_parser::{Address, MessageParser};
+
+/// The pairs of a raw message: `from`, `to`, `subject`, `date`, `message_id` and `body`, as far as
+/// the message has them. `None` if it is not a message at all.
+pub fn message_item(raw: &[u8]) -> Option<Vec<(String, String)>> {
+    let message = MessageParser::default().parse(raw)?;
+    let mut item = Vec::new();
+    let mut push = |key: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            item.push((key.to_owned(), value));
+        }
+    };
+    push("from", message.from().map(addresses));
+    push("to", message.to().map(addresses));
+    push("subject", message.subject().map(str::to_owned));
+    push("date", message.date().map(|date| date.to_rfc3339()));
+    push("message_id", message.message_id().map(str::to_owned));
+    // Converted from the HTML part if there is no text part
+    push(
+        "body",
+        message.body_text(0).map(|body| body.trim().to_owned()),
+    );
+    Some(item)
+}
+
+/// Addresses like `Name <address>`, separated by commas.
+fn addresses(address: &Address) -> String {
+    address
+        .iter()
+        .filter_map(|addr| match (addr.name(), addr.address()) {
+            (Some(name), Some(address)) => Some(format!("{name} <{address}>")),
+            (None, Some(address)) => Some(address.to_owned()),
+            (Some(name), None) => Some(name.to_owned()),
+            (None, None) => None,
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,561 @@
+//! The `file` sink appends items to a file, as JSON lines, CSV or templated lines.
+//!
+//! The file is rotated once it would grow past `max_size`, or when `rotate_schedule` comes up. The
+//! rotated file is renamed to the path with the time of rotation appended, optionally gzipped, and
+//! only the newest `keep` rotated files are kept around.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Local};
+use cron::Schedule;
+use feed_plumber_plugin_rs::{template::json, FeedPlumberSink};
+use flate2::{write::GzEncoder, Compression};
+use minijinja::{AutoEscape, Environment};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+
+#[derive(Deserialize)]
+pub struct FileConfig {
+    path: PathBuf,
+    #[serde(default)]
+    format: FileFormat,
+    /// Each line with `format = "template"`, a minijinja template.
+    template: Option<String>,
+    /// The columns with `format = "csv"`. Taken from the file's header, or else the keys of the
+    /// first item, if not set.
+    columns: Option<Vec<String>>,
+    /// Whether CSV files start with a header.
+    #[serde(default = "default_header")]
+    header: bool,
+    /// In bytes.
+    max_size: Option<u64>,
+    /// A cron expression, like the schedules of sources.
+    rotate_schedule: Option<String>,
+    /// Whether rotated files are gzipped.
+    #[serde(default)]
+    compress: bool,
+    /// How many rotated files are kept. All of them if not set.
+    keep: Option<usize>,
+    #[serde(default)]
+    fsync: Fsync,
+}
+
+#[inline]
+const fn default_header() -> bool {
+    true
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FileFormat {
+    /// A JSON object per item.
+    #[default]
+    Json,
+    /// A record per item, with a field per column.
+    Csv,
+    /// The rendered `template` per item.
+    Template,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Fsync {
+    /// Left to the OS.
+    #[default]
+    Never,
+    /// After every batch of items.
+    Batch,
+    /// After every item.
+    Item,
+}
+
+/// The file currently written to.
+struct Open {
+    writer: BufWriter<File>,
+    size: u64,
+    rotate_at: Option<DateTime<Local>>,
+}
+
+pub struct FileSink {
+    path: PathBuf,
+    format: FileFormat,
+    template: Option<Environment<'static>>,
+    /// The CSV columns, once known.
+    columns: Option<Vec<String>>,
+    /// Whether `columns` were configured rather than found.
+    fixed_columns: bool,
+    header: bool,
+    max_size: Option<u64>,
+    rotate_schedule: Option<Schedule>,
+    compress: bool,
+    keep: Option<usize>,
+    fsync: Fsync,
+    /// Opened on the first write, and again after rotating or failing to write.
+    file: Option<Open>,
+}
+
+impl FeedPlumberSink for FileSink {
+    type ConfigType = FileConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let template = match (config.format, config.template) {
+            (FileFormat::Template, Some(template)) => {
+                let mut env = Environment::new();
+                env.set_auto_escape_callback(|_| AutoEscape::None);
+                env.add_filter("json", json);
+                env.add_template_owned("line", template)
+                    .context("Invalid `template`")?;
+                Some(env)
+            }
+            (FileFormat::Template, None) => bail!("`format = \"template\"` needs a `template`"),
+            (_, Some(_)) => bail!("`template` is only used with `format = \"template\"`"),
+            (_, None) => None,
+        };
+        if config.format != FileFormat::Csv && config.columns.is_some() {
+            bail!("`columns` are only used with `format = \"csv\"`");
+        }
+        if config.format == FileFormat::Csv && !config.header && config.columns.is_none() {
+            bail!("`header = false` needs `columns`");
+        }
+        if config.max_size == Some(0) {
+            bail!("`max_size` must be more than 0");
+        }
+        let rotate_schedule = config
+            .rotate_schedule
+            .as_deref()
+            .map(Schedule::from_str)
+            .transpose()
+            .context("Invalid `rotate_schedule`")?;
+        if let Some(parent) = config
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Creating directory \"{}\"", parent.display()))?;
+        }
+        Ok(Self {
+            path: config.path,
+            format: config.format,
+            template,
+            fixed_columns: config.columns.is_some(),
+            columns: config.columns,
+            header: config.header,
+            max_size: config.max_size,
+            rotate_schedule,
+            compress: config.compress,
+            keep: config.keep,
+            fsync: config.fsync,
+            file: None,
+        })
+    }
+
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()> {
+        let res = self.write_items(&items);
+        if res.is_err() {
+            // Reopened on the next batch, in case the file was the problem
+            self.file = None;
+        }
+        res
+    }
+}
+
+impl FileSink {
+    fn write_items(&mut self, items: &[Vec<(&str, &str)>]) -> anyhow::Result<()> {
+        for item in items {
+            if self.file.is_none() {
+                self.open()?;
+            }
+            if self.format == FileFormat::Csv && self.columns.is_none() {
+                let mut columns = Vec::<String>::new();
+                for (key, _) in item {
+                    if !columns.iter().any(|column| column == key) {
+                        columns.push((*key).to_owned());
+                    }
+                }
+                self.columns = Some(columns);
+            }
+            let line = self.line(item)?;
+            if self.due(line.len() as u64) {
+                self.rotate()?;
+                self.open()?;
+            }
+            let open = self.file.as_mut().ok_or(anyhow!("File not open"))?;
+            if open.size == 0 && self.format == FileFormat::Csv && self.header {
+                let header = csv_record(self.columns.iter().flatten().map(String::as_str))?;
+                open.writer.write_all(&header).context("Writing header")?;
+                open.size += header.len() as u64;
+            }
+            open.writer.write_all(&line).context("Writing item")?;
+            open.size += line.len() as u64;
+            if self.fsync == Fsync::Item {
+                sync(&mut open.writer)?;
+            }
+        }
+        if let Some(open) = &mut self.file {
+            match self.fsync {
+                Fsync::Batch => sync(&mut open.writer)?,
+                _ => open.writer.flush().context("Writing items")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the file for appending. CSV files are rotated first if their header doesn't match
+    /// the configured columns.
+    fn open(&mut self) -> anyhow::Result<()> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Reading \"{}\"", self.path.display()))
+            }
+        };
+        if self.format == FileFormat::Csv && self.header {
+            if let Some(found) = metadata
+                .as_ref()
+                .filter(|metadata| metadata.len() > 0)
+                .map(|_| read_header(&self.path))
+                .transpose()?
+            {
+                match &self.columns {
+                    Some(columns) if *columns != found && self.fixed_columns => {
+                        self.rotate()?;
+                        return self.open();
+                    }
+                    Some(_) => {}
+                    None => self.columns = Some(found),
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Opening \"{}\"", self.path.display()))?;
+        // A schedule that came up while the service was not running rotates on the first write
+        let since = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified().ok())
+            .map_or_else(Local::now, DateTime::<Local>::from);
+        self.file = Some(Open {
+            writer: BufWriter::new(file),
+            size: metadata.map_or(0, |metadata| metadata.len()),
+            rotate_at: self
+                .rotate_schedule
+                .as_ref()
+                .and_then(|schedule| schedule.after(&since).next()),
+        });
+        Ok(())
+    }
+
+    /// Whether the file has to be rotated before `len` more bytes are written to it.
+    fn due(&mut self, len: u64) -> bool {
+        let Some(open) = &mut self.file else {
+            return false;
+        };
+        let too_large = self
+            .max_size
+            .is_some_and(|max_size| open.size + len > max_size);
+        let scheduled = open.rotate_at.is_some_and(|at| at <= Local::now());
+        if scheduled && open.size == 0 {
+            // Nothing to rotate, so waiting for the next time instead
+            open.rotate_at = self
+                .rotate_schedule
+                .as_ref()
+                .and_then(|schedule| schedule.upcoming(Local).next());
+            return false;
+        }
+        open.size > 0 && (too_large || scheduled)
+    }
+
+    /// Moves the file out of the way, and compresses and prunes rotated files as configured.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        if let Some(mut open) = self.file.take() {
+            match self.fsync {
+                Fsync::Never => open.writer.flush().context("Writing items")?,
+                _ => sync(&mut open.writer)?,
+            }
+        }
+        let stamp = Local::now().format("%Y%m%d-%H%M%S");
+        let extension = if self.compress { ".gz" } else { "" };
+        let mut rotated = rotated_path(&self.path, &format!("{stamp}"));
+        let mut count = 1;
+        while rotated.exists() || rotated_with(&rotated, extension).exists() {
+            count += 1;
+            rotated = rotated_path(&self.path, &format!("{stamp}-{count}"));
+        }
+        fs::rename(&self.path, &rotated)
+            .with_context(|| format!("Rotating \"{}\"", self.path.display()))?;
+        if self.compress {
+            compress(&rotated, &rotated_with(&rotated, extension))?;
+        }
+        if let Some(keep) = self.keep {
+            self.prune(keep)?;
+        }
+        Ok(())
+    }
+
+    /// Removes all but the newest `keep` rotated files.
+    fn prune(&self, keep: usize) -> anyhow::Result<()> {
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default()
+        );
+        let mut rotated = fs::read_dir(directory)
+            .with_context(|| format!("Reading directory \"{}\"", directory.display()))?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let stamp = name.strip_prefix(&prefix)?;
+                let modified = entry.metadata().and_then(|metadata| metadata.modified());
+                stamp
+                    .starts_with(|char: char| char.is_ascii_digit())
+                    .then(|| (modified.ok(), directory.join(&name)))
+            })
+            .collect::<Vec<_>>();
+        // Files rotated within the same second only differ in their suffix
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(keep);
+        for (_, path) in &rotated[..excess] {
+            fs::remove_file(path).with_context(|| format!("Removing \"{}\"", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// An item as a line of the file.
+    fn line(&self, item: &[(&str, &str)]) -> anyhow::Result<Vec<u8>> {
+        match self.format {
+            FileFormat::Json => {
+                let object = item
+                    .iter()
+                    .map(|(key, value)| ((*key).to_owned(), JsonValue::String((*value).to_owned())))
+                    .collect::<Map<_, _>>();
+                let mut line = serde_json::to_vec(&object)?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            FileFormat::Csv => {
+                // Keys not among the columns are left out
+                csv_record(self.columns.iter().flatten().map(|column| {
+                    item.iter()
+                        .find(|(key, _)| key == column)
+                        .map_or("", |(_, value)| *value)
+                }))
+            }
+            FileFormat::Template => {
+                let env = self.template.as_ref().ok_or(anyhow!("No `template`"))?;
+                let context = item
+                    .iter()
+                    .rev()
+                    .map(|(key, value)| (*key, *value))
+                    .collect::<BTreeMap<_, _>>();
+                let mut line = env
+                    .get_template("line")
+                    .and_then(|template| template.render(&context))
+                    .context("Rendering `template`")?;
+                if !line.ends_with('\n') {
+                    line.push('\n');
+                }
+                Ok(line.into_bytes())
+            }
+        }
+    }
+}
+
+fn rotated_path(path: &Path, stamp: &str) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".");
+    rotated.push(stamp);
+    PathBuf::from(rotated)
+}
+
+fn rotated_with(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn compress(from: &Path, to: &Path) -> anyhow::Result<()> {
+    File::open(from)
+        .and_then(|mut file| {
+            let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+            io::copy(&mut file, &mut encoder)?;
+            encoder.finish()?.sync_all()
+        })
+        .and_then(|_| fs::remove_file(from))
+        .with_context(|| format!("Compressing \"{}\"", from.display()))
+}
+
+fn sync(writer: &mut BufWriter<File>) -> anyhow::Result<()> {
+    writer
+        .flush()
+        .and_then(|_| writer.get_ref().sync_data())
+        .context("Syncing file")
+}
+
+fn read_header(path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .with_context(|| format!("Reading \"{}\"", path.display()))?;
+    let mut record = csv::StringRecord::new();
+    reader
+        .read_record(&mut record)
+        .with_context(|| format!("Reading header of \"{}\"", path.display()))?;
+    Ok(record.iter().map(str::to_owned).collect())
+}
+
+fn csv_record<'a>(fields: impl IntoIterator<Item = &'a str>) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields).context("Writing CSV")?;
+    writer
+        .into_inner()
+        .map_err(|err| anyhow!("{}", err.error()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn sink(path: &Path, config: serde_json::Value) -> FileSink {
+        let mut config = config;
+        config["path"] = path.display().to_string().into();
+        FileSink::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    /// The contents of the files rotated from `path`, oldest first.
+    fn rotated(path: &Path) -> Vec<String> {
+        let prefix = format!("{}.", path.file_name().unwrap().to_string_lossy());
+        let mut rotated = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|rotated| {
+                rotated
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with(&prefix)
+            })
+            .map(|rotated| (fs::metadata(&rotated).unwrap().modified().unwrap(), rotated))
+            .collect::<Vec<_>>();
+        rotated.sort();
+        rotated
+            .into_iter()
+            .map(|(_, rotated)| {
+                let mut contents = String::new();
+                let mut file = File::open(&rotated).unwrap();
+                if rotated
+                    .extension()
+                    .is_some_and(|extension| extension == "gz")
+                {
+                    GzDecoder::new(file).read_to_string(&mut contents).unwrap();
+                } else {
+                    file.read_to_string(&mut contents).unwrap();
+                }
+                contents
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rotates_past_max_size_and_keeps_the_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.json");
+        let mut sink = sink(&path, serde_json::json!({ "max_size": 25, "keep": 2 }));
+        for n in ["1", "2", "3", "4", "5", "6", "7"] {
+            sink.sink_items(vec![vec![("n", n)]]).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"n\":\"7\"}\n");
+        assert_eq!(
+            rotated(&path),
+            [
+                "{\"n\":\"3\"}\n{\"n\":\"4\"}\n",
+                "{\"n\":\"5\"}\n{\"n\":\"6\"}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn compresses_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.json");
+        let mut sink = sink(
+            &path,
+            serde_json::json!({ "max_size": 15, "compress": true }),
+        );
+        sink.sink_items(vec![vec![("n", "1")], vec![("n", "2")]])
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"n\":\"2\"}\n");
+        assert_eq!(rotated(&path), ["{\"n\":\"1\"}\n"]);
+    }
+
+    #[test]
+    fn takes_csv_columns_from_the_first_item_or_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.csv");
+        let mut sink = sink(&path, serde_json::json!({ "format": "csv" }));
+        sink.sink_items(vec![
+            vec![("title", "Hi, there"), ("link", "https://a")],
+            vec![("link", "https://b"), ("extra", "left out")],
+        ])
+        .unwrap();
+        drop(sink);
+
+        let mut sink = self::sink(&path, serde_json::json!({ "format": "csv" }));
+        sink.sink_items(vec![vec![("link", "https://c"), ("title", "Bye")]])
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "title,link\n\"Hi, there\",https://a\n,https://b\nBye,https://c\n"
+        );
+        assert_eq!(rotated(&path), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rotates_csv_files_with_another_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.csv");
+        fs::write(&path, "title\nOld\n").unwrap();
+
+        let config = serde_json::json!({ "format": "csv", "columns": ["title", "link"] });
+        let mut sink = sink(&path, config);
+        sink.sink_items(vec![vec![("title", "New"), ("link", "https://a")]])
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "title,link\nNew,https://a\n"
+        );
+        assert_eq!(rotated(&path), ["title\nOld\n"]);
+    }
+
+    #[test]
+    fn writes_csv_without_a_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.csv");
+        let config = serde_json::json!({ "format": "csv", "columns": ["a", "b"], "header": false });
+        let mut sink = sink(&path, config);
+        sink.sink_items(vec![vec![("b", "2"), ("a", "1")]]).unwrap();
+        sink.sink_items(vec![vec![("a", "3")]]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1,2\n3,\n");
+    }
+}
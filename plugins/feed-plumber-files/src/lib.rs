@@ -0,0 +1,37 @@
+use feed_plumber_plugin_rs::feed_plumber_plugin;
+
+#[cfg(unix)]
+use crate::tail::TailSource;
+use crate::{file::FileSink, watch::WatchSource};
+
+mod file;
+mod lines;
+// Rotation is noticed by the file's device and inode, which only Unix has
+#[cfg(unix)]
+mod tail;
+mod watch;
+
+#[cfg(unix)]
+feed_plumber_plugin! {
+    sources:;
+    sinks: "file" => FileSink;
+    processors:;
+    event_sources: "tail" => TailSource, "watch" => WatchSource;
+}
+
+#[cfg(not(unix))]
+feed_plumber_plugin! {
+    sources:;
+    sinks: "file" => FileSink;
+    processors:;
+    event_sources: "watch" => WatchSource;
+}
+
+type Item = Vec<(String, String)>;
+
+const DEFAULT_POLL_INTERVAL: u64 = 1000;
+
+#[inline]
+const fn default_poll_interval() -> u64 {
+    DEFAULT_POLL_INTERVAL
+}
@@ -0,0 +1,102 @@
+//! Turning lines of text into items.
+
+use anyhow::{bail, Context};
+use feed_plumber_plugin_rs::json;
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineFormat {
+    /// The whole line, as `line`.
+    #[default]
+    Plain,
+    /// A JSON value, with nested fields flattened to keys like `request.method`, or a scalar as
+    /// `value`.
+    Json,
+    /// The named groups of `pattern`.
+    Regex,
+}
+
+/// How lines are parsed, shared by the sources' configs.
+#[derive(Deserialize)]
+pub struct LineConfig {
+    #[serde(default)]
+    format: LineFormat,
+    /// The regex with named groups for `format = "regex"`.
+    pattern: Option<String>,
+    /// Whether lines that don't parse are emitted as `line` rather than dropped.
+    #[serde(default)]
+    keep_unparsed: bool,
+}
+
+impl LineConfig {
+    /// Whether any of the options were set.
+    pub fn is_configured(&self) -> bool {
+        self.format != LineFormat::Plain || self.pattern.is_some() || self.keep_unparsed
+    }
+}
+
+pub struct LineParser {
+    format: LineFormat,
+    pattern: Option<Regex>,
+    keep_unparsed: bool,
+}
+
+impl LineParser {
+    pub fn new(config: LineConfig) -> anyhow::Result<Self> {
+        let pattern = match (config.format, config.pattern) {
+            (LineFormat::Regex, Some(pattern)) => {
+                let pattern = Regex::new(&pattern).context("Invalid `pattern`")?;
+                if pattern.capture_names().flatten().next().is_none() {
+                    bail!("`pattern` has no named groups, like `(?P<name>...)`");
+                }
+                Some(pattern)
+            }
+            (LineFormat::Regex, None) => bail!("`format = \"regex\"` needs a `pattern`"),
+            (_, Some(_)) => bail!("`pattern` is only used with `format = \"regex\"`"),
+            (_, None) => None,
+        };
+        Ok(Self {
+            format: config.format,
+            pattern,
+            keep_unparsed: config.keep_unparsed,
+        })
+    }
+
+    /// The pairs of a line, or `None` for blank lines and lines that don't parse and are dropped.
+    pub fn parse(&self, line: &str) -> Option<Vec<(String, String)>> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            return None;
+        }
+        let parsed = match self.format {
+            LineFormat::Plain => None,
+            LineFormat::Json => parse_json(line).ok(),
+            LineFormat::Regex => self.pattern.as_ref().and_then(|pattern| {
+                let captures = pattern.captures(line)?;
+                Some(
+                    pattern
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| {
+                            let value = captures.name(name)?;
+                            Some((name.to_owned(), value.as_str().to_owned()))
+                        })
+                        .collect(),
+                )
+            }),
+        };
+        match parsed {
+            Some(pairs) => Some(pairs),
+            None if self.format == LineFormat::Plain || self.keep_unparsed => {
+                Some(vec![("line".to_owned(), line.to_owned())])
+            }
+            None => None,
+        }
+    }
+}
+
+fn parse_json(line: &str) -> anyhow::Result<Vec<(String, String)>> {
+    Ok(json::flatten(&serde_json::from_str(line)?))
+}
@@ -0,0 +1,326 @@
+//! The `tail` source follows a file as it is appended to, like `tail -F`.
+//!
+//! Files are checked every `poll_interval`. When the path names a different file than before, the
+//! old file was rotated: it is read to its end, and the new one is followed from its start. A file
+//! that got shorter was truncated and is followed from its start again. The position reached is
+//! saved to `state_file` after every batch, so a restart picks up where the source left off.
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    Emitter, FeedPlumberEventSource,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    default_poll_interval,
+    lines::{LineConfig, LineParser},
+    Item,
+};
+
+/// The most read from a file in one go. Longer backlogs are read over several polls, and longer
+/// lines are split.
+const MAX_READ: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct TailConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    path: PathBuf,
+    /// Whether a file seen for the first time is read from its start rather than its end.
+    #[serde(default)]
+    from_start: bool,
+    /// In milliseconds.
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    state_file: Option<PathBuf>,
+    #[serde(flatten)]
+    lines: LineConfig,
+}
+
+/// Where in which file the source is.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    dev: u64,
+    inode: u64,
+    offset: u64,
+}
+
+struct Followed {
+    file: File,
+    position: Position,
+}
+
+pub struct TailSource {
+    path: PathBuf,
+    from_start: bool,
+    poll_interval: Duration,
+    state_file: PathBuf,
+    parser: LineParser,
+    /// The position saved by an earlier run, until the file is opened.
+    saved: Option<Position>,
+    followed: Option<Followed>,
+}
+
+impl FeedPlumberEventSource for TailSource {
+    type ConfigType = TailConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("tail-{}.json", config.name)));
+        Ok(Self {
+            path: config.path,
+            from_start: config.from_start,
+            poll_interval: Duration::from_millis(config.poll_interval),
+            saved: load_state(&state_file)?,
+            state_file,
+            parser: LineParser::new(config.lines)?,
+            followed: None,
+        })
+    }
+
+    fn run(&mut self, emitter: &Emitter) -> anyhow::Result<()> {
+        loop {
+            let (items, more) = self.poll()?;
+            if !items.is_empty() && !emitter.emit(items) {
+                // Not saving the position, so the lines are read again next time
+                return Ok(());
+            }
+            self.save()?;
+            let wait = if more {
+                Duration::ZERO
+            } else {
+                self.poll_interval
+            };
+            if emitter.wait(wait) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl TailSource {
+    /// Saves the position reached, if it moved.
+    fn save(&mut self) -> anyhow::Result<()> {
+        if let Some(followed) = &self.followed {
+            if self.saved != Some(followed.position) {
+                save_state(&self.state_file, &followed.position)?;
+                self.saved = Some(followed.position);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads what was added since the last poll. Also returns whether there is more to read.
+    fn poll(&mut self) -> anyhow::Result<(Vec<Item>, bool)> {
+        let mut items = Vec::new();
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Reading \"{}\"", self.path.display()))
+            }
+        };
+
+        let rotated = self.followed.as_ref().is_some_and(|followed| {
+            metadata.as_ref().is_some_and(|metadata| {
+                (metadata.dev(), metadata.ino()) != (followed.position.dev, followed.position.inode)
+            })
+        });
+        if rotated {
+            // Whatever was written to the old file before it was rotated
+            let mut followed = self.followed.take().unwrap();
+            while self.read(&mut followed, true, &mut items)? {}
+            self.saved = None;
+        }
+
+        if self.followed.is_none() {
+            let Some(metadata) = metadata else {
+                return Ok((items, false));
+            };
+            let file = File::open(&self.path)
+                .with_context(|| format!("Opening \"{}\"", self.path.display()))?;
+            let (dev, inode) = (metadata.dev(), metadata.ino());
+            let offset = match self.saved {
+                Some(saved) if (saved.dev, saved.inode) == (dev, inode) => saved.offset,
+                // Rotated while the source was not running, or rotated just now
+                Some(_) => 0,
+                None if rotated || self.from_start => 0,
+                None => metadata.len(),
+            };
+            self.followed = Some(Followed {
+                file,
+                position: Position { dev, inode, offset },
+            });
+        }
+
+        let mut followed = self.followed.take().unwrap();
+        let len = followed
+            .file
+            .metadata()
+            .with_context(|| format!("Reading \"{}\"", self.path.display()))?
+            .len();
+        if len < followed.position.offset {
+            followed.position.offset = 0;
+        }
+        let more = self.read(&mut followed, false, &mut items)?;
+        self.followed = Some(followed);
+        Ok((items, more))
+    }
+
+    /// Reads complete lines from the position on, or everything with `to_end`. Returns whether
+    /// there is more to read.
+    fn read(
+        &self,
+        followed: &mut Followed,
+        to_end: bool,
+        items: &mut Vec<Item>,
+    ) -> anyhow::Result<bool> {
+        let mut buffer = Vec::new();
+        followed
+            .file
+            .seek(SeekFrom::Start(followed.position.offset))
+            .and_then(|_| {
+                followed
+                    .file
+                    .by_ref()
+                    .take(MAX_READ as u64)
+                    .read_to_end(&mut buffer)
+            })
+            .with_context(|| format!("Reading \"{}\"", self.path.display()))?;
+        let full = buffer.len() == MAX_READ;
+        let complete = match buffer.iter().rposition(|byte| *byte == b'\n') {
+            Some(newline) if !to_end => newline + 1,
+            // A line longer than what is read at once is split rather than stalling
+            None if !to_end && !full => 0,
+            _ => buffer.len(),
+        };
+        followed.position.offset += complete as u64;
+        items.extend(
+            String::from_utf8_lossy(&buffer[..complete])
+                .lines()
+                .filter_map(|line| self.parser.parse(line)),
+        );
+        Ok(full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::Path};
+
+    use super::*;
+
+    fn tail(path: &Path, state_file: &Path, from_start: bool) -> TailSource {
+        let config = serde_json::json!({
+            "name": "test",
+            "path": path,
+            "from_start": from_start,
+            "state_file": state_file,
+        });
+        TailSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    /// The lines read by the next poll.
+    fn poll(tail: &mut TailSource) -> Vec<String> {
+        let (items, _) = tail.poll().unwrap();
+        items.into_iter().map(|item| item[0].1.clone()).collect()
+    }
+
+    #[test]
+    fn follows_appended_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("log"), dir.path().join("state.json"));
+        append(&path, "old\n");
+
+        let mut source = tail(&path, &state_file, false);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        append(&path, "one\ntw");
+        assert_eq!(poll(&mut source), ["one"]);
+        append(&path, "o\n");
+        assert_eq!(poll(&mut source), ["two"]);
+
+        let mut source = tail(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["old", "one", "two"]);
+    }
+
+    #[test]
+    fn resumes_from_the_saved_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("log"), dir.path().join("state.json"));
+        append(&path, "one\n");
+
+        let mut source = tail(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["one"]);
+        source.save().unwrap();
+        drop(source);
+        append(&path, "two\n");
+
+        // `from_start` only applies to files without a saved position
+        let mut source = tail(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["two"]);
+    }
+
+    #[test]
+    fn reads_rotated_files_to_their_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("log"), dir.path().join("state.json"));
+        append(&path, "one\n");
+
+        let mut source = tail(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["one"]);
+        append(&path, "two\nunfinished");
+        fs::rename(&path, dir.path().join("log.1")).unwrap();
+        append(&path, "three\n");
+        assert_eq!(poll(&mut source), ["two", "unfinished", "three"]);
+        append(&path, "four\n");
+        assert_eq!(poll(&mut source), ["four"]);
+    }
+
+    #[test]
+    fn starts_over_on_files_rotated_while_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("log"), dir.path().join("state.json"));
+        append(&path, "one\n");
+
+        let mut source = tail(&path, &state_file, false);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        source.save().unwrap();
+        drop(source);
+        fs::rename(&path, dir.path().join("log.1")).unwrap();
+        append(&path, "two\n");
+
+        let mut source = tail(&path, &state_file, false);
+        assert_eq!(poll(&mut source), ["two"]);
+    }
+
+    #[test]
+    fn starts_over_on_truncated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, state_file) = (dir.path().join("log"), dir.path().join("state.json"));
+        append(&path, "a long line\n");
+
+        let mut source = tail(&path, &state_file, true);
+        assert_eq!(poll(&mut source), ["a long line"]);
+        fs::write(&path, "short\n").unwrap();
+        assert_eq!(poll(&mut source), ["short"]);
+    }
+}
@@ -0,0 +1,449 @@
+//! The `watch` source reports files showing up in a directory.
+//!
+//! The directory is scanned every `poll_interval`. A file is only reported once it stayed the
+//! same for a whole interval, so files still being written are not picked up half-way. The files
+//! reported so far are saved to `state_file`, so files added while the source was not running are
+//! reported when it starts.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    Emitter, FeedPlumberEventSource,
+};
+use globset::{Glob, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    default_poll_interval,
+    lines::{LineConfig, LineParser},
+    Item,
+};
+
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct WatchConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    directory: PathBuf,
+    /// A glob the path relative to `directory` has to match, e.g. `*.csv`.
+    #[serde(rename = "match")]
+    pattern: Option<String>,
+    #[serde(default)]
+    recursive: bool,
+    /// Whether the files already there when the source first starts are reported.
+    #[serde(default)]
+    existing: bool,
+    /// Whether files are reported again when they change.
+    #[serde(default)]
+    modified: bool,
+    #[serde(default)]
+    contents: Contents,
+    /// Files larger than this, in bytes, are reported without their contents.
+    #[serde(default = "default_max_size")]
+    max_size: u64,
+    /// In milliseconds.
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    state_file: Option<PathBuf>,
+    #[serde(flatten)]
+    lines: LineConfig,
+}
+
+#[inline]
+const fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Contents {
+    /// Just the file's details.
+    #[default]
+    None,
+    /// The file's details and its text, as `contents`.
+    Whole,
+    /// An item per line, parsed like `tail` does, with the file's details.
+    Lines,
+}
+
+/// What a file looked like when it was scanned.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    /// In milliseconds since the epoch.
+    modified: i64,
+}
+
+pub struct WatchSource {
+    directory: PathBuf,
+    pattern: Option<GlobMatcher>,
+    recursive: bool,
+    modified: bool,
+    contents: Contents,
+    max_size: u64,
+    poll_interval: Duration,
+    state_file: PathBuf,
+    /// Where `state_file` really is, so it is not reported when it lies in `directory`.
+    canonical_state_file: Option<PathBuf>,
+    parser: LineParser,
+    /// The files reported so far. `None` until the first scan if there was no saved state.
+    reported: Option<HashMap<PathBuf, Stamp>>,
+    /// Whether files found by the first scan are reported.
+    existing: bool,
+    /// Files waiting to stay the same for an interval.
+    settling: HashMap<PathBuf, Stamp>,
+    /// Files that could not be read, retried every scan but only warned about once.
+    unreadable: HashSet<PathBuf>,
+}
+
+impl FeedPlumberEventSource for WatchSource {
+    type ConfigType = WatchConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        if !config.directory.is_dir() {
+            bail!("\"{}\" is not a directory", config.directory.display());
+        }
+        if config.contents != Contents::Lines && config.lines.is_configured() {
+            bail!(
+                "`format`, `pattern` and `keep_unparsed` are only used with `contents = \"lines\"`"
+            );
+        }
+        let pattern = config
+            .pattern
+            .map(|pattern| Glob::new(&pattern).map(|glob| glob.compile_matcher()))
+            .transpose()
+            .context("Invalid `match`")?;
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("watch-{}.json", config.name)));
+        Ok(Self {
+            directory: config.directory,
+            pattern,
+            recursive: config.recursive,
+            modified: config.modified,
+            contents: config.contents,
+            max_size: config.max_size,
+            poll_interval: Duration::from_millis(config.poll_interval),
+            reported: load_state(&state_file)?,
+            canonical_state_file: canonicalize(&state_file),
+            state_file,
+            parser: LineParser::new(config.lines)?,
+            existing: config.existing,
+            settling: HashMap::new(),
+            unreadable: HashSet::new(),
+        })
+    }
+
+    fn run(&mut self, emitter: &Emitter) -> anyhow::Result<()> {
+        loop {
+            let (items, changed) = self.check()?;
+            if !items.is_empty() && !emitter.emit(items) {
+                // Not saving, so the files are reported again next time
+                return Ok(());
+            }
+            if changed {
+                self.save()?;
+            }
+            if emitter.wait(self.poll_interval) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl WatchSource {
+    /// Scans the directory for files to report. Also returns whether the files reported so far
+    /// changed and need saving.
+    fn check(&mut self) -> anyhow::Result<(Vec<Item>, bool)> {
+        let mut files = Vec::new();
+        self.scan(&self.directory, &mut files)?;
+        let mut reported = self.reported.take().unwrap_or_else(|| {
+            if self.existing {
+                HashMap::new()
+            } else {
+                files.iter().cloned().collect()
+            }
+        });
+
+        let mut changed = false;
+        reported.retain(|path, _| {
+            let found = files.iter().any(|(file, _)| file == path);
+            changed |= !found;
+            found
+        });
+        self.settling
+            .retain(|path, _| files.iter().any(|(file, _)| file == path));
+
+        let mut items = Vec::new();
+        for (path, stamp) in files {
+            let event = match reported.get(&path) {
+                None => "created",
+                Some(reported) if self.modified && *reported != stamp => "modified",
+                Some(_) => continue,
+            };
+            if self.settling.get(&path) != Some(&stamp) {
+                self.settling.insert(path, stamp);
+                continue;
+            }
+            self.settling.remove(&path);
+            match self.items(&path, stamp, event) {
+                Ok(file_items) => {
+                    self.unreadable.remove(&path);
+                    items.extend(file_items);
+                }
+                Err(err) => {
+                    // Skipped rather than failing the source, and tried again next scan
+                    if self.unreadable.insert(path.clone()) {
+                        eprintln!("Warning: watch source skipping a file for now: {err:#}");
+                    }
+                    self.settling.insert(path, stamp);
+                    continue;
+                }
+            }
+            reported.insert(path, stamp);
+            changed = true;
+        }
+        self.unreadable
+            .retain(|path| self.settling.contains_key(path));
+
+        self.reported = Some(reported);
+        Ok((items, changed))
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        match &self.reported {
+            Some(reported) => save_state(&self.state_file, reported),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the files in `directory` matching the pattern.
+    fn scan(&self, directory: &Path, files: &mut Vec<(PathBuf, Stamp)>) -> anyhow::Result<()> {
+        let entries = fs::read_dir(directory)
+            .with_context(|| format!("Reading directory \"{}\"", directory.display()))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("Reading directory \"{}\"", directory.display()))?;
+            let path = entry.path();
+            // Not following symlinks to directories, which could loop
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                if self.recursive {
+                    self.scan(&path, files)?;
+                }
+                continue;
+            }
+            // Files can disappear between listing and reading them
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            if !metadata.is_file() || self.is_state_file(&path) {
+                continue;
+            }
+            let relative = path.strip_prefix(&self.directory).unwrap_or(&path);
+            if self
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| !pattern.is_match(relative))
+            {
+                continue;
+            }
+            let modified = metadata
+                .modified()
+                .ok()
+                .map(DateTime::<Utc>::from)
+                .map_or(0, |modified| modified.timestamp_millis());
+            files.push((
+                path,
+                Stamp {
+                    size: metadata.len(),
+                    modified,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_state_file(&self, path: &Path) -> bool {
+        path.file_name() == self.state_file.file_name()
+            && canonicalize(path).is_some_and(|path| Some(path) == self.canonical_state_file)
+    }
+
+    fn items(&self, path: &Path, stamp: Stamp, event: &str) -> anyhow::Result<Vec<Item>> {
+        let modified = DateTime::<Utc>::from(
+            SystemTime::UNIX_EPOCH + Duration::from_millis(stamp.modified.max(0) as u64),
+        );
+        let details = vec![
+            ("path".to_owned(), path.display().to_string()),
+            (
+                "name".to_owned(),
+                path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            ),
+            ("size".to_owned(), stamp.size.to_string()),
+            ("modified".to_owned(), modified.to_rfc3339()),
+            ("event".to_owned(), event.to_owned()),
+        ];
+        if self.contents == Contents::None || stamp.size > self.max_size {
+            return Ok(vec![details]);
+        }
+        let text = match fs::read(path) {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+            // Gone already, so there is nothing to report
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).with_context(|| format!("Reading \"{}\"", path.display())),
+        };
+        Ok(match self.contents {
+            Contents::Lines => text
+                .lines()
+                .filter_map(|line| self.parser.parse(line))
+                .map(|pairs| details.iter().cloned().chain(pairs).collect())
+                .collect(),
+            _ => {
+                let mut item = details;
+                item.push(("contents".to_owned(), text));
+                vec![item]
+            }
+        })
+    }
+}
+
+/// The absolute path of `path` with symlinks resolved, for a file that may not exist yet.
+fn canonicalize(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(parent).ok()?.join(path.file_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(directory: &Path, state_file: &Path, config: serde_json::Value) -> WatchSource {
+        let mut config = config;
+        config["name"] = "test".into();
+        config["directory"] = directory.display().to_string().into();
+        config["state_file"] = state_file.display().to_string().into();
+        WatchSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    /// The names and events of the files reported by the next scan.
+    fn check(watch: &mut WatchSource) -> Vec<(String, String)> {
+        let (items, _) = watch.check().unwrap();
+        items
+            .into_iter()
+            .map(|item| {
+                let value = |key| item.iter().find(|(k, _)| k == key).unwrap().1.clone();
+                (value("name"), value("event"))
+            })
+            .collect()
+    }
+
+    fn reported(names: &[(&str, &str)]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|(name, event)| (name.to_string(), event.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_files_once_they_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        fs::write(dir.path().join("old.txt"), "old").unwrap();
+
+        let config = serde_json::json!({ "modified": true, "match": "*.txt" });
+        let mut source = watch(dir.path(), &state_file, config);
+        assert_eq!(check(&mut source), []);
+        fs::write(dir.path().join("new.txt"), "new").unwrap();
+        fs::write(dir.path().join("new.csv"), "new").unwrap();
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("new.txt", "created")]));
+        fs::write(dir.path().join("old.txt"), "changed").unwrap();
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("old.txt", "modified")]));
+        assert_eq!(check(&mut source), []);
+    }
+
+    #[test]
+    fn resumes_from_the_saved_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let watched = dir.path().join("watched");
+        let state_file = dir.path().join("state.json");
+        fs::create_dir(&watched).unwrap();
+        fs::write(watched.join("one"), "1").unwrap();
+
+        let mut source = watch(
+            &watched,
+            &state_file,
+            serde_json::json!({ "existing": true }),
+        );
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("one", "created")]));
+        source.save().unwrap();
+        drop(source);
+        fs::write(watched.join("two"), "2").unwrap();
+
+        let mut source = watch(
+            &watched,
+            &state_file,
+            serde_json::json!({ "existing": true }),
+        );
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("two", "created")]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_its_own_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let link = other.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+
+        // The state file is in the watched directory, but named through a symlink
+        let state_file = link.join("state.json");
+        let config = serde_json::json!({ "existing": true });
+        let mut source = watch(dir.path(), &state_file, config);
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("file", "created")]));
+        source.save().unwrap();
+        assert!(dir.path().join("state.json").exists());
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), []);
+    }
+
+    // /proc/self/mem looks like an empty file, but reading it from the start fails, even for root
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn skips_unreadable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let watched = dir.path().join("watched");
+        fs::create_dir(&watched).unwrap();
+        std::os::unix::fs::symlink("/proc/self/mem", watched.join("unreadable")).unwrap();
+        fs::write(watched.join("readable"), "text").unwrap();
+
+        let config = serde_json::json!({ "existing": true, "contents": "whole" });
+        let mut source = watch(&watched, &dir.path().join("state.json"), config);
+        assert_eq!(check(&mut source), []);
+        assert_eq!(check(&mut source), reported(&[("readable", "created")]));
+        assert!(source.unreadable.contains(&watched.join("unreadable")));
+        assert_eq!(check(&mut source), []);
+
+        fs::remove_file(watched.join("unreadable")).unwrap();
+        assert_eq!(check(&mut source), []);
+        assert!(source.unreadable.is_empty());
+    }
+}
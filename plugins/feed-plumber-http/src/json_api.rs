@@ -0,0 +1,403 @@
+//! The `json-api` source fetches JSON from a URL and emits an item per element of an array in it.
+//!
+//! The elements are selected with a JSONPath, and their fields either mapped to item keys with
+//! more JSONPaths or flattened into keys like `user.login`. Paginated APIs are followed through
+//! next links, cursors or page numbers, up to `max_pages` pages per poll.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context};
+use feed_plumber_plugin_rs::{json, FeedPlumberSource};
+use reqwest::{
+    blocking::{Client, Response},
+    header::LINK,
+    Url,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+use crate::{authorize, client, default_timeout, error_body, Auth, TlsConfig};
+
+const DEFAULT_SELECT: &str = "$";
+const DEFAULT_MAX_PAGES: u32 = 10;
+const DEFAULT_START_PAGE: u64 = 1;
+
+#[derive(Deserialize)]
+pub struct JsonApiConfig {
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    auth: Option<Auth>,
+    #[serde(default)]
+    tls: TlsConfig,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// A JSONPath selecting the elements, or an array of them.
+    #[serde(default = "default_select")]
+    select: String,
+    /// Item keys to JSONPaths relative to each element. Elements are flattened if not set.
+    fields: Option<BTreeMap<String, String>>,
+    pagination: Option<Pagination>,
+    /// The most pages fetched per poll.
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+}
+
+#[inline]
+fn default_select() -> String {
+    DEFAULT_SELECT.to_owned()
+}
+
+#[inline]
+const fn default_max_pages() -> u32 {
+    DEFAULT_MAX_PAGES
+}
+
+#[inline]
+const fn default_start_page() -> u64 {
+    DEFAULT_START_PAGE
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+enum Pagination {
+    /// The URL of the next page is at `path` in the response, or else in its `Link` header.
+    NextLink { path: Option<String> },
+    /// The cursor at `path` in the response is sent as the query parameter `param`.
+    Cursor { path: String, param: String },
+    /// Pages are numbered from `start` in the query parameter `param`, until one is empty.
+    Page {
+        param: String,
+        #[serde(default = "default_start_page")]
+        start: u64,
+    },
+}
+
+/// [`Pagination`] with its paths parsed.
+enum Pager {
+    NextLink(Option<JsonPath>),
+    Cursor(JsonPath, String),
+    Page(String, u64),
+}
+
+pub struct JsonApiSource {
+    client: Client,
+    url: Url,
+    auth: Option<Auth>,
+    select: JsonPath,
+    fields: Option<Vec<(String, JsonPath)>>,
+    pager: Option<Pager>,
+    max_pages: u32,
+}
+
+impl FeedPlumberSource for JsonApiSource {
+    type ConfigType = JsonApiConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url).context("`url` property invalid URL")?;
+        let select = JsonPath::parse(&config.select).context("Invalid `select`")?;
+        let fields = config
+            .fields
+            .map(|fields| {
+                fields
+                    .into_iter()
+                    .map(|(key, path)| {
+                        JsonPath::parse(&path)
+                            .map(|path| (key.clone(), path))
+                            .with_context(|| format!("Invalid path of field \"{key}\""))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let pager = config
+            .pagination
+            .map(|pagination| -> anyhow::Result<_> {
+                Ok(match pagination {
+                    Pagination::NextLink { path } => Pager::NextLink(
+                        path.map(|path| JsonPath::parse(&path))
+                            .transpose()
+                            .context("Invalid pagination `path`")?,
+                    ),
+                    Pagination::Cursor { path, param } => Pager::Cursor(
+                        JsonPath::parse(&path).context("Invalid pagination `path`")?,
+                        param,
+                    ),
+                    Pagination::Page { param, start } => Pager::Page(param, start),
+                })
+            })
+            .transpose()?;
+        if config.max_pages == 0 {
+            bail!("`max_pages` must be more than 0");
+        }
+        Ok(JsonApiSource {
+            client: client(&config.headers, &config.tls, config.timeout)?,
+            url,
+            auth: config.auth,
+            select,
+            fields,
+            pager,
+            max_pages: config.max_pages,
+        })
+    }
+
+    fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let mut items = Vec::new();
+        let mut url = match &self.pager {
+            Some(Pager::Page(param, start)) => with_query(&self.url, param, &start.to_string()),
+            _ => self.url.clone(),
+        };
+        for page in 1..=self.max_pages {
+            let res = authorize(self.client.get(url.clone()), &self.auth)
+                .send()
+                .with_context(|| format!("Fetching {url}"))?;
+            if !res.status().is_success() {
+                bail!("Server returned {}: {}", res.status(), error_body(res));
+            }
+            let link = next_link(&res);
+            let body = res
+                .json::<Value>()
+                .with_context(|| format!("Parsing response of {url}"))?;
+
+            let elements = self.elements(&body);
+            let empty = elements.is_empty();
+            items.extend(elements.into_iter().map(|element| self.item(element)));
+
+            let next = match &self.pager {
+                None => None,
+                Some(Pager::NextLink(path)) => {
+                    let next = match path {
+                        Some(path) => path.query(&body).first().and_then(Value::as_str),
+                        None => link.as_deref(),
+                    };
+                    next.filter(|next| !next.is_empty())
+                        .map(|next| url.join(next))
+                        .transpose()
+                        .context("Invalid next link")?
+                        .filter(|next| *next != url)
+                }
+                Some(Pager::Cursor(path, param)) => path
+                    .query(&body)
+                    .first()
+                    .and_then(text)
+                    .filter(|cursor| !cursor.is_empty())
+                    .map(|cursor| with_query(&self.url, param, &cursor)),
+                Some(Pager::Page(param, start)) => (!empty)
+                    .then(|| with_query(&self.url, param, &(start + u64::from(page)).to_string())),
+            };
+            match next {
+                Some(next) => url = next,
+                None => break,
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl JsonApiSource {
+    fn elements<'a>(&self, body: &'a Value) -> Vec<&'a Value> {
+        let nodes = self.select.query(body).all();
+        match nodes[..] {
+            [Value::Array(elements)] => elements.iter().collect(),
+            _ => nodes,
+        }
+    }
+
+    fn item(&self, element: &Value) -> Vec<(String, String)> {
+        match &self.fields {
+            Some(fields) => fields
+                .iter()
+                .filter_map(|(key, path)| {
+                    let value = path.query(element).first().and_then(text)?;
+                    Some((key.clone(), value))
+                })
+                .collect(),
+            None => json::flatten(element),
+        }
+    }
+}
+
+/// The text of a value: strings as they are, other values as JSON, nothing for `null`.
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        _ => Some(value.to_string()),
+    }
+}
+
+/// `url` with the query parameter `param` set to `value`.
+fn with_query(url: &Url, param: &str, value: &str) -> Url {
+    let pairs = url
+        .query_pairs()
+        .filter(|(name, _)| name != param)
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(param, value);
+    url
+}
+
+/// The `rel="next"` URL of a `Link` header.
+fn next_link(res: &Response) -> Option<String> {
+    let header = res.headers().get(LINK)?.to_str().ok()?;
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let is_next = params.split(';').any(|param| {
+            param
+                .trim()
+                .strip_prefix("rel=")
+                .is_some_and(|rel| rel.trim_matches('"').split(' ').any(|rel| rel == "next"))
+        });
+        is_next.then(|| {
+            target
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_owned()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::serve;
+
+    fn source(url: &str, config: Value) -> JsonApiSource {
+        let mut config = config;
+        config["url"] = url.into();
+        JsonApiSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    /// Polls a source getting the given pages, returning the ids emitted and the targets
+    /// requested.
+    fn poll(config: Value, pages: Vec<(&'static str, Value)>) -> (Vec<String>, Vec<String>) {
+        let pages = pages
+            .into_iter()
+            .map(|(headers, body)| (200, headers, body.to_string()))
+            .collect();
+        let (url, server) = serve(pages);
+        let items = source(&format!("{url}/api?since=0"), config)
+            .poll_source()
+            .unwrap();
+        let ids = items
+            .into_iter()
+            .map(|item| item.into_iter().find(|(key, _)| key == "id").unwrap().1)
+            .collect();
+        let targets = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|(target, _, _)| target)
+            .collect();
+        (ids, targets)
+    }
+
+    #[test]
+    fn follows_next_links_in_the_body() {
+        let config = json!({
+            "select": "$.items",
+            "pagination": { "type": "next-link", "path": "$.next" },
+        });
+        let pages = vec![
+            (
+                "",
+                json!({ "items": [{ "id": 1 }, { "id": 2 }], "next": "/api?page=2" }),
+            ),
+            ("", json!({ "items": [{ "id": 3 }], "next": null })),
+        ];
+        assert_eq!(
+            poll(config, pages),
+            (
+                vec!["1".to_owned(), "2".to_owned(), "3".to_owned()],
+                vec!["/api?since=0".to_owned(), "/api?page=2".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn follows_link_headers() {
+        let config = json!({ "pagination": { "type": "next-link" } });
+        let pages = vec![
+            (
+                "Link: </api?page=1>; rel=\"prev\", </api?page=3>; rel=\"next\"\r\n",
+                json!([{ "id": 1 }]),
+            ),
+            ("", json!([{ "id": 2 }])),
+        ];
+        let (ids, targets) = poll(config, pages);
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(targets, ["/api?since=0", "/api?page=3"]);
+    }
+
+    #[test]
+    fn sends_cursors() {
+        let config = json!({
+            "select": "$.data[*]",
+            "pagination": { "type": "cursor", "path": "$.meta.cursor", "param": "after" },
+        });
+        let pages = vec![
+            (
+                "",
+                json!({ "data": [{ "id": 1 }], "meta": { "cursor": "c 1" } }),
+            ),
+            (
+                "",
+                json!({ "data": [{ "id": 2 }], "meta": { "cursor": "" } }),
+            ),
+        ];
+        let (ids, targets) = poll(config, pages);
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(targets, ["/api?since=0", "/api?since=0&after=c+1"]);
+    }
+
+    #[test]
+    fn counts_pages_until_one_is_empty() {
+        let config = json!({ "pagination": { "type": "page", "param": "since", "start": 0 } });
+        let pages = vec![
+            ("", json!([{ "id": 1 }])),
+            ("", json!([{ "id": 2 }])),
+            ("", json!([])),
+        ];
+        let (ids, targets) = poll(config, pages);
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(targets, ["/api?since=0", "/api?since=1", "/api?since=2"]);
+    }
+
+    #[test]
+    fn stops_at_max_pages() {
+        let config = json!({ "pagination": { "type": "page", "param": "p" }, "max_pages": 2 });
+        let pages = vec![("", json!([{ "id": 1 }])), ("", json!([{ "id": 2 }]))];
+        let (ids, targets) = poll(config, pages);
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(targets, ["/api?since=0&p=1", "/api?since=0&p=2"]);
+    }
+
+    #[test]
+    fn maps_fields() {
+        let config = json!({
+            "fields": { "id": "$.id", "author": "$.user.login", "missing": "$.nothing" },
+        });
+        let (url, server) = serve(vec![(
+            200,
+            "",
+            json!([{ "id": 7, "user": { "login": "ferris" } }]).to_string(),
+        )]);
+        let items = source(&url, config).poll_source().unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            items,
+            [[
+                ("author".to_owned(), "ferris".to_owned()),
+                ("id".to_owned(), "7".to_owned()),
+            ]]
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, thread::sleep, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use feed_plumber_plugin_rs::{feed_plumber_plugin, template::json, FeedPlumberSink};
+use minijinja::{AutoEscape, Environment};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    Certificate, Method, StatusCode, Url,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{json_api::JsonApiSource, scrape::ScrapeSource};
+
+mod json_api;
+mod scrape;
+
+feed_plumber_plugin! {
+    sources: "json-api" => JsonApiSource, "scrape" => ScrapeSource;
+    sinks: "http" => HttpSink;
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: u64 = 1000;
+/// The longest the doubling retry delay grows to.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+const DEFAULT_TIMEOUT: u64 = 10_000;
+const DEFAULT_RETRY_STATUS: [u16; 5] = [429, 500, 502, 503, 504];
+const DEFAULT_TEMPLATE_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+/// How much of an error response's body is reported.
+const ERROR_BODY_LIMIT: usize = 500;
+
+#[derive(Deserialize)]
+struct HttpConfig {
+    url: String,
+    #[serde(default)]
+    method: HttpMethod,
+    #[serde(default)]
+    format: BodyFormat,
+    /// The body of each request with `format = "template"`, a minijinja template.
+    body: Option<String>,
+    /// The `Content-Type` of templated bodies.
+    content_type: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    auth: Option<Auth>,
+    #[serde(default)]
+    tls: TlsConfig,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Statuses counting as delivered. Any 2xx if not set.
+    success_status: Option<Vec<u16>>,
+    /// Statuses that are retried, along with failed connections.
+    #[serde(default = "default_retry_status")]
+    retry_status: Vec<u16>,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    /// Delay before the first retry in milliseconds, doubling for each retry after up to a minute.
+    /// A `Retry-After` header takes precedence.
+    #[serde(default = "default_retry_delay")]
+    retry_delay: u64,
+}
+
+#[inline]
+const fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+#[inline]
+fn default_retry_status() -> Vec<u16> {
+    DEFAULT_RETRY_STATUS.to_vec()
+}
+
+#[inline]
+const fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+#[inline]
+const fn default_retry_delay() -> u64 {
+    DEFAULT_RETRY_DELAY
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+enum HttpMethod {
+    #[default]
+    #[serde(alias = "post")]
+    Post,
+    #[serde(alias = "put")]
+    Put,
+    #[serde(alias = "patch")]
+    Patch,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum BodyFormat {
+    /// A JSON object per item, one request each.
+    #[default]
+    Json,
+    /// A JSON array of objects per batch, in one request.
+    JsonArray,
+    /// A form-encoded body per item, one request each.
+    Form,
+    /// The rendered `body` per item, one request each.
+    Template,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum Auth {
+    Bearer {
+        token: String,
+    },
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct TlsConfig {
+    /// Trusts any certificate. Only for testing.
+    #[serde(default)]
+    accept_invalid_certs: bool,
+    /// A PEM file with extra root certificates, e.g. of an internal CA.
+    ca_certificate: Option<PathBuf>,
+}
+
+/// Sends items to a URL, as JSON, form-encoded or templated bodies.
+struct HttpSink {
+    client: Client,
+    url: Url,
+    method: Method,
+    format: BodyFormat,
+    template: Option<Environment<'static>>,
+    content_type: HeaderValue,
+    auth: Option<Auth>,
+    success_status: Option<Vec<u16>>,
+    retry_status: Vec<u16>,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl FeedPlumberSink for HttpSink {
+    type ConfigType = HttpConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url).context("`url` property invalid URL")?;
+        let client = client(&config.headers, &config.tls, config.timeout)?;
+
+        let template = match (config.format, config.body) {
+            (BodyFormat::Template, Some(body)) => {
+                let mut env = Environment::new();
+                env.set_auto_escape_callback(|_| AutoEscape::None);
+                env.add_filter("json", json);
+                env.add_template_owned("body", body)
+                    .context("Invalid `body` template")?;
+                Some(env)
+            }
+            (BodyFormat::Template, None) => bail!("`format = \"template\"` needs a `body`"),
+            (_, Some(_)) => bail!("`body` is only used with `format = \"template\"`"),
+            (_, None) => None,
+        };
+        let content_type = HeaderValue::try_from(
+            config
+                .content_type
+                .as_deref()
+                .unwrap_or(DEFAULT_TEMPLATE_CONTENT_TYPE),
+        )
+        .context("Invalid `content_type`")?;
+
+        Ok(HttpSink {
+            client,
+            url,
+            method: match config.method {
+                HttpMethod::Post => Method::POST,
+                HttpMethod::Put => Method::PUT,
+                HttpMethod::Patch => Method::PATCH,
+            },
+            format: config.format,
+            template,
+            content_type,
+            auth: config.auth,
+            success_status: config.success_status,
+            retry_status: config.retry_status,
+            max_retries: config.max_retries,
+            retry_delay: Duration::from_millis(config.retry_delay),
+        })
+    }
+
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()> {
+        if self.format == BodyFormat::JsonArray {
+            let array = items.iter().map(|item| object(item)).collect::<Vec<_>>();
+            return self.send(|request| request.json(&array));
+        }
+        let total = items.len();
+        let mut failed = Vec::new();
+        for item in &items {
+            let res = match self.format {
+                BodyFormat::Json => self.send(|request| request.json(&object(item))),
+                BodyFormat::Form => self.send(|request| request.form(item)),
+                BodyFormat::Template => self.render(item).and_then(|body| {
+                    self.send(|request| {
+                        request
+                            .header(CONTENT_TYPE, self.content_type.clone())
+                            .body(body.clone())
+                    })
+                }),
+                BodyFormat::JsonArray => unreachable!(),
+            };
+            if let Err(err) = res {
+                failed.push(format!("{err:#}"));
+            }
+        }
+        match failed.first() {
+            None => Ok(()),
+            Some(first) => Err(anyhow!(
+                "{} of {total} requests failed, first: {first}",
+                failed.len()
+            )),
+        }
+    }
+}
+
+impl HttpSink {
+    fn render(&self, item: &[(&str, &str)]) -> anyhow::Result<String> {
+        let env = self
+            .template
+            .as_ref()
+            .ok_or(anyhow!("No `body` template"))?;
+        let context = item
+            .iter()
+            .rev()
+            .map(|(key, value)| (*key, *value))
+            .collect::<BTreeMap<_, _>>();
+        env.get_template("body")
+            .and_then(|template| template.render(&context))
+            .context("Rendering `body`")
+    }
+
+    /// Sends a request with the body set by `body`, retrying as configured.
+    fn send(&self, body: impl Fn(RequestBuilder) -> RequestBuilder) -> anyhow::Result<()> {
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+        loop {
+            let request = authorize(
+                self.client.request(self.method.clone(), self.url.clone()),
+                &self.auth,
+            );
+            let (retry, err) = match body(request).send() {
+                Ok(res) if self.succeeded(res.status()) => return Ok(()),
+                Ok(res) => {
+                    let retry = self.retry_status.contains(&res.status().as_u16());
+                    let wait = retry_after(&res);
+                    let err = anyhow!("Server returned {}: {}", res.status(), error_body(res));
+                    if let Some(wait) = wait {
+                        delay = wait;
+                    }
+                    (retry, err)
+                }
+                Err(err) => (
+                    err.is_connect() || err.is_timeout(),
+                    anyhow!(err).context("Sending request"),
+                ),
+            };
+            if !retry || retries >= self.max_retries {
+                return Err(err);
+            }
+            retries += 1;
+            sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    fn succeeded(&self, status: StatusCode) -> bool {
+        match &self.success_status {
+            Some(success) => success.contains(&status.as_u16()),
+            None => status.is_success(),
+        }
+    }
+}
+
+/// A client sending `headers` with every request.
+fn client(
+    headers: &BTreeMap<String, String>,
+    tls: &TlsConfig,
+    timeout: u64,
+) -> anyhow::Result<Client> {
+    let mut default_headers = HeaderMap::new();
+    for (name, value) in headers {
+        default_headers.insert(
+            HeaderName::try_from(name).with_context(|| format!("Invalid header \"{name}\""))?,
+            HeaderValue::try_from(value)
+                .with_context(|| format!("Invalid value of header \"{name}\""))?,
+        );
+    }
+    let mut client = Client::builder()
+        .default_headers(default_headers)
+        .timeout(Duration::from_millis(timeout))
+        .danger_accept_invalid_certs(tls.accept_invalid_certs);
+    if let Some(path) = &tls.ca_certificate {
+        let pem = fs::read(path)
+            .with_context(|| format!("Reading CA certificate \"{}\"", path.display()))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Parsing CA certificate \"{}\"", path.display()))?;
+        for certificate in certificates {
+            client = client.add_root_certificate(certificate);
+        }
+    }
+    client.build().context("Creating HTTP client")
+}
+
+fn authorize(request: RequestBuilder, auth: &Option<Auth>) -> RequestBuilder {
+    match auth {
+        Some(Auth::Bearer { token }) => request.bearer_auth(token),
+        Some(Auth::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+        None => request,
+    }
+}
+
+/// The item as a JSON object. The first of repeated keys wins, like in `body` templates.
+fn object(item: &[(&str, &str)]) -> Map<String, Value> {
+    let mut object = Map::new();
+    for (key, value) in item {
+        object
+            .entry(*key)
+            .or_insert_with(|| Value::String((*value).to_owned()));
+    }
+    object
+}
+
+/// The wait asked for by a `Retry-After` header in seconds.
+fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+        .map(|secs| Duration::from_secs_f64(secs.clamp(0.0, 3600.0)))
+}
+
+fn error_body(res: Response) -> String {
+    let body = res.text().unwrap_or_default();
+    if body.chars().count() <= ERROR_BODY_LIMIT {
+        return body;
+    }
+    body.chars().take(ERROR_BODY_LIMIT).chain(['…']).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A request's target, `Content-Type` and body.
+    pub(crate) type Received = (String, String, String);
+
+    /// A server answering the requests it gets, one connection each, with the given statuses,
+    /// extra header lines and bodies. Returns its URL and the requests.
+    pub(crate) fn serve(
+        responses: Vec<(u16, &'static str, String)>,
+    ) -> (String, JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, headers, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let target = request_line.split(' ').nth(1).unwrap().to_owned();
+                let mut length = 0;
+                let mut content_type = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        } else if name.eq_ignore_ascii_case("content-type") {
+                            content_type = value.trim().to_owned();
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                reader.read_exact(&mut request).unwrap();
+                requests.push((target, content_type, String::from_utf8(request).unwrap()));
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status} Stub\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    /// [`serve`] for the sink, returning the `Content-Type` and body of each request.
+    fn stub(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<(String, String)>>) {
+        let responses = responses
+            .into_iter()
+            .map(|(status, body)| (status, "", body.to_owned()))
+            .collect();
+        let (url, server) = serve(responses);
+        let handle = thread::spawn(move || {
+            let requests = server.join().unwrap();
+            requests
+                .into_iter()
+                .map(|(_, content_type, body)| (content_type, body))
+                .collect()
+        });
+        (format!("{url}/hook"), handle)
+    }
+
+    fn sink(url: &str, config: Value) -> HttpSink {
+        let mut config = config;
+        config["url"] = url.into();
+        config["retry_delay"] = 10.into();
+        HttpSink::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn retries_unavailable_servers() {
+        let (url, stub) = stub(vec![(503, "Down for maintenance"), (200, "")]);
+        sink(&url, json!({}))
+            .sink_items(vec![vec![("title", "Hello"), ("title", "Again")]])
+            .unwrap();
+        let requests = stub.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], requests[1]);
+        assert_eq!(requests[1].0, "application/json");
+        let payload: Value = serde_json::from_str(&requests[1].1).unwrap();
+        assert_eq!(payload, json!({ "title": "Hello" }));
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (url, stub) = stub(vec![(503, "Down"), (503, "Still down")]);
+        let err = sink(&url, json!({ "max_retries": 1 }))
+            .sink_items(vec![vec![("title", "Hello")]])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("503 Service Unavailable: Still down"));
+        assert_eq!(stub.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fails_statuses_not_counting_as_delivered() {
+        let (url, stub) = stub(vec![(200, ""), (202, ""), (400, "Bad item")]);
+        let items = vec![vec![("n", "1")], vec![("n", "2")], vec![("n", "3")]];
+        let err = sink(&url, json!({ "success_status": [202] }))
+            .sink_items(items)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 of 3 requests failed, first: Server returned 200 OK: "
+        );
+        // Neither is retried
+        assert_eq!(stub.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn sends_batches_as_json_arrays() {
+        let (url, stub) = stub(vec![(204, "")]);
+        let items = vec![vec![("n", "1"), ("n", "one")], vec![("n", "2")]];
+        sink(&url, json!({ "format": "json-array" }))
+            .sink_items(items)
+            .unwrap();
+        let requests = stub.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "application/json");
+        let payload: Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(payload, json!([{ "n": "1" }, { "n": "2" }]));
+    }
+
+    #[test]
+    fn sends_forms() {
+        let (url, stub) = stub(vec![(200, "")]);
+        sink(&url, json!({ "format": "form" }))
+            .sink_items(vec![vec![("tag", "a b"), ("tag", "c&d")]])
+            .unwrap();
+        let requests = stub.join().unwrap();
+        assert_eq!(
+            requests,
+            [(
+                "application/x-www-form-urlencoded".to_owned(),
+                "tag=a+b&tag=c%26d".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn sends_templated_bodies() {
+        let (url, stub) = stub(vec![(200, "")]);
+        let config = json!({ "format": "template", "body": "{{ title }}: {{ link | json }}" });
+        sink(&url, config)
+            .sink_items(vec![vec![
+                ("title", "Hello"),
+                ("title", "Again"),
+                ("link", "https://example.com/\"quoted\""),
+            ]])
+            .unwrap();
+        let requests = stub.join().unwrap();
+        assert_eq!(
+            requests,
+            [(
+                DEFAULT_TEMPLATE_CONTENT_TYPE.to_owned(),
+                r#"Hello: "https://example.com/\"quoted\"""#.to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! The `scrape` source turns a listing page of a site without a feed into items.
+//!
+//! Every poll fetches the page and emits an item per element matching the `items` selector, with
+//! `fields` taken from the text or attributes of elements inside it. `href` and `src` attributes
+//! are resolved against the page's URL. Items are recognized by their `link`: the links emitted so
+//! far are saved to `state_file`, and forgotten once they are no longer on the page.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Context};
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    FeedPlumberSource,
+};
+use reqwest::{blocking::Client, Url};
+use scraper::{ElementRef, Html, Selector};
+use serde::Deserialize;
+
+use crate::{authorize, client, default_timeout, error_body, Auth, TlsConfig};
+
+/// The field items are recognized by.
+const LINK_KEY: &str = "link";
+/// The key the page's title is emitted under, as the `feed` source does.
+const PAGE_TITLE_KEY: &str = "feed_title";
+
+#[derive(Deserialize)]
+pub struct ScrapeConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    auth: Option<Auth>,
+    #[serde(default)]
+    tls: TlsConfig,
+    /// In milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// A CSS selector matching an element per item.
+    items: String,
+    /// Item keys to what is taken from each item's element. Needs a `link`.
+    fields: BTreeMap<String, FieldConfig>,
+    /// Whether the items already on the page when the source first polls are emitted.
+    #[serde(default)]
+    existing: bool,
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FieldConfig {
+    /// The text of the first element matching a CSS selector.
+    Text(String),
+    /// An attribute or the text of the first element matching `selector`, or of the item's
+    /// element itself without one.
+    Element {
+        selector: Option<String>,
+        attr: Option<String>,
+    },
+}
+
+/// [`FieldConfig`] with its selector parsed.
+struct Field {
+    key: String,
+    selector: Option<Selector>,
+    attr: Option<String>,
+}
+
+pub struct ScrapeSource {
+    client: Client,
+    url: Url,
+    auth: Option<Auth>,
+    items: Selector,
+    fields: Vec<Field>,
+    existing: bool,
+    state_file: PathBuf,
+    /// The links emitted so far. `None` until the first poll if there was no saved state.
+    seen: Option<HashSet<String>>,
+}
+
+impl FeedPlumberSource for ScrapeSource {
+    type ConfigType = ScrapeConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url).context("`url` property invalid URL")?;
+        let items = parse_selector(&config.items).context("Invalid `items` selector")?;
+        if !config.fields.contains_key(LINK_KEY) {
+            bail!("`fields` has no `{LINK_KEY}`, which items are recognized by");
+        }
+        let fields = config
+            .fields
+            .into_iter()
+            .map(|(key, field)| {
+                let (selector, attr) = match field {
+                    FieldConfig::Text(selector) => (Some(selector), None),
+                    FieldConfig::Element { selector, attr } => (selector, attr),
+                };
+                let selector = selector
+                    .as_deref()
+                    .map(parse_selector)
+                    .transpose()
+                    .with_context(|| format!("Invalid selector of field \"{key}\""))?;
+                Ok(Field {
+                    key,
+                    selector,
+                    attr,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("scrape-{}.json", config.name)));
+        Ok(ScrapeSource {
+            client: client(&config.headers, &config.tls, config.timeout)?,
+            url,
+            auth: config.auth,
+            items,
+            fields,
+            existing: config.existing,
+            seen: load_state(&state_file)?,
+            state_file,
+        })
+    }
+
+    fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let res = authorize(self.client.get(self.url.clone()), &self.auth)
+            .send()
+            .with_context(|| format!("Fetching {}", self.url))?;
+        if !res.status().is_success() {
+            bail!("Server returned {}: {}", res.status(), error_body(res));
+        }
+        // Redirects change what relative URLs are relative to
+        let url = res.url().clone();
+        let page = Html::parse_document(
+            &res.text()
+                .with_context(|| format!("Reading response of {url}"))?,
+        );
+        let base = page
+            .select(&Selector::parse("base[href]").unwrap())
+            .next()
+            .and_then(|base| url.join(base.value().attr("href")?).ok())
+            .unwrap_or(url);
+        let title = page
+            .select(&Selector::parse("title").unwrap())
+            .next()
+            .map(text)
+            .filter(|title| !title.is_empty());
+
+        let mut items = Vec::new();
+        let mut links = HashSet::new();
+        let mut matched = false;
+        for element in page.select(&self.items) {
+            matched = true;
+            let item = self.item(element, &base);
+            // Items without a link can't be told apart, and the same link is emitted once
+            match item.iter().find(|(key, _)| key == LINK_KEY) {
+                Some((_, link)) if links.insert(link.clone()) => items.push(item),
+                _ => {}
+            }
+        }
+        // Most likely the site changed, rather than emptied the listing
+        if !matched {
+            bail!("No elements on {base} match `items`");
+        }
+
+        let mut seen = self.seen.take().unwrap_or_else(|| {
+            if self.existing {
+                HashSet::new()
+            } else {
+                links.clone()
+            }
+        });
+        let before = seen.len();
+        seen.retain(|link| links.contains(link));
+        let mut changed = seen.len() != before;
+        items.retain(|item| {
+            let link = item.iter().find(|(key, _)| key == LINK_KEY);
+            link.is_some_and(|(_, link)| seen.insert(link.clone()))
+        });
+        changed |= !items.is_empty();
+        if changed {
+            save_state(&self.state_file, &seen)?;
+        }
+        self.seen = Some(seen);
+
+        if let Some(title) = title {
+            for item in &mut items {
+                item.push((PAGE_TITLE_KEY.to_owned(), title.clone()));
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl ScrapeSource {
+    fn item(&self, element: ElementRef, base: &Url) -> Vec<(String, String)> {
+        let mut item = Vec::new();
+        for field in &self.fields {
+            let element = match &field.selector {
+                Some(selector) => element.select(selector).next(),
+                None => Some(element),
+            };
+            let value = element.and_then(|element| match field.attr.as_deref() {
+                Some(attr @ ("href" | "src")) => {
+                    let value = element.value().attr(attr)?.trim();
+                    Some(base.join(value).map_or(value.to_owned(), String::from))
+                }
+                Some(attr) => element
+                    .value()
+                    .attr(attr)
+                    .map(|value| value.trim().to_owned()),
+                None => Some(text(element)),
+            });
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                item.push((field.key.clone(), value));
+            }
+        }
+        item
+    }
+}
+
+fn parse_selector(selector: &str) -> anyhow::Result<Selector> {
+    Selector::parse(selector).map_err(|err| anyhow!("{err}"))
+}
+
+/// The text of an element, with runs of whitespace collapsed into single spaces.
+fn text(element: ElementRef) -> String {
+    element
+        .text()
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::serve;
+
+    type Item = Vec<(String, String)>;
+
+    /// A listing page with an item per link, and one without a link.
+    fn page(links: &[&str]) -> String {
+        let items = links
+            .iter()
+            .map(|link| format!("<li><a href=\"{link}\">  Post\n {link} </a></li>"))
+            .collect::<String>();
+        format!("<html><head><title>News</title></head><body><ul>{items}<li>No link</li></ul></body></html>")
+    }
+
+    /// Polls a source once per page, returning the items of each poll and the pages' URL.
+    fn poll(existing: bool, pages: &[&[&str]]) -> (Vec<Vec<Item>>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let responses = pages.iter().map(|links| (200, "", page(links))).collect();
+        let (url, server) = serve(responses);
+        let config = json!({
+            "name": "test",
+            "url": format!("{url}/news/"),
+            "items": "li",
+            "fields": { "title": "a", "link": { "selector": "a", "attr": "href" } },
+            "existing": existing,
+            "state_file": dir.path().join("state.json"),
+        });
+        let mut source = ScrapeSource::new(serde_json::from_value(config).unwrap()).unwrap();
+        let polls = pages
+            .iter()
+            .map(|_| source.poll_source().unwrap())
+            .collect();
+        server.join().unwrap();
+        (polls, format!("{url}/news/"))
+    }
+
+    /// The links of the items of each poll, relative to the page.
+    fn links(polls: &[Vec<Item>], url: &str) -> Vec<Vec<String>> {
+        polls
+            .iter()
+            .map(|items| {
+                items
+                    .iter()
+                    .map(|item| item[0].1.strip_prefix(url).unwrap().to_owned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn emits_each_link_once() {
+        let (polls, url) = poll(
+            false,
+            &[
+                &["1", "2"],
+                &["3", "1", "3", "2"],
+                &["3", "2"],
+                &["1", "3", "2"],
+            ],
+        );
+        assert_eq!(
+            polls[1],
+            [[
+                ("link".to_owned(), format!("{url}3")),
+                ("title".to_owned(), "Post 3".to_owned()),
+                ("feed_title".to_owned(), "News".to_owned()),
+            ]]
+        );
+        // Gone from the page in between, so it is new again
+        assert_eq!(links(&polls, &url), [vec![], vec!["3"], vec![], vec!["1"]]);
+    }
+
+    #[test]
+    fn emits_existing_links_once_per_page() {
+        let (polls, url) = poll(true, &[&["1", "/news/1", "2", "1"], &["2", "1"]]);
+        assert_eq!(links(&polls, &url), [vec!["1", "2"], vec![]]);
+    }
+}
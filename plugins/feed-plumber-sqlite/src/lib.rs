@@ -0,0 +1,30 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use feed_plumber_plugin_rs::feed_plumber_plugin;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{sink::SqliteSink, source::SqliteSource};
+
+mod sink;
+mod source;
+
+feed_plumber_plugin! {
+    sources: "sqlite" => SqliteSource;
+    sinks: "sqlite" => SqliteSink;
+}
+
+/// How long to wait for other connections to release their locks on the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn open(database: &Path, flags: OpenFlags) -> anyhow::Result<Connection> {
+    let connection = Connection::open_with_flags(database, flags)
+        .with_context(|| format!("Opening database \"{}\"", database.display()))?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(connection)
+}
+
+/// Quotes a table or column name for use in SQL.
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
@@ -0,0 +1,342 @@
+//! The `sqlite` sink writes an item per row of a table.
+//!
+//! The table and any columns it lacks are created as items need them, as `TEXT` columns. With a
+//! `key`, a row with the same value in the key's column is updated rather than another inserted.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+use feed_plumber_plugin_rs::FeedPlumberSink;
+use rusqlite::{params_from_iter, Connection, OpenFlags, Transaction};
+use serde::Deserialize;
+
+use crate::{open, quote};
+
+#[derive(Deserialize)]
+pub struct SqliteSinkConfig {
+    database: PathBuf,
+    table: String,
+    /// Item keys to the columns they go into. Every key goes into the column of the same name if
+    /// not set.
+    columns: Option<BTreeMap<String, String>>,
+    /// The item key rows are upserted on. It must be one of `columns`, if those are set.
+    key: Option<String>,
+}
+
+pub struct SqliteSink {
+    connection: Connection,
+    table: String,
+    columns: Option<BTreeMap<String, String>>,
+    /// The column rows are upserted on.
+    key: Option<String>,
+    /// The columns of the table, empty until it exists.
+    existing: HashSet<String>,
+}
+
+impl FeedPlumberSink for SqliteSink {
+    type ConfigType = SqliteSinkConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let connection = open(&config.database, OpenFlags::default())?;
+        let key = match (config.key, &config.columns) {
+            (Some(key), Some(columns)) => Some(
+                columns
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("`key` \"{key}\" is not one of `columns`"))?,
+            ),
+            (key, _) => key,
+        };
+        let mut sink = SqliteSink {
+            connection,
+            table: config.table,
+            columns: config.columns,
+            key,
+            existing: HashSet::new(),
+        };
+        sink.existing = table_columns(&sink.connection, &sink.table)?;
+        // Creating what can be created up front, so mistakes show up right away
+        let mut columns = Vec::new();
+        for column in sink
+            .columns
+            .iter()
+            .flat_map(|columns| columns.values())
+            .chain(&sink.key)
+        {
+            if !columns.contains(column) {
+                columns.push(column.clone());
+            }
+        }
+        let tx = sink.connection.transaction()?;
+        add_columns(&tx, &sink.table, &mut sink.existing, &columns)?;
+        if let Some(key) = &sink.key {
+            // Upserts need a unique index on the key column
+            tx.execute(
+                &format!(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})",
+                    quote(&format!("{}_{key}_key", sink.table)),
+                    quote(&sink.table),
+                    quote(key)
+                ),
+                [],
+            )
+            .with_context(|| format!("Creating unique index on \"{key}\""))?;
+        }
+        tx.commit().context("Creating table")?;
+        Ok(sink)
+    }
+
+    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) -> anyhow::Result<()> {
+        let total = items.len();
+        let mut failed = Vec::new();
+        let tx = self.connection.transaction()?;
+        for item in &items {
+            // The first of duplicate keys wins
+            let mut row: Vec<(String, &str)> = Vec::new();
+            for (key, value) in item {
+                let column = match &self.columns {
+                    Some(columns) => match columns.get(*key) {
+                        Some(column) => column.clone(),
+                        None => continue,
+                    },
+                    None => (*key).to_owned(),
+                };
+                if !row.iter().any(|(existing, _)| *existing == column) {
+                    row.push((column, value));
+                }
+            }
+            if row.is_empty() {
+                continue;
+            }
+            if let Err(err) = write_row(&tx, &self.table, &mut self.existing, &self.key, &row) {
+                failed.push(format!("{err:#}"));
+            }
+        }
+        if let Err(err) = tx.commit() {
+            // Columns added in the transaction are gone again
+            self.existing = table_columns(&self.connection, &self.table)?;
+            return Err(err).context("Committing items");
+        }
+        match failed.first() {
+            None => Ok(()),
+            Some(first) => Err(anyhow!(
+                "{} of {total} items failed, first: {first}",
+                failed.len()
+            )),
+        }
+    }
+}
+
+fn write_row(
+    tx: &Transaction,
+    table: &str,
+    existing: &mut HashSet<String>,
+    key: &Option<String>,
+    row: &[(String, &str)],
+) -> anyhow::Result<()> {
+    if let Some(key) = key {
+        if !row.iter().any(|(column, _)| column == key) {
+            return Err(anyhow!("No value for key column \"{key}\""));
+        }
+    }
+    let columns = row
+        .iter()
+        .map(|(column, _)| column.clone())
+        .collect::<Vec<_>>();
+    add_columns(tx, table, existing, &columns)?;
+
+    let names = columns
+        .iter()
+        .map(|column| quote(column))
+        .collect::<Vec<_>>();
+    let mut sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote(table),
+        names.join(", "),
+        vec!["?"; names.len()].join(", ")
+    );
+    if let Some(key) = key {
+        let updates = names
+            .iter()
+            .filter(|name| **name != quote(key))
+            .map(|name| format!("{name} = excluded.{name}"))
+            .collect::<Vec<_>>();
+        sql += &format!(" ON CONFLICT ({}) DO ", quote(key));
+        if updates.is_empty() {
+            sql += "NOTHING";
+        } else {
+            sql += &format!("UPDATE SET {}", updates.join(", "));
+        }
+    }
+    tx.prepare_cached(&sql)
+        .and_then(|mut statement| {
+            statement.execute(params_from_iter(row.iter().map(|(_, value)| value)))
+        })
+        .context("Writing item")?;
+    Ok(())
+}
+
+/// Creates the table, or adds the columns it lacks.
+fn add_columns(
+    tx: &Transaction,
+    table: &str,
+    existing: &mut HashSet<String>,
+    columns: &[String],
+) -> anyhow::Result<()> {
+    let missing = columns
+        .iter()
+        .filter(|column| !existing.contains(*column))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if existing.is_empty() {
+        let definitions = missing
+            .iter()
+            .map(|column| format!("{} TEXT", quote(column)))
+            .collect::<Vec<_>>();
+        tx.execute(
+            &format!("CREATE TABLE {} ({})", quote(table), definitions.join(", ")),
+            [],
+        )
+        .with_context(|| format!("Creating table \"{table}\""))?;
+    } else {
+        for column in &missing {
+            tx.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN {} TEXT",
+                    quote(table),
+                    quote(column)
+                ),
+                [],
+            )
+            .with_context(|| format!("Adding column \"{column}\" to \"{table}\""))?;
+        }
+    }
+    existing.extend(missing.into_iter().cloned());
+    Ok(())
+}
+
+fn table_columns(connection: &Connection, table: &str) -> anyhow::Result<HashSet<String>> {
+    connection
+        .prepare("SELECT name FROM pragma_table_info(?1)")
+        .and_then(|mut statement| {
+            statement
+                .query_map([table], |row| row.get(0))?
+                .collect::<Result<HashSet<String>, _>>()
+        })
+        .with_context(|| format!("Reading columns of \"{table}\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn sink(database: &Path, config: serde_json::Value) -> anyhow::Result<SqliteSink> {
+        let mut config = config;
+        config["database"] = database.display().to_string().into();
+        config["table"] = "items".into();
+        SqliteSink::new(serde_json::from_value(config).unwrap())
+    }
+
+    /// The table's rows, ordered by their first column, with NULLs as empty strings.
+    fn rows(database: &Path) -> Vec<Vec<String>> {
+        let connection = Connection::open(database).unwrap();
+        let mut statement = connection
+            .prepare("SELECT * FROM items ORDER BY 1")
+            .unwrap();
+        let columns = statement.column_count();
+        let rows = statement
+            .query_map([], |row| {
+                (0..columns)
+                    .map(|idx| Ok(row.get::<_, Option<String>>(idx)?.unwrap_or_default()))
+                    .collect()
+            })
+            .unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn upserts_on_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("items.db");
+        let mut sink = sink(&database, serde_json::json!({ "key": "id" })).unwrap();
+        sink.sink_items(vec![
+            vec![("id", "1"), ("title", "One")],
+            vec![("id", "2"), ("title", "Two")],
+        ])
+        .unwrap();
+        sink.sink_items(vec![vec![("id", "1"), ("title", "Uno"), ("title", "Ein")]])
+            .unwrap();
+        assert_eq!(rows(&database), [["1", "Uno"], ["2", "Two"]]);
+
+        let err = sink
+            .sink_items(vec![vec![("title", "None")], vec![("id", "3")]])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 of 2 items failed, first: No value for key column \"id\""
+        );
+        assert_eq!(rows(&database), [["1", "Uno"], ["2", "Two"], ["3", ""]]);
+    }
+
+    #[test]
+    fn adds_missing_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("items.db");
+        let mut sink = sink(&database, serde_json::json!({})).unwrap();
+        sink.sink_items(vec![vec![("id", "1")]]).unwrap();
+        sink.sink_items(vec![vec![("id", "2"), ("title", "Two")]])
+            .unwrap();
+        drop(sink);
+        assert_eq!(rows(&database), [["1", ""], ["2", "Two"]]);
+
+        // Columns added by someone else are known when starting
+        Connection::open(&database)
+            .unwrap()
+            .execute("ALTER TABLE items ADD COLUMN link TEXT", [])
+            .unwrap();
+        let mut sink = self::sink(&database, serde_json::json!({})).unwrap();
+        sink.sink_items(vec![vec![("id", "3"), ("link", "https://example.com")]])
+            .unwrap();
+        assert_eq!(
+            rows(&database),
+            [
+                ["1", "", ""],
+                ["2", "Two", ""],
+                ["3", "", "https://example.com"]
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_keys_to_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("items.db");
+        let config = serde_json::json!({
+            "columns": { "id": "item_id", "title": "item_title" },
+            "key": "id",
+        });
+        let mut sink = sink(&database, config).unwrap();
+        sink.sink_items(vec![
+            vec![("id", "1"), ("title", "One"), ("ignored", "x")],
+            vec![("id", "1"), ("title", "Uno")],
+        ])
+        .unwrap();
+        assert_eq!(rows(&database), [["1", "Uno"]]);
+    }
+
+    #[test]
+    fn rejects_a_key_outside_the_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("items.db");
+        let config = serde_json::json!({ "columns": { "title": "title" }, "key": "id" });
+        let err = sink(&database, config).err().unwrap();
+        assert_eq!(err.to_string(), "`key` \"id\" is not one of `columns`");
+    }
+}
@@ -0,0 +1,341 @@
+//! The `sqlite` source emits the rows of a query that are new since the last poll.
+//!
+//! Rows are told apart by a high-water mark, a column of the query that only grows, like an
+//! autoincrementing id or an insertion time. Every poll emits the rows from the highest value seen
+//! so far on, in its order, and saves the new highest value to `state_file` along with the rows
+//! emitted at it. Rows sharing a value, like items inserted in the same second, are told apart by
+//! their contents, so none are skipped when a poll stops among them or they arrive later.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use feed_plumber_plugin_rs::{
+    state::{load_state, save_state},
+    FeedPlumberSource,
+};
+use rusqlite::{types::Value, Connection, OpenFlags, Params};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value as JsonValue};
+
+use crate::{open, quote};
+
+const DEFAULT_MAX_ROWS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SqliteSourceConfig {
+    /// The source's name, used for the default state file.
+    name: String,
+    database: PathBuf,
+    query: String,
+    /// The column of `query` telling which rows are new.
+    high_water_mark: String,
+    /// Whether the rows already there when the source first polls are emitted.
+    #[serde(default)]
+    from_start: bool,
+    /// The most rows emitted per poll, the rest follow on the next polls.
+    #[serde(default = "default_max_rows")]
+    max_rows: usize,
+    state_file: Option<PathBuf>,
+}
+
+#[inline]
+const fn default_max_rows() -> usize {
+    DEFAULT_MAX_ROWS
+}
+
+type Item = Vec<(String, String)>;
+
+/// What is saved to the state file.
+#[derive(Serialize, Deserialize, Clone)]
+struct State {
+    last: JsonValue,
+    /// The rows emitted with `last` as their mark, so rows sharing it are neither lost nor emitted
+    /// twice.
+    #[serde(default)]
+    at_last: Vec<Item>,
+}
+
+pub struct SqliteSource {
+    connection: Connection,
+    /// The rows of `query` from the high-water mark on.
+    sql: String,
+    /// The rows with the highest high-water mark, for starting out at the end.
+    max_sql: String,
+    high_water_mark: String,
+    from_start: bool,
+    max_rows: usize,
+    state_file: PathBuf,
+    /// `None` before the first poll.
+    state: Option<State>,
+}
+
+impl FeedPlumberSource for SqliteSource {
+    type ConfigType = SqliteSourceConfig;
+
+    fn new(config: Self::ConfigType) -> anyhow::Result<Self> {
+        let connection = open(&config.database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let query = config.query.trim().trim_end_matches(';');
+        let column = quote(&config.high_water_mark);
+        let sql = format!(
+            "SELECT * FROM ({query}) WHERE ?1 IS NULL OR {column} >= ?1 ORDER BY {column} LIMIT ?2"
+        );
+        let columns = connection
+            .prepare(&sql)
+            .context("Invalid `query`")?
+            .column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if !columns.contains(&config.high_water_mark) {
+            bail!(
+                "`query` has no column \"{}\" for `high_water_mark`",
+                config.high_water_mark
+            );
+        }
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| PathBuf::from(format!("sqlite-{}.json", config.name)));
+        Ok(SqliteSource {
+            connection,
+            sql,
+            max_sql: format!(
+                "SELECT * FROM ({query}) WHERE {column} = (SELECT MAX({column}) FROM ({query}))"
+            ),
+            high_water_mark: config.high_water_mark,
+            from_start: config.from_start,
+            max_rows: config.max_rows,
+            state: load_state(&state_file)?,
+            state_file,
+        })
+    }
+
+    fn poll_source(&mut self) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+        let state = match self.state.clone() {
+            Some(state) => state,
+            None if self.from_start => State {
+                last: JsonValue::Null,
+                at_last: Vec::new(),
+            },
+            None => {
+                let rows = self.rows(&self.max_sql, [])?;
+                let state = State {
+                    last: rows
+                        .first()
+                        .map_or(JsonValue::Null, |(mark, _)| mark.clone()),
+                    at_last: rows.into_iter().map(|(_, item)| item).collect(),
+                };
+                save_state(&self.state_file, &state)?;
+                self.state = Some(state);
+                return Ok(Vec::new());
+            }
+        };
+
+        // The rows emitted at the last mark come first, and don't count towards `max_rows`
+        let limit = self.max_rows + state.at_last.len();
+        let rows = self.rows(&self.sql, (from_json(state.last.clone()), limit as i64))?;
+        let mut seen = state.at_last.clone();
+        let mut items = Vec::new();
+        let mut marks = Vec::new();
+        for (mark, item) in rows {
+            if mark == state.last {
+                if let Some(idx) = seen.iter().position(|seen| *seen == item) {
+                    seen.swap_remove(idx);
+                    continue;
+                }
+            }
+            if items.len() == self.max_rows {
+                break;
+            }
+            marks.push(mark);
+            items.push(item);
+        }
+
+        // Rows with a NULL mark can't be paged past, and don't move it
+        let last = marks
+            .iter()
+            .rev()
+            .find(|mark| !mark.is_null())
+            .cloned()
+            .unwrap_or_else(|| state.last.clone());
+        let mut at_last = if last == state.last {
+            state.at_last
+        } else {
+            Vec::new()
+        };
+        at_last.extend(
+            marks
+                .iter()
+                .zip(&items)
+                .filter(|(mark, _)| **mark == last)
+                .map(|(_, item)| item.clone()),
+        );
+        let state = State { last, at_last };
+        if !items.is_empty() {
+            save_state(&self.state_file, &state)?;
+        }
+        self.state = Some(state);
+        Ok(items)
+    }
+}
+
+impl SqliteSource {
+    /// The rows of `sql`, as items along with their high-water mark.
+    fn rows(&self, sql: &str, params: impl Params) -> anyhow::Result<Vec<(JsonValue, Item)>> {
+        let mut statement = self.connection.prepare_cached(sql)?;
+        let names = statement
+            .column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let mut rows = statement.query(params).context("Querying")?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().context("Querying")? {
+            let mut mark = JsonValue::Null;
+            let mut item = Vec::new();
+            for (idx, name) in names.iter().enumerate() {
+                let value = row.get::<_, Value>(idx)?;
+                if *name == self.high_water_mark {
+                    mark = to_json(value.clone());
+                }
+                let value = match value {
+                    Value::Null => continue,
+                    Value::Integer(value) => value.to_string(),
+                    Value::Real(value) => value.to_string(),
+                    Value::Text(value) => value,
+                    Value::Blob(value) => String::from_utf8_lossy(&value).into_owned(),
+                };
+                item.push((name.clone(), value));
+            }
+            result.push((mark, item));
+        }
+        Ok(result)
+    }
+}
+
+/// Values of the high-water mark as they are saved.
+fn to_json(value: Value) -> JsonValue {
+    match value {
+        Value::Integer(value) => value.into(),
+        Value::Real(value) => Number::from_f64(value).map_or(JsonValue::Null, JsonValue::Number),
+        Value::Text(value) => value.into(),
+        Value::Blob(value) => String::from_utf8_lossy(&value).into_owned().into(),
+        Value::Null => JsonValue::Null,
+    }
+}
+
+fn from_json(value: JsonValue) -> Value {
+    match value {
+        JsonValue::Number(number) => match number.as_i64() {
+            Some(value) => Value::Integer(value),
+            None => number.as_f64().map_or(Value::Null, Value::Real),
+        },
+        JsonValue::String(value) => Value::Text(value),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn database(path: &Path, rows: &[(Option<i64>, &str)]) {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS items (id INTEGER PRIMARY KEY, mark INTEGER, title TEXT)",
+                [],
+            )
+            .unwrap();
+        for (mark, title) in rows {
+            connection
+                .execute(
+                    "INSERT INTO items (mark, title) VALUES (?1, ?2)",
+                    (mark, title),
+                )
+                .unwrap();
+        }
+    }
+
+    fn source(dir: &Path, from_start: bool, max_rows: usize) -> SqliteSource {
+        let config = serde_json::json!({
+            "name": "test",
+            "database": dir.join("items.db"),
+            "query": "SELECT mark, title FROM items ORDER BY id;",
+            "high_water_mark": "mark",
+            "from_start": from_start,
+            "max_rows": max_rows,
+            "state_file": dir.join("state.json"),
+        });
+        SqliteSource::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    /// The titles of the rows emitted by the next poll.
+    fn poll(source: &mut SqliteSource) -> Vec<String> {
+        source
+            .poll_source()
+            .unwrap()
+            .into_iter()
+            .map(|item| item.into_iter().find(|(key, _)| key == "title").unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn starts_at_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        database(
+            &dir.path().join("items.db"),
+            &[(Some(1), "a"), (Some(2), "b")],
+        );
+        let mut source = source(dir.path(), false, 10);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        database(
+            &dir.path().join("items.db"),
+            &[(Some(2), "c"), (Some(3), "d")],
+        );
+        assert_eq!(poll(&mut source), ["c", "d"]);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn stops_among_rows_sharing_a_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.db");
+        database(
+            &path,
+            &[
+                (Some(1), "a"),
+                (Some(1), "b"),
+                (Some(1), "c"),
+                (Some(2), "d"),
+            ],
+        );
+        let mut source = source(dir.path(), true, 2);
+        assert_eq!(poll(&mut source), ["a", "b"]);
+
+        // Picking up from the saved state
+        let mut source = self::source(dir.path(), true, 2);
+        assert_eq!(poll(&mut source), ["c", "d"]);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+        // Arriving later with a mark already seen
+        database(&path, &[(Some(2), "e")]);
+        assert_eq!(poll(&mut source), ["e"]);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn emits_rows_without_a_mark_before_the_first_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("items.db");
+        database(&path, &[(None, "x"), (None, "y"), (Some(1), "a")]);
+        let mut source = source(dir.path(), true, 1);
+        assert_eq!(poll(&mut source), ["x"]);
+        assert_eq!(poll(&mut source), ["y"]);
+        assert_eq!(poll(&mut source), ["a"]);
+        // Once there is a mark, rows without one can't be told apart from old ones
+        database(&path, &[(None, "z"), (Some(2), "b")]);
+        assert_eq!(poll(&mut source), ["b"]);
+        assert_eq!(poll(&mut source), Vec::<String>::new());
+    }
+}
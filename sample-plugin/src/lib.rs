@@ -1,13 +1,19 @@
 use feed_plumber_plugin_rs::{
-    feed_plumber_plugin, toml::Value, FeedPlumberProcessor, FeedPlumberSink, FeedPlumberSource,
+    feed_plumber_plugin, toml::Value, Emitter, FeedPlumberEventSource, FeedPlumberProcessor,
+    FeedPlumberSink, FeedPlumberSource, FeedPlumberStreamProcessor,
 };
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
 
 feed_plumber_plugin! {
     sources: "counter" => CounterSource;
     sinks: "console" => ConsoleSink;
     processors: "keymap" => KeyMapProcessor;
+    stream_processors: "digest" => DigestProcessor;
+    event_sources: "ticker" => TickerSource;
 }
 
 #[derive(Deserialize, Default)]
@@ -68,7 +74,10 @@ impl FeedPlumberSink for ConsoleSink {
         })
     }
 
-    fn sink_items(&mut self, items: Vec<Vec<(&str, &str)>>) {
+    fn sink_items(
+        &mut self,
+        items: Vec<Vec<(&str, &str)>>,
+    ) -> feed_plumber_plugin_rs::anyhow::Result<()> {
         for item in items {
             println!("{:-<30}", "Item");
             for (key, value) in item {
@@ -77,6 +86,7 @@ impl FeedPlumberSink for ConsoleSink {
             println!("{:-<30}", "-");
             self.sequence += 1;
         }
+        Ok(())
     }
 }
 
@@ -115,3 +125,100 @@ impl FeedPlumberProcessor for KeyMapProcessor {
             .collect())
     }
 }
+
+#[derive(Deserialize, Default)]
+struct DigestProcessorConfig {
+    #[serde(default = "default_key")]
+    key_name: String,
+}
+
+/// Collects items until it is flushed, then emits a single item summarizing them.
+struct DigestProcessor {
+    key: String,
+    sources: BTreeSet<String>,
+    values: Vec<String>,
+}
+
+impl FeedPlumberStreamProcessor for DigestProcessor {
+    type ConfigType = DigestProcessorConfig;
+
+    fn new(config: Self::ConfigType) -> feed_plumber_plugin_rs::anyhow::Result<Self> {
+        Ok(Self {
+            key: config.key_name,
+            sources: BTreeSet::new(),
+            values: Vec::new(),
+        })
+    }
+
+    fn receive_items(
+        &mut self,
+        source: &str,
+        items: Vec<Vec<(&str, &str)>>,
+    ) -> feed_plumber_plugin_rs::anyhow::Result<Vec<Vec<(String, String)>>> {
+        self.sources.insert(source.to_owned());
+        for item in items {
+            for (key, value) in item {
+                if key == self.key {
+                    self.values.push(value.to_owned());
+                }
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn flush(&mut self) -> feed_plumber_plugin_rs::anyhow::Result<Vec<Vec<(String, String)>>> {
+        if self.values.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sources = std::mem::take(&mut self.sources);
+        let values = std::mem::take(&mut self.values);
+        Ok(vec![vec![
+            ("items".to_owned(), values.len().to_string()),
+            (
+                "sources".to_owned(),
+                sources.into_iter().collect::<Vec<_>>().join(", "),
+            ),
+            (self.key.clone(), values.join(", ")),
+        ]])
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct TickerSourceConfig {
+    #[serde(default = "default_key")]
+    key_name: String,
+    /// In milliseconds.
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    1000
+}
+
+/// Emits an increasing count every `interval`, without being polled.
+struct TickerSource {
+    key: String,
+    interval: Duration,
+    count: usize,
+}
+
+impl FeedPlumberEventSource for TickerSource {
+    type ConfigType = TickerSourceConfig;
+
+    fn new(config: Self::ConfigType) -> feed_plumber_plugin_rs::anyhow::Result<Self> {
+        Ok(Self {
+            key: config.key_name,
+            interval: Duration::from_millis(config.interval),
+            count: 0,
+        })
+    }
+
+    fn run(&mut self, emitter: &Emitter) -> feed_plumber_plugin_rs::anyhow::Result<()> {
+        while !emitter.wait(self.interval) {
+            self.count += 1;
+            emitter.emit(vec![vec![(self.key.clone(), self.count.to_string())]]);
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,26 @@
 use std::ffi::{c_char, c_void, CStr};
 
+pub mod wasm;
+pub mod wire;
+
 pub type InitializationFunction = unsafe extern "C" fn() -> FeedPlumberPlugin;
 
 pub const INITIALIZATION_FUNCTION_NAME: &str = "_feedplumber_plugin_init";
 
+/// Version of the layout of the structs plugins and the service exchange. Bumped whenever it
+/// changes, as a plugin built against another layout cannot be loaded safely.
+pub const ABI_VERSION: u32 = 2;
+
+/// Name of the `u32` static plugins export with the [`ABI_VERSION`] they were built against.
+pub const ABI_VERSION_SYMBOL_NAME: &str = "FEED_PLUMBER_ABI_VERSION";
+
+/// Key of a single-pair item reporting a recoverable error in place of the component's items.
+pub const ERROR_KEY_WARN: &str = "FEED_PLUMBER_WARN";
+/// Key of a single-pair item reporting that the component cannot continue.
+pub const ERROR_KEY_FATAL: &str = "FEED_PLUMBER_FATAL";
+/// Key of a single-pair item reporting that the component panicked. The value is the panic message.
+pub const ERROR_KEY_PANIC: &str = "FEED_PLUMBER_PANIC";
+
 #[repr(C)]
 pub struct KeyValuePair {
     pub key: *mut c_char,
@@ -55,6 +72,10 @@ pub struct FeedPlumberPlugin {
     pub sinks_len: usize,
     pub processors: *const FeedPlumberProcessorMeta,
     pub processors_len: usize,
+    pub stream_processors: *const FeedPlumberStreamProcessorMeta,
+    pub stream_processors_len: usize,
+    pub event_sources: *const FeedPlumberEventSourceMeta,
+    pub event_sources_len: usize,
 }
 unsafe impl Sync for FeedPlumberPlugin {}
 unsafe impl Send for FeedPlumberPlugin {}
@@ -65,6 +86,7 @@ pub struct FeedPlumberSourceMeta {
     pub name: StaticString,
     pub create: unsafe extern "C" fn(*const c_char) -> CreationResult,
     pub poll_source: unsafe extern "C" fn(*mut c_void) -> Items,
+    pub destroy: unsafe extern "C" fn(*mut c_void),
 }
 
 #[repr(C)]
@@ -72,7 +94,9 @@ pub struct FeedPlumberSourceMeta {
 pub struct FeedPlumberSinkMeta {
     pub name: StaticString,
     pub create: unsafe extern "C" fn(*const c_char) -> CreationResult,
-    pub sink_items: unsafe extern "C" fn(*mut c_void, Items),
+    /// Returns no items on success, or an error item.
+    pub sink_items: unsafe extern "C" fn(*mut c_void, Items) -> Items,
+    pub destroy: unsafe extern "C" fn(*mut c_void),
 }
 
 #[repr(C)]
@@ -81,6 +105,48 @@ pub struct FeedPlumberProcessorMeta {
     pub name: StaticString,
     pub create: unsafe extern "C" fn(*const c_char) -> CreationResult,
     pub process_items: unsafe extern "C" fn(*mut c_void, Items) -> Items,
+    pub destroy: unsafe extern "C" fn(*mut c_void),
+}
+
+/// A processor that keeps state across batches, possibly from several sources, and emits items
+/// into its own pipelines rather than replying to the batch it was given.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FeedPlumberStreamProcessorMeta {
+    pub name: StaticString,
+    pub create: unsafe extern "C" fn(*const c_char) -> CreationResult,
+    /// Receives a batch from the named source. Returns the items to emit right away, which are
+    /// usually none.
+    pub receive_items: unsafe extern "C" fn(*mut c_void, *const c_char, Items) -> Items,
+    /// Called on the processor's schedule. Returns the items to emit.
+    pub flush: unsafe extern "C" fn(*mut c_void) -> Items,
+    pub destroy: unsafe extern "C" fn(*mut c_void),
+}
+
+/// Passed to a running event source, to hand items to the service and to learn when to stop.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Emitter {
+    pub context: *mut c_void,
+    /// Hands items to the service, which takes ownership of them. Returns false once the source
+    /// is asked to stop, in which case the items are dropped.
+    pub emit: unsafe extern "C" fn(*mut c_void, Items) -> bool,
+    /// Waits up to the given number of milliseconds for the source to be asked to stop, returning
+    /// whether it was. Waiting for 0 milliseconds just checks.
+    pub wait_stop: unsafe extern "C" fn(*mut c_void, u64) -> bool,
+}
+
+/// A source that runs on a thread of its own and emits items as they arrive, rather than being
+/// polled.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FeedPlumberEventSourceMeta {
+    pub name: StaticString,
+    pub create: unsafe extern "C" fn(*const c_char) -> CreationResult,
+    /// Runs until the source is asked to stop through the emitter, or fails. The emitter is only
+    /// valid during the call. Returns no items when stopped, or an error item.
+    pub run: unsafe extern "C" fn(*mut c_void, Emitter) -> Items,
+    pub destroy: unsafe extern "C" fn(*mut c_void),
 }
 
 #[repr(C)]
@@ -88,5 +154,7 @@ pub struct FeedPlumberProcessorMeta {
 pub struct CreationResult {
     pub handle: *mut c_void,
     pub message: *mut c_char,
+    /// Whether creation failed because the plugin panicked.
+    pub panicked: bool,
     pub destroy_message: unsafe extern "C" fn(*mut c_char),
 }
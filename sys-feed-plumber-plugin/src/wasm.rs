@@ -0,0 +1,45 @@
+//! The ABI between the service and plugins compiled to WebAssembly.
+//!
+//! Guest memory cannot be shared with the host, so everything is passed as byte buffers encoded
+//! with [`crate::wire`]. A buffer returned by the guest is packed into a `u64` as
+//! `(ptr << 32) | len` and must be released by the host with [`FREE`]. Buffers passed to the guest
+//! are allocated by the host with [`ALLOC`] and released by the host after the call.
+//!
+//! Exports:
+//! - `ALLOC(len: u32) -> u32`
+//! - `FREE(ptr: u32, len: u32)`
+//! - `MANIFEST() -> u64`: three string lists, the source, sink and processor type names.
+//! - `CREATE(kind: u32, type_ptr: u32, type_len: u32, config_ptr: u32, config_len: u32) -> u64`:
+//!   a status byte, followed by a `u32` handle for [`CREATED`] or a message otherwise.
+//! - `POLL_SOURCE(handle: u32) -> u64`: items, where errors are reported in-band as with native
+//!   plugins.
+//! - `SINK_ITEMS(handle: u32, items_ptr: u32, items_len: u32) -> u64`: items, empty on success.
+//! - `PROCESS_ITEMS(handle: u32, items_ptr: u32, items_len: u32) -> u64`: items.
+//! - `DESTROY(handle: u32)`
+
+pub const ALLOC: &str = "feed_plumber_alloc";
+pub const FREE: &str = "feed_plumber_free";
+pub const MANIFEST: &str = "feed_plumber_manifest";
+pub const CREATE: &str = "feed_plumber_create";
+pub const POLL_SOURCE: &str = "feed_plumber_poll_source";
+pub const SINK_ITEMS: &str = "feed_plumber_sink_items";
+pub const PROCESS_ITEMS: &str = "feed_plumber_process_items";
+pub const DESTROY: &str = "feed_plumber_destroy";
+
+pub const KIND_SOURCE: u32 = 0;
+pub const KIND_SINK: u32 = 1;
+pub const KIND_PROCESSOR: u32 = 2;
+
+pub const CREATED: u8 = 0;
+pub const CREATE_FAILED: u8 = 1;
+pub const CREATE_PANICKED: u8 = 2;
+
+#[inline]
+pub const fn pack(ptr: u32, len: u32) -> u64 {
+    ((ptr as u64) << 32) | len as u64
+}
+
+#[inline]
+pub const fn unpack(packed: u64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
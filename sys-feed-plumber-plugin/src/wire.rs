@@ -0,0 +1,99 @@
+//! A small binary encoding for items, used wherever items cannot be passed as [`crate::Items`]
+//! (child process plugin hosts, WebAssembly plugins).
+//!
+//! Integers are little-endian `u32`s, strings are a length followed by UTF-8 bytes, and items are
+//! a count of items, each a count of pairs followed by the key and value strings.
+
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy)]
+pub struct WireError;
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Corrupt wire data")
+    }
+}
+
+impl std::error::Error for WireError {}
+
+#[derive(Default)]
+pub struct Writer(pub Vec<u8>);
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn items<'a, I, K, V>(&mut self, items: I)
+    where
+        I: IntoIterator<Item = &'a Vec<(K, V)>>,
+        I::IntoIter: ExactSizeIterator,
+        K: AsRef<str> + 'a,
+        V: AsRef<str> + 'a,
+    {
+        let items = items.into_iter();
+        self.u32(items.len() as u32);
+        for item in items {
+            self.u32(item.len() as u32);
+            for (key, value) in item {
+                self.str(key.as_ref());
+                self.str(value.as_ref());
+            }
+        }
+    }
+}
+
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], WireError> {
+        if self.0.len() < len {
+            return Err(WireError);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, WireError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, WireError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn str(&mut self) -> Result<String, WireError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| WireError)
+    }
+
+    pub fn items(&mut self) -> Result<Vec<Vec<(String, String)>>, WireError> {
+        let count = self.u32()?;
+        let mut items = Vec::new();
+        for _ in 0..count {
+            let pairs = self.u32()?;
+            let mut item = Vec::new();
+            for _ in 0..pairs {
+                item.push((self.str()?, self.str()?));
+            }
+            items.push(item);
+        }
+        Ok(items)
+    }
+}